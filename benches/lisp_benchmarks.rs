@@ -1,6 +1,6 @@
 // benches/lisp_benchmarks.rs
 use lazy_static::lazy_static;
-use rand::{rngs::{ThreadRng, StdRng}, Rng, SeedableRng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use core::panic;
 use std::io::BufRead;
 
@@ -11,13 +11,14 @@ const SEED: u64 = 0;
 
 fn make_env() -> Env {
     let mut env = Env::new();
+    env.bind_core_forms();
     env.bind_builtin("env", |env, args| {
         // Get the env as a map
         if args.is_empty() {
-            return Expr::Map(env.get_bindings());
+            return Expr::Map(env.get_bindings().into());
         }
         let a = env.eval(args[0].clone());
-        env.get(&a).cloned().unwrap_or(Expr::None)
+        env.get(&a).unwrap_or(Expr::None)
     });
 
     env.bind_builtin("+", |env, exprs| {
@@ -32,15 +33,13 @@ fn make_env() -> Env {
                 (Expr::Int(a), Expr::Float(b)) => sum = Expr::Float(a as f64 + b),
                 (Expr::Float(a), Expr::Int(b)) => sum = Expr::Float(a + b as f64),
                 (Expr::String(a), Expr::String(b)) => sum = Expr::String(format!("{}{}", a, b)),
-                (Expr::List(a), Expr::List(b)) => {
-                    let mut list = a.clone();
-                    list.extend(b);
-                    sum = Expr::List(list);
+                (Expr::List(mut a), Expr::List(b)) => {
+                    a.append(b);
+                    sum = Expr::List(a);
                 }
-                (Expr::List(a), b) => {
-                    let mut list = a.clone();
-                    list.push(b);
-                    sum = Expr::List(list);
+                (Expr::List(mut a), b) => {
+                    a.push_back(b);
+                    sum = Expr::List(a);
                 }
                 (a, b) => return Expr::error(format!("Invalid expr {} + {}", a, b)),
             }
@@ -79,7 +78,7 @@ fn make_env() -> Env {
                 (Expr::List(a), Expr::Int(b)) => {
                     let mut list = a.clone();
                     for _ in 0..b {
-                        list.extend(a.clone());
+                        list.append(a.clone());
                     }
                     product = Expr::List(list);
                 }
@@ -167,25 +166,6 @@ fn make_env() -> Env {
         Expr::Bool(a > b)
     });
 
-    env.bind_lazy_builtin("if", |env, exprs| {
-        let cond = env.eval(exprs[0].clone());
-        let then = exprs[1].clone();
-        if exprs.len() < 3 {
-            if cond == Expr::Bool(true) {
-                then
-            } else {
-                Expr::None
-            }
-        } else {
-            let else_ = exprs[2].clone();
-            if cond == Expr::Bool(true) {
-                then
-            } else {
-                else_
-            }
-        }
-    });
-
     env.bind_builtin("define", |env, exprs| {
         let name = exprs[0].clone();
         let value = env.eval(exprs[1].clone());
@@ -204,11 +184,11 @@ fn make_env() -> Env {
         let params = args[1].clone();
         let body = args[2].clone();
         if let Expr::List(params) = params {
-            let f = env.eval(Expr::Function(None, params, Box::new(body)));
+            let f = env.eval(Expr::Function(None, params.to_vec(), Box::new(body)));
             env.bind(name, f);
             Expr::None
         } else {
-            return Expr::error(format!("Invalid params {:?}", params));
+            Expr::error(format!("Invalid params {:?}", params))
         }
     });
 
@@ -226,19 +206,6 @@ fn make_env() -> Env {
         Expr::None
     });
 
-    // env.bind_builtin("do", |env, exprs| {
-    //     let mut result = Expr::default();
-    //     for e in exprs {
-    //         result = env.eval(e.clone());
-    //     }
-    //     result
-    // });
-
-    env.bind_lazy_builtin("do", |_env, exprs| {
-        use std::sync::Arc;
-        Expr::Many(Arc::new(Vec::from(exprs)))
-    });
-
     env.bind_builtin("sqrt", |env, expr| {
         let e = env.eval(expr[0].clone());
         match e {
@@ -262,42 +229,20 @@ fn make_env() -> Env {
 
     env.alias("^", "pow");
 
-    let lambda = |env: &mut Env, expr: Vec<Expr>| {
-        let params = expr[0].clone();
-        let body = expr[1].clone();
-        if let Expr::List(params) = params {
-            Expr::Function(Some(Box::new(env.clone())), params, Box::new(body))
-        } else {
-            return Expr::error(format!("Invalid params {:?}", params));
-        }
-    };
-    env.bind_builtin("lambda", lambda);
-    env.bind_builtin("\\", lambda);
-
     env.bind_builtin("apply", |env, expr| {
         let f = env.eval(expr[0].clone());
         let args = env.eval(expr[1].clone());
         if let Expr::List(args) = args {
             match f {
-                Expr::Function(Some(mut env), params, body) => {
-                    let mut new_env = env.clone();
-                    for (param, arg) in params.iter().zip(args.iter()) {
-                        new_env.bind(param.clone(), env.eval(arg.clone()));
-                    }
-                    new_env.eval(*body.clone())
+                Expr::Function(..) | Expr::Builtin(_) => {
+                    // Quote the arguments so they aren't evaluated again by the call.
+                    let args = args.iter().map(Expr::quote).collect::<Vec<_>>();
+                    env.eval(f.apply(&args))
                 }
-                Expr::Function(None, params, body) => {
-                    let mut new_env = env.clone();
-                    for (param, arg) in params.iter().zip(args.iter()) {
-                        new_env.bind(param.clone(), env.eval(arg.clone()));
-                    }
-                    new_env.eval(*body.clone())
-                }
-                Expr::Builtin(f) => f.apply(&mut env.clone(), args),
                 f => Expr::error(format!("Invalid function {f} apply {}", Expr::from(args))),
             }
         } else {
-            Expr::error(format!("Invalid function {f} apply {}", Expr::from(args)))
+            Expr::error(format!("Invalid function {f} apply {}", args))
         }
     });
 
@@ -305,14 +250,13 @@ fn make_env() -> Env {
         let a = env.eval(expr[0].clone());
         let b = env.eval(expr[1].clone());
         // Create a new list with a as the head and b as the tail.
-        if let Expr::List(b) = b {
-            let mut list = vec![a];
-            list.extend(b);
-            Expr::List(list)
+        if let Expr::List(mut b) = b {
+            b.push_front(a);
+            Expr::List(b)
         } else if b == Expr::None {
-            Expr::List(vec![a])
+            Expr::List(vec![a].into())
         } else {
-            Expr::List(vec![a, b])
+            Expr::List(vec![a, b].into())
         }
     });
     let head = |env: &mut Env, expr: Vec<Expr>| {
@@ -326,7 +270,7 @@ fn make_env() -> Env {
     let tail = |env: &mut Env, expr: Vec<Expr>| {
         let a = env.eval(expr[0].clone());
         if let Expr::List(a) = a {
-            Expr::List(a[1..].to_vec())
+            Expr::List(a.slice(1..))
         } else {
             Expr::error(format!("Invalid tail {a}"))
         }
@@ -390,7 +334,7 @@ fn make_env() -> Env {
             if i >= args.len() {
                 return Expr::error("Too few arguments");
             }
-            let specifier = format!("{{}}");
+            let specifier = "{}".to_string();
             let value = env.eval(args[i].clone());
             match value {
                 Expr::String(s) => {
@@ -418,15 +362,15 @@ fn make_env() -> Env {
         for e in expr {
             list.push(env.eval(e.clone()));
         }
-        Expr::List(list)
+        Expr::List(list.into())
     });
 
     env.bind_builtin("append", |env, expr| {
-        let mut list = vec![];
+        let mut list = Vector::new();
         for e in expr {
             let e = env.eval(e.clone());
             if let Expr::List(l) = e {
-                list.extend(l);
+                list.append(l);
             } else {
                 return Expr::error(format!("Invalid append {e}"));
             }
@@ -451,8 +395,6 @@ fn make_env() -> Env {
         }
     });
 
-    env.bind_builtin("quote", |_env, expr| expr[0].clone());
-
     env.bind_builtin("or", |env, expr| {
         for e in expr {
             let e = env.eval(e.clone());
@@ -477,7 +419,7 @@ fn make_env() -> Env {
         let e = env.eval(expr[0].clone());
         match e {
             Expr::Bool(b) => Expr::Bool(!b),
-            e => return Expr::error(format!("Invalid not {e}")),
+            e => Expr::error(format!("Invalid not {e}")),
         }
     });
 
@@ -493,7 +435,7 @@ fn make_env() -> Env {
     });
 
     env.bind_builtin("let", |env, expr| {
-        let mut new_env = env.clone();
+        let mut new_env = env.new_scope();
         let bindings = expr[0].clone();
         let body = expr[1].clone();
         match bindings {
@@ -542,7 +484,7 @@ fn make_env() -> Env {
             }
             (Expr::Map(a), b) => a.get(&b).cloned().unwrap_or(Expr::None),
             (Expr::Tree(a), b) => a.get(&b).cloned().unwrap_or(Expr::None),
-            (a, b) => return Expr::error(format!("Invalid expr get {} {}", a, b)),
+            (a, b) => Expr::error(format!("Invalid expr get {} {}", a, b)),
         }
     });
     env.alias("get", "@");
@@ -566,10 +508,10 @@ fn make_env() -> Env {
                 Expr::String(a)
             }
             (Expr::List(mut a), Expr::Int(b)) => {
-                if b as usize >= a.len() {
-                    a.resize(b as usize + 1, Expr::None);
+                while b as usize >= a.len() {
+                    a.push_back(Expr::None);
                 }
-                a[b as usize] = c;
+                a.set(b as usize, c);
                 Expr::List(a)
             }
             (Expr::Map(mut a), b) => {
@@ -580,7 +522,7 @@ fn make_env() -> Env {
                 a.insert(b, c);
                 Expr::Tree(a)
             }
-            (a, b) => return Expr::error(format!("Invalid expr set {} {} {}", a, b, c)),
+            (a, b) => Expr::error(format!("Invalid expr set {} {} {}", a, b, c)),
         }
     });
 
@@ -591,12 +533,12 @@ fn make_env() -> Env {
         match (a, b) {
             (Expr::List(a), Expr::List(b)) => {
                 let mut list = vec![];
-                for (a, b) in a.into_iter().zip(b.into_iter()) {
-                    list.push(Expr::List(vec![a, b]));
+                for (a, b) in a.into_iter().zip(b) {
+                    list.push(Expr::List(vec![a, b].into()));
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
-            (a, b) => return Expr::error(format!("Invalid expr zip {} {}", a, b)),
+            (a, b) => Expr::error(format!("Invalid expr zip {} {}", a, b)),
         }
     });
 
@@ -605,7 +547,7 @@ fn make_env() -> Env {
         let a = env.eval(expr[0].clone());
         match a {
             Expr::List(a) => {
-                let mut map = std::collections::HashMap::new();
+                let mut map = HashTrieMap::new();
                 for e in a {
                     if let Expr::List(e) = e {
                         if e.len() == 2 {
@@ -614,14 +556,14 @@ fn make_env() -> Env {
                             return Expr::error(format!("Invalid pair {}", Expr::from(e)));
                         }
                     } else {
-                        return Expr::error(format!("Invalid pair {}", Expr::from(e)));
+                        return Expr::error(format!("Invalid pair {}", e));
                     }
                 }
                 Expr::Map(map)
             }
-            Expr::Map(a) => return Expr::Map(a),
-            Expr::Tree(a) => return Expr::Map(a.into_iter().collect()),
-            a => return Expr::error(format!("Invalid expr to-map {}", Expr::from(a))),
+            Expr::Map(a) => Expr::Map(a),
+            Expr::Tree(a) => Expr::Map(a.into_iter().collect()),
+            a => Expr::error(format!("Invalid expr to-map {}", a)),
         }
     });
 
@@ -630,7 +572,7 @@ fn make_env() -> Env {
         let a = env.eval(expr[0].clone());
         match a {
             Expr::List(a) => {
-                let mut tree = std::collections::BTreeMap::new();
+                let mut tree = OrdMap::new();
                 for e in a {
                     if let Expr::List(e) = e {
                         if e.len() == 2 {
@@ -639,14 +581,14 @@ fn make_env() -> Env {
                             return Expr::error(format!("Invalid pair {}", Expr::from(e)));
                         }
                     } else {
-                        return Expr::error(format!("Invalid pair {}", Expr::from(e)));
+                        return Expr::error(format!("Invalid pair {}", e));
                     }
                 }
                 Expr::Tree(tree)
             }
-            Expr::Map(a) => return Expr::Tree(a.into_iter().collect()),
-            Expr::Tree(a) => return Expr::Tree(a),
-            a => return Expr::error(format!("Invalid expr to-tree {}", Expr::from(a))),
+            Expr::Map(a) => Expr::Tree(a.into_iter().collect()),
+            Expr::Tree(a) => Expr::Tree(a),
+            a => Expr::error(format!("Invalid expr to-tree {}", a)),
         }
     });
 
@@ -656,19 +598,19 @@ fn make_env() -> Env {
             Expr::Map(a) => {
                 let mut list = vec![];
                 for (k, v) in a {
-                    list.push(Expr::List(vec![k, v]));
+                    list.push(Expr::List(vec![k, v].into()));
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
             Expr::Tree(a) => {
                 let mut list = vec![];
                 for (k, v) in a {
-                    list.push(Expr::List(vec![k, v]));
+                    list.push(Expr::List(vec![k, v].into()));
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
-            Expr::List(a) => return Expr::List(a),
-            a => return Expr::error(format!("Invalid expr to-list {}", a)),
+            Expr::List(a) => Expr::List(a),
+            a => Expr::error(format!("Invalid expr to-list {}", a)),
         }
     });
 
@@ -679,15 +621,15 @@ fn make_env() -> Env {
             Expr::List(a) => {
                 let mut list = vec![];
                 for e in a {
-                    list.push(env.eval(Expr::List(vec![f.clone(), e])));
+                    list.push(env.eval(Expr::List(vec![f.clone(), e].into())));
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
             Expr::Map(a) => {
-                let mut map = std::collections::HashMap::new();
+                let mut map = HashTrieMap::new();
                 for (k, v) in a {
-                    // map.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v])));
-                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    // map.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v].into())));
+                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
                     if let Expr::List(pair) = pair {
                         map.insert(pair[0].clone(), pair[1].clone());
                     } else {
@@ -697,10 +639,10 @@ fn make_env() -> Env {
                 Expr::Map(map)
             }
             Expr::Tree(a) => {
-                let mut tree = std::collections::BTreeMap::new();
+                let mut tree = OrdMap::new();
                 for (k, v) in a {
-                    // tree.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v])));
-                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    // tree.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v].into())));
+                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
                     if let Expr::List(pair) = pair {
                         tree.insert(pair[0].clone(), pair[1].clone());
                     } else {
@@ -709,7 +651,7 @@ fn make_env() -> Env {
                 }
                 Expr::Tree(tree)
             }
-            a => return Expr::error(format!("Invalid expr map {}", a)),
+            a => Expr::error(format!("Invalid expr map {}", a)),
         }
     });
 
@@ -721,16 +663,16 @@ fn make_env() -> Env {
             Expr::List(a) => {
                 let mut list = vec![];
                 for e in a {
-                    if env.eval(Expr::List(vec![f.clone(), e.clone()])) == Expr::Bool(true) {
+                    if env.eval(Expr::List(vec![f.clone(), e.clone()].into())) == Expr::Bool(true) {
                         list.push(e);
                     }
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
             Expr::Map(a) => {
-                let mut map = std::collections::HashMap::new();
+                let mut map = HashTrieMap::new();
                 for (k, v) in a {
-                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
                     if x == Expr::Bool(true) {
                         map.insert(k, v);
                     }
@@ -738,16 +680,31 @@ fn make_env() -> Env {
                 Expr::Map(map)
             }
             Expr::Tree(a) => {
-                let mut tree = std::collections::BTreeMap::new();
+                let mut tree = OrdMap::new();
                 for (k, v) in a {
-                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
                     if x == Expr::Bool(true) {
                         tree.insert(k, v);
                     }
                 }
                 Expr::Tree(tree)
             }
-            a => return Expr::error(format!("Invalid expr filter {}", a)),
+            a => Expr::error(format!("Invalid expr filter {}", a)),
+        }
+    });
+
+    env.bind_strict_builtin("pmap", |env, expr| {
+        let f = env.eval(expr[0].clone());
+        let a = env.eval(expr[1].clone());
+        match a {
+            Expr::List(a) => {
+                let calls = a.into_iter().map(|e| Expr::List(vec![f.clone(), e].into()));
+                match env.eval_parallel(calls) {
+                    Ok(list) => Expr::List(list.into()),
+                    Err(err) => err,
+                }
+            }
+            a => Expr::error(format!("Invalid expr pmap {}", a)),
         }
     });
 
@@ -760,25 +717,25 @@ fn make_env() -> Env {
             Expr::List(a) => {
                 let mut acc = b;
                 for e in a {
-                    acc = env.eval(Expr::List(vec![f.clone(), acc, e]));
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, e].into()));
                 }
                 acc
             }
             Expr::Map(a) => {
                 let mut acc = b;
                 for (k, v) in a {
-                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v]));
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v].into()));
                 }
                 acc
             }
             Expr::Tree(a) => {
                 let mut acc = b;
                 for (k, v) in a {
-                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v]));
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v].into()));
                 }
                 acc
             }
-            a => return Expr::error(format!("Invalid expr reduce {}", a)),
+            a => Expr::error(format!("Invalid expr reduce {}", a)),
         }
     });
 
@@ -806,23 +763,27 @@ fn make_env() -> Env {
             c => return Expr::error(format!("Invalid expr range {}", c)),
         };
 
+        if c == 0 {
+            return Expr::error("range expected a step other than 0");
+        }
+
+        // A negative step counts down to the end instead.
+        let in_range = |i: i64| if c > 0 { i <= b } else { i >= b };
+
         let mut list = vec![];
-        let mut i = a;
-        while i <= b {
+        let mut next = Some(a);
+        while let Some(i) = next.filter(|&i| in_range(i)) {
             list.push(Expr::Int(i));
-            i += c;
+            next = i.checked_add(c);
         }
-        Expr::List(list)
+        Expr::List(list.into())
     });
 
     env.bind_builtin("rev", |env, expr| {
         let a = env.eval(expr[0].clone());
         match a {
-            Expr::List(mut a) => {
-                a.reverse();
-                Expr::List(a)
-            }
-            a => return Expr::error(format!("Invalid expr rev {}", a)),
+            Expr::List(a) => Expr::List(a.iter().rev().cloned().collect()),
+            a => Expr::error(format!("Invalid expr rev {}", a)),
         }
     });
 
@@ -846,7 +807,7 @@ fn make_env() -> Env {
                 let mut rng = StdRng::seed_from_u64(SEED);
                 Expr::Float(rng.gen_range(low..=high as f64))
             }
-            (a, b) => return Expr::error(format!("Invalid expr rand {} {}", a, b)),
+            (a, b) => Expr::error(format!("Invalid expr rand {} {}", a, b)),
         }
     });
 
@@ -862,11 +823,11 @@ fn make_env() -> Env {
                 let mut code = String::new();
                 for line in reader.lines() {
                     code.push_str(&line.unwrap());
-                    code.push_str("\n");
+                    code.push('\n');
                 }
                 Expr::String(code)
             }
-            a => return Expr::error(format!("Invalid expr read {}", a)),
+            a => Expr::error(format!("Invalid expr read {}", a)),
         }
     });
 
@@ -884,7 +845,7 @@ fn make_env() -> Env {
                 file.write_all(content.as_bytes()).unwrap();
                 Expr::None
             }
-            (a, b) => return Expr::error(format!("Invalid expr write {} {}", a, b)),
+            (a, b) => Expr::error(format!("Invalid expr write {} {}", a, b)),
         }
     });
 
@@ -901,9 +862,9 @@ fn make_env() -> Env {
                     .expect("failed to execute process");
                 let stdout = String::from_utf8(output.stdout).unwrap();
                 let stderr = String::from_utf8(output.stderr).unwrap();
-                Expr::List(vec![Expr::String(stdout), Expr::String(stderr)])
+                Expr::List(vec![Expr::String(stdout), Expr::String(stderr)].into())
             }
-            a => return Expr::error(format!("Invalid expr shell {}", a)),
+            a => Expr::error(format!("Invalid expr shell {}", a)),
         }
     });
 
@@ -1014,6 +975,32 @@ fn benchmark_map_filter(c: &mut Criterion) {
     group.finish();
 }
 
+fn benchmark_parallel_map(c: &mut Criterion) {
+    let mut env = make_env();
+    let records_def = r#"{
+        (defun collatz (n steps)
+            (if (<= n 1) steps
+                (if (= (% n 2) 0)
+                    (collatz (/ n 2) (+ steps 1))
+                    (collatz (+ (* n 3) 1) (+ steps 1)))))
+        (define records (range 1 100))
+    }"#;
+    env.eval_str(records_def).unwrap();
+    assert_eq!(
+        env.eval_str("(map (lambda (n) (collatz n 0)) records)").unwrap(),
+        env.eval_str("(pmap (lambda (n) (collatz n 0)) records)").unwrap()
+    );
+
+    let mut group = c.benchmark_group("Parallel Map");
+    for builtin in ["map", "pmap"] {
+        let call = format!("({builtin} (lambda (n) (collatz n 0)) records)");
+        group.bench_function(format!("Collatz steps of 100 records ({builtin})"), |b| {
+            b.iter(|| env.eval_str(black_box(&call)).unwrap())
+        });
+    }
+    group.finish();
+}
+
 fn benchmark_fastfact(c: &mut Criterion) {
     let mut env = make_env();
     let fastfact_def = r#"
@@ -1105,8 +1092,10 @@ fn benchmark_quicksort(c: &mut Criterion) {
     "#;
     env.eval_str(quicksort_def).expect("Failed to define quicksort");
 
-    // Generate a large unsorted list of 10,000 elements
-    let quicksort_call = "(quicksort (range 10000 0 -1))";
+    // Sort a large list of 10,000 elements, in reverse order.
+    // Negative literals would be read as subtraction, so the step is written (- 0 1).
+    let quicksort_call = "(quicksort (range 10000 0 (- 0 1)))";
+    assert_eq!(env.eval_str("(len (range 10000 0 (- 0 1)))").unwrap(), Expr::Int(10001));
 
     c.bench_function("Quicksort on 10,000 elements", |b| {
         b.iter(|| {
@@ -1114,6 +1103,95 @@ fn benchmark_quicksort(c: &mut Criterion) {
         })
     });
 }
+fn benchmark_compiled(c: &mut Criterion) {
+    let defs = r#"{
+        (defun fact (n)
+            (if n <= 0
+                1
+                n * (fact (- n 1))))
+        (define compose (lambda (f g) (lambda (x) (f (g x)))))
+        (define square (lambda (x) (* x x)))
+        (define inc (lambda (x) (+ x 1)))
+        (define test (compose square inc))
+        (defun quicksort (lst)
+            (if (<= (len lst) 1) lst {
+                (define pivot (get lst (/ (len lst) 2)))
+                (define less (filter (\(x) (< x pivot)) lst))
+                (define equal (filter (\(x) (= x pivot)) lst))
+                (define greater (filter (\(x) (> x pivot)) lst))
+                (+ (quicksort less) equal (quicksort greater))}))
+    }"#;
+
+    let mut interpreted = make_env();
+    let mut compiled = make_env();
+    compiled.set_compiler_enabled(true);
+    interpreted.eval_str(defs).expect("Failed to define functions");
+    compiled.eval_str(defs).expect("Failed to compile functions");
+
+    let calls = [
+        ("Compiled factorial 10", "(fact 10)"),
+        ("Compiled compose (square . inc) on 5", "(test 5)"),
+        ("Compiled quicksort on 10,000 elements", "(quicksort (range 10000 0 (- 0 1)))"),
+    ];
+    // Make sure there is something to sort, so the comparison below means something.
+    assert_eq!(compiled.eval_str("(len (range 10000 0 (- 0 1)))").unwrap(), Expr::Int(10001));
+
+    // The compiled code must agree with the tree-walking interpreter.
+    for (_, call) in calls {
+        assert_eq!(interpreted.eval_str(call).unwrap(), compiled.eval_str(call).unwrap());
+    }
+
+    for (name, call) in calls {
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                compiled.eval_str(black_box(call)).unwrap();
+            })
+        });
+    }
+}
+
+fn benchmark_literals(c: &mut Criterion) {
+    // A 10,000 element list of numbers, and a tree with as many entries.
+    let list = Expr::List((0..10000).map(Expr::Int).collect());
+    let tree = Expr::Tree((0..10000).map(|i| (Expr::Int(i), Expr::String(i.to_string()))).collect());
+
+    let mut group = c.benchmark_group("Literals");
+    for rebinding in [false, true] {
+        let mut env = make_env();
+        env.set_atom_rebinding(rebinding);
+        let mode = if rebinding { "with atom rebinding" } else { "symbols only" };
+
+        group.bench_function(format!("List of 10,000 numbers ({mode})"), |b| {
+            b.iter(|| env.eval(black_box(list.clone())))
+        });
+        group.bench_function(format!("Tree of 10,000 entries ({mode})"), |b| {
+            b.iter(|| env.eval(black_box(tree.clone())))
+        });
+    }
+    group.finish();
+}
+
+fn benchmark_symbols(c: &mut Criterion) {
+    // Parse a program that mentions the same symbols many times, from several threads at once.
+    let program = (0..100)
+        .map(|i| format!("(defun f{} (x y) (if (< x y) (+ x {i}) (f{} (- x 1) y)))", i % 10, i % 10))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let program = format!("{{ {program} }}");
+    // Keep the symbols alive between iterations, like an environment's bindings would.
+    let _defined = Expr::parse(&program).unwrap();
+
+    c.bench_function("Parse on 8 threads", |b| {
+        b.iter(|| {
+            std::thread::scope(|scope| {
+                for _ in 0..8 {
+                    scope.spawn(|| Expr::parse(black_box(&program)).unwrap());
+                }
+            })
+        })
+    });
+}
+
 criterion_group!(
     benches,
     benchmark_quicksort,
@@ -1123,5 +1201,9 @@ criterion_group!(
     benchmark_fact,
     benchmark_stirlings,
     benchmark_map_filter,
+    benchmark_parallel_map,
+    benchmark_compiled,
+    benchmark_literals,
+    benchmark_symbols,
 );
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,9 @@
+(do
+    (defrecord Point (x y))
+    (define p (make-Point 1 2))
+
+    ; Fields can be read with get, by a symbol or a string, like a map.
+    (println "x = " (get p 'x))
+    (println "y = " (get p "y"))
+    (println "z = " (get p 'z))
+    (println "sum = " (+ (Point-x p) (get p 'y))))
@@ -0,0 +1,9 @@
+(do
+    ; Builtins that need every value at once realize lazy sequences into lists.
+    (define evens (seq (list 0 2 4)))
+
+    (println "evens + (6) = " (+ evens (list 6)))
+    (println "(1) + evens = " (+ (list 1) evens))
+    (println "cons -2 evens = " (cons (- 0 2) evens))
+    (println "set evens 1 9 = " (set evens 1 9))
+    (println (format "format {} and {evens}" (drop 1 (seq (list 1 2 3))))))
@@ -0,0 +1,259 @@
+//! # Async Evaluation
+//!
+//! Builtins are synchronous, so a builtin that waits for the host application,
+//! like a network request, would block the async executor it runs on. Instead,
+//! such builtins can be bound with [`Env::bind_async_builtin`], and programs
+//! that call them evaluated with [`Env::eval_async`].
+//!
+//! The interpreter itself stays synchronous: [`Env::eval_async`] evaluates the
+//! expression on a thread of its own. When an async builtin is called there, the
+//! future it returns is sent back to the task awaiting [`Env::eval_async`], which
+//! awaits it on the host's executor, while the evaluating thread waits for its
+//! output. The executor is never blocked, so it can be a single-threaded one.
+use std::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+use super::{Env, ErrorKind, EvalError, Expr};
+
+/// The size of the stack of the thread that evaluates an expression asynchronously.
+///
+/// This matches the usual size of a main thread's stack, so that programs can
+/// recurse as deeply as they would when evaluated synchronously.
+pub(crate) const EVAL_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// A future returned by an async builtin.
+type BuiltinFuture = Pin<Box<dyn Future<Output = Expr> + Send>>;
+
+/// A message from the evaluating thread to the task awaiting the evaluation.
+enum Message {
+    /// An async builtin was called. The task should await the future,
+    /// and send its output back, while the evaluating thread waits.
+    Await(BuiltinFuture, mpsc::Sender<Expr>),
+    /// The evaluation finished with a result.
+    Done(Expr),
+}
+
+/// The evaluating thread's connection to the task awaiting the evaluation.
+#[derive(Clone)]
+pub(crate) struct Host {
+    /// The channel for sending messages to the task.
+    ///
+    /// This is only `None` while the host is being dropped.
+    sender: Option<mpsc::Sender<Message>>,
+    /// The waker of the task, which is woken after every message.
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Host {
+    /// Send a message to the task, returning false if it is no longer listening.
+    fn send(&self, message: Message) -> bool {
+        let sent = self.sender.as_ref().is_some_and(|sender| sender.send(message).is_ok());
+        self.wake();
+        sent
+    }
+
+    /// Wake the task, so it checks for messages.
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Host {
+    fn drop(&mut self) {
+        // If this was the last sender, the task must wake up to find the channel
+        // disconnected, for example when the evaluating thread panicked.
+        drop(self.sender.take());
+        self.wake();
+    }
+}
+
+thread_local! {
+    /// The host of the evaluation running on this thread, if it is asynchronous.
+    static HOST: RefCell<Option<Host>> = const { RefCell::new(None) };
+}
+
+/// Get the host of the evaluation running on this thread, if it is asynchronous.
+pub(crate) fn current_host() -> Option<Host> {
+    HOST.with(|host| host.borrow().clone())
+}
+
+/// Run a function with the given host for the evaluations on this thread.
+///
+/// This is used to let threads started by an asynchronous evaluation, like the
+/// workers of [`Env::eval_parallel`], call async builtins too.
+pub(crate) fn with_host<T>(host: Option<Host>, f: impl FnOnce() -> T) -> T {
+    /// Restores the previous host when dropped, even if the function panics.
+    struct Restore(Option<Host>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            HOST.with(|host| *host.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(HOST.with(|current| current.replace(host)));
+    f()
+}
+
+/// Wait for the output of an async builtin's future.
+fn await_on_host(name: &str, future: BuiltinFuture) -> Expr {
+    let Some(host) = current_host() else {
+        return EvalError::new(
+            ErrorKind::Other,
+            format!("The async builtin {name} can only be called during Env::eval_async"),
+        )
+        .into();
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    if !host.send(Message::Await(future, sender)) {
+        return Expr::error(format!("The evaluation calling {name} was cancelled"));
+    }
+    drop(host);
+    receiver
+        .recv()
+        .unwrap_or_else(|_| Expr::error(format!("The evaluation calling {name} was cancelled")))
+}
+
+impl Env {
+    /// Bind an async builtin function to a symbol in the environment.
+    ///
+    /// An async builtin is strict: its arguments are evaluated first, and their
+    /// values are passed to the function, which returns a future of its result.
+    /// The future is awaited by the task calling [`Env::eval_async`], so it can
+    /// wait on the host's I/O without blocking its executor.
+    ///
+    /// Calling an async builtin outside of [`Env::eval_async`] returns an error.
+    #[inline]
+    pub fn bind_async_builtin<F, Fut>(&mut self, symbol: &str, f: F)
+    where
+        F: Fn(Vec<Expr>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Expr> + Send + 'static,
+    {
+        let name = symbol.to_string();
+        self.bind_strict_builtin(symbol, move |env, args| match env.eval_all(args) {
+            Ok(args) => await_on_host(&name, Box::pin(f(args))),
+            Err(err) => err,
+        });
+    }
+
+    /// Evaluate an expression asynchronously, awaiting the async builtins it calls.
+    ///
+    /// This behaves like [`Env::eval`], but while an async builtin's future is
+    /// pending, the returned future is too, instead of blocking the thread that
+    /// polls it. The evaluation runs on a thread of its own, and only the futures
+    /// of async builtins are awaited by the caller.
+    ///
+    /// If the returned future is dropped before it completes, the evaluation is
+    /// abandoned: every async builtin it calls afterwards returns an error.
+    /// Use an [`InterruptHandle`](crate::InterruptHandle) to stop it sooner.
+    ///
+    /// ```rust
+    /// use std::{future::Future, pin::pin, sync::Arc, task::{Context, Poll, Wake}};
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// // A mock of some I/O, which is pending the first time it is polled.
+    /// async fn fetch(key: Expr) -> Expr {
+    ///     let mut ready = false;
+    ///     std::future::poll_fn(|cx| {
+    ///         if ready {
+    ///             return Poll::Ready(());
+    ///         }
+    ///         ready = true;
+    ///         cx.waker().wake_by_ref();
+    ///         Poll::Pending
+    ///     })
+    ///     .await;
+    ///     Expr::String(format!("value of {key}"))
+    /// }
+    ///
+    /// // A minimal single-threaded executor.
+    /// fn block_on<T>(future: impl Future<Output = T>) -> T {
+    ///     struct Unpark(std::thread::Thread);
+    ///     impl Wake for Unpark {
+    ///         fn wake(self: Arc<Self>) {
+    ///             self.0.unpark();
+    ///         }
+    ///     }
+    ///     let waker = Arc::new(Unpark(std::thread::current())).into();
+    ///     let mut cx = Context::from_waker(&waker);
+    ///     let mut future = pin!(future);
+    ///     loop {
+    ///         match future.as_mut().poll(&mut cx) {
+    ///             Poll::Ready(result) => return result,
+    ///             Poll::Pending => std::thread::park(),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_async_builtin("fetch", |args| fetch(args[0].clone()));
+    ///
+    /// let result = block_on(env.eval_async(Expr::parse("(fetch 'user)").unwrap()));
+    /// assert_eq!(result, Expr::String("value of user".to_string()));
+    ///
+    /// // Without an async evaluation to await it, the builtin fails.
+    /// assert!(env.eval_str("(fetch 'user)").unwrap().is_err());
+    /// ```
+    pub async fn eval_async(&mut self, expr: Expr) -> Expr {
+        let (sender, receiver) = mpsc::channel();
+        let waker = Arc::new(Mutex::new(None));
+        let host = Host {
+            sender: Some(sender),
+            waker: waker.clone(),
+        };
+
+        let mut env = Env {
+            raised: None,
+            ..self.clone()
+        };
+        let thread = std::thread::Builder::new()
+            .name("sage-lisp-eval".to_string())
+            .stack_size(EVAL_STACK_SIZE)
+            .spawn(move || {
+                let result = with_host(Some(host.clone()), || env.eval(expr));
+                host.send(Message::Done(result));
+            });
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => return EvalError::new(ErrorKind::Io, e.to_string()).into(),
+        };
+
+        let result = loop {
+            // Register the waker before checking for a message,
+            // so a message sent in between still wakes the task.
+            let message = poll_fn(|cx| {
+                *waker.lock().unwrap() = Some(cx.waker().clone());
+                match receiver.try_recv() {
+                    Ok(message) => Poll::Ready(Some(message)),
+                    Err(mpsc::TryRecvError::Empty) => Poll::Pending,
+                    Err(mpsc::TryRecvError::Disconnected) => Poll::Ready(None),
+                }
+            })
+            .await;
+
+            match message {
+                Some(Message::Await(future, reply)) => {
+                    let _ = reply.send(future.await);
+                }
+                Some(Message::Done(result)) => break result,
+                // The thread stopped without a result, so it must have panicked.
+                None => match thread.join() {
+                    Err(panic) => std::panic::resume_unwind(panic),
+                    Ok(()) => unreachable!("the evaluating thread exited without a result"),
+                },
+            }
+        };
+
+        if result.is_err() && self.raised.is_none() {
+            self.raised = Some(Box::new(result.clone()));
+        }
+        result
+    }
+}
@@ -0,0 +1,528 @@
+//! A persistent hash map, stored as a hash array mapped trie.
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{Debug, Formatter, Result as FmtResult},
+    hash::{BuildHasher, Hash, Hasher},
+    iter::FusedIterator,
+    ops::Index,
+    sync::Arc,
+};
+
+/// The number of bits of the hash used at each level of the trie.
+const BITS: u32 = 5;
+
+/// A persistent unordered map, used for [`Expr::Map`](crate::Expr::Map).
+///
+/// The entries are stored in a trie indexed by the bits of their keys' hashes,
+/// where each node has up to 32 children. Cloning a map is O(1), and looking up,
+/// inserting and removing a key are O(log n) with a large base. An updated map
+/// shares all of its nodes with the original, except for the path to the changed entry.
+///
+/// Keys are hashed with a fixed hasher, so the order of iteration only depends
+/// on the keys in the map.
+///
+/// ```rust
+/// use sage_lisp::HashTrieMap;
+///
+/// let mut a: HashTrieMap<i32, i32> = (0..100).map(|i| (i, i * i)).collect();
+/// let b = a.clone();
+/// a.insert(5, 0);
+/// a.remove(&6);
+///
+/// assert_eq!(a.len(), 99);
+/// assert_eq!(a.get(&5), Some(&0));
+/// assert_eq!(b[&5], 25);
+/// assert_eq!(b.get(&6), Some(&36));
+/// ```
+///
+/// Random sequences of operations leave a map with the same entries as a `HashMap`,
+/// including keys whose hashes collide, and copies taken along the way keep the
+/// entries they had:
+///
+/// ```rust
+/// use rand::{rngs::StdRng, Rng, SeedableRng};
+/// use sage_lisp::HashTrieMap;
+/// use std::collections::HashMap;
+/// use std::hash::{Hash, Hasher};
+///
+/// // Every four keys share a hash.
+/// #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// struct Key(u16);
+/// impl Hash for Key {
+///     fn hash<H: Hasher>(&self, state: &mut H) {
+///         (self.0 / 4).hash(state)
+///     }
+/// }
+///
+/// let mut rng = StdRng::seed_from_u64(1);
+/// let (mut map, mut model) = (HashTrieMap::new(), HashMap::new());
+/// let mut copies = vec![];
+/// for i in 0..5000 {
+///     let key = Key(rng.gen_range(0..1000));
+///     match rng.gen_range(0..4) {
+///         0 | 1 => assert_eq!(map.insert(key.clone(), i), model.insert(key.clone(), i)),
+///         2 => assert_eq!(map.remove(&key), model.remove(&key)),
+///         _ => {
+///             if let Some(value) = map.get_mut(&key) {
+///                 *value += 1;
+///             }
+///             if let Some(value) = model.get_mut(&key) {
+///                 *value += 1;
+///             }
+///         }
+///     }
+///     assert_eq!(map.len(), model.len());
+///     assert_eq!(map.get(&key), model.get(&key));
+///     if i % 250 == 0 {
+///         copies.push((map.clone(), model.clone()));
+///     }
+/// }
+/// for (map, model) in copies {
+///     let mut entries: Vec<_> = map.iter().map(|(k, v)| (k.clone(), *v)).collect();
+///     entries.sort();
+///     let mut expected: Vec<_> = model.into_iter().collect();
+///     expected.sort();
+///     assert_eq!(entries, expected);
+/// }
+/// ```
+pub struct HashTrieMap<K, V> {
+    root: Option<Arc<Node<K, V>>>,
+    len: usize,
+}
+
+/// A node of the trie, with an entry for each of the hash fragments set in its bitmap.
+struct Node<K, V> {
+    bitmap: u32,
+    entries: Vec<Entry<K, V>>,
+}
+
+enum Entry<K, V> {
+    /// A single key and value, with the hash of the key.
+    Leaf(u64, Arc<(K, V)>),
+    /// The entries for keys whose hashes are all the same.
+    Collision(u64, Arc<Vec<(K, V)>>),
+    /// The entries for keys that share this fragment of their hashes.
+    Node(Arc<Node<K, V>>),
+}
+
+impl<K, V> Clone for Node<K, V> {
+    fn clone(&self) -> Self {
+        Node {
+            bitmap: self.bitmap,
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<K, V> Clone for Entry<K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            Entry::Leaf(hash, entry) => Entry::Leaf(*hash, entry.clone()),
+            Entry::Collision(hash, entries) => Entry::Collision(*hash, entries.clone()),
+            Entry::Node(node) => Entry::Node(node.clone()),
+        }
+    }
+}
+
+fn hash_of<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The bit for the fragment of a hash used at a level of the trie.
+fn bit(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) & ((1 << BITS) - 1))
+}
+
+impl<K, V> Node<K, V> {
+    /// The position in the entries of the fragment with a bit.
+    fn index(&self, bit: u32) -> usize {
+        (self.bitmap & (bit - 1)).count_ones() as usize
+    }
+
+    /// Create a node holding two entries with different hashes.
+    fn pair(a: Entry<K, V>, a_hash: u64, b: Entry<K, V>, b_hash: u64, shift: u32) -> Self {
+        let (a_bit, b_bit) = (bit(a_hash, shift), bit(b_hash, shift));
+        if a_bit == b_bit {
+            let node = Self::pair(a, a_hash, b, b_hash, shift + BITS);
+            Node { bitmap: a_bit, entries: vec![Entry::Node(Arc::new(node))] }
+        } else {
+            let entries = if a_bit < b_bit { vec![a, b] } else { vec![b, a] };
+            Node { bitmap: a_bit | b_bit, entries }
+        }
+    }
+
+    fn get<Q>(&self, hash: u64, key: &Q) -> Option<&(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let mut node = self;
+        let mut shift = 0;
+        loop {
+            let bit = bit(hash, shift);
+            if node.bitmap & bit == 0 {
+                return None;
+            }
+            match &node.entries[node.index(bit)] {
+                Entry::Leaf(h, entry) => return (*h == hash && entry.0.borrow() == key).then_some(&**entry),
+                Entry::Collision(h, entries) if *h == hash => {
+                    return entries.iter().find(|(k, _)| k.borrow() == key)
+                }
+                Entry::Collision(..) => return None,
+                Entry::Node(child) => {
+                    node = child;
+                    shift += BITS;
+                }
+            }
+        }
+    }
+}
+
+impl<K: Eq + Clone, V: Clone> Node<K, V> {
+    fn insert(&mut self, hash: u64, shift: u32, key: K, value: V) -> Option<V> {
+        let bit = bit(hash, shift);
+        let index = self.index(bit);
+        if self.bitmap & bit == 0 {
+            self.bitmap |= bit;
+            self.entries.insert(index, Entry::Leaf(hash, Arc::new((key, value))));
+            return None;
+        }
+        let entry = match &mut self.entries[index] {
+            Entry::Node(child) => return Arc::make_mut(child).insert(hash, shift + BITS, key, value),
+            Entry::Leaf(h, entry) if *h == hash && entry.0 == key => {
+                let old = std::mem::replace(entry, Arc::new((key, value)));
+                return Some(Arc::unwrap_or_clone(old).1);
+            }
+            Entry::Collision(h, entries) if *h == hash => {
+                let entries = Arc::make_mut(entries);
+                if let Some((_, slot)) = entries.iter_mut().find(|(k, _)| *k == key) {
+                    return Some(std::mem::replace(slot, value));
+                }
+                entries.push((key, value));
+                return None;
+            }
+            Entry::Leaf(h, entry) if *h == hash => {
+                let (k, v) = &**entry;
+                Entry::Collision(hash, Arc::new(vec![(k.clone(), v.clone()), (key, value)]))
+            }
+            existing => {
+                // The hashes differ, so they can be told apart further down the trie.
+                let existing_hash = match existing {
+                    Entry::Leaf(h, _) | Entry::Collision(h, _) => *h,
+                    Entry::Node(_) => unreachable!("nodes are handled above"),
+                };
+                let leaf = Entry::Leaf(hash, Arc::new((key, value)));
+                Entry::Node(Arc::new(Self::pair(existing.clone(), existing_hash, leaf, hash, shift + BITS)))
+            }
+        };
+        self.entries[index] = entry;
+        None
+    }
+
+    fn remove<Q>(&mut self, hash: u64, shift: u32, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let bit = bit(hash, shift);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        let index = self.index(bit);
+        match &mut self.entries[index] {
+            // The leaf is removed below, once it's no longer borrowed.
+            Entry::Leaf(h, entry) if *h == hash && entry.0.borrow() == key => {}
+            Entry::Leaf(..) => return None,
+            Entry::Collision(h, _) if *h != hash => return None,
+            Entry::Collision(_, entries) => {
+                let position = entries.iter().position(|(k, _)| k.borrow() == key)?;
+                let entries = Arc::make_mut(entries);
+                let (_, value) = entries.remove(position);
+                if entries.len() == 1 {
+                    let last = entries.pop().expect("the last colliding entry");
+                    self.entries[index] = Entry::Leaf(hash, Arc::new(last));
+                }
+                return Some(value);
+            }
+            Entry::Node(child) => {
+                let child = Arc::make_mut(child);
+                let value = child.remove(hash, shift + BITS, key)?;
+                // A node with a single entry is replaced by that entry,
+                // unless it's another node for a longer shared fragment.
+                if let [Entry::Leaf(..) | Entry::Collision(..)] = child.entries[..] {
+                    let last = child.entries.pop().expect("the last entry");
+                    self.entries[index] = last;
+                }
+                return Some(value);
+            }
+        }
+        self.bitmap &= !bit;
+        match self.entries.remove(index) {
+            Entry::Leaf(_, entry) => Some(Arc::unwrap_or_clone(entry).1),
+            _ => unreachable!("only leaves are removed directly"),
+        }
+    }
+}
+
+impl<K, V> HashTrieMap<K, V> {
+    /// Create an empty map.
+    #[inline]
+    pub const fn new() -> Self {
+        Self { root: None, len: 0 }
+    }
+
+    /// The number of entries in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is the map empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get a reference to the value for a key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    /// Get references to the key and value of the entry for a key.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (k, v) = self.root.as_ref()?.get(hash_of(key), key)?;
+        Some((k, v))
+    }
+
+    /// Is there an entry for a key?
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Iterate over the entries of the map, in no particular order.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            stack: self.root.iter().map(|root| root.entries.iter()).collect(),
+            collision: Default::default(),
+            remaining: self.len,
+        }
+    }
+
+    /// Iterate over the keys of the map, in no particular order.
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /// Iterate over the values of the map, in no particular order.
+    pub fn values(&self) -> impl ExactSizeIterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> HashTrieMap<K, V> {
+    /// Insert a value for a key, and return the value it replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let root = self.root.get_or_insert_with(|| Arc::new(Node { bitmap: 0, entries: Vec::new() }));
+        let old = Arc::make_mut(root).insert(hash_of(&key), 0, key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Remove the entry for a key, and return its value.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        // Don't copy the path to a key that isn't there.
+        if !self.contains_key(key) {
+            return None;
+        }
+        let root = Arc::make_mut(self.root.as_mut()?);
+        let value = root.remove(hash_of(key), 0, key)?;
+        self.len -= 1;
+        if self.len == 0 {
+            self.root = None;
+        }
+        Some(value)
+    }
+
+    /// Get a mutable reference to the value for a key.
+    ///
+    /// The entry and the nodes on the path to it are copied first, if they are shared.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let hash = hash_of(key);
+        let mut node = Arc::make_mut(self.root.as_mut()?);
+        let mut shift = 0;
+        loop {
+            let bit = bit(hash, shift);
+            let index = node.index(bit);
+            match &mut node.entries[index] {
+                Entry::Leaf(_, entry) => return Some(&mut Arc::make_mut(entry).1),
+                Entry::Collision(_, entries) => {
+                    let entries = Arc::make_mut(entries);
+                    return entries.iter_mut().find(|(k, _)| k.borrow() == key).map(|(_, v)| v);
+                }
+                Entry::Node(child) => {
+                    node = Arc::make_mut(child);
+                    shift += BITS;
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> Clone for HashTrieMap<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { root: self.root.clone(), len: self.len }
+    }
+}
+
+impl<K, V> Default for HashTrieMap<K, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for HashTrieMap<K, V> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq> PartialEq for HashTrieMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K: Hash + Eq, V: Eq> Eq for HashTrieMap<K, V> {}
+
+impl<K, V, Q> Index<&Q> for HashTrieMap<K, V>
+where
+    K: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> FromIterator<(K, V)> for HashTrieMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Extend<(K, V)> for HashTrieMap<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, S: BuildHasher> From<HashMap<K, V, S>> for HashTrieMap<K, V> {
+    fn from(map: HashMap<K, V, S>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, const N: usize> From<[(K, V); N]> for HashTrieMap<K, V> {
+    fn from(entries: [(K, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a HashTrieMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Clone, V: Clone> IntoIterator for HashTrieMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+/// An iterator over the entries of a [`HashTrieMap`].
+pub struct Iter<'a, K, V> {
+    /// The entries left in each node on the path to the current one.
+    stack: Vec<std::slice::Iter<'a, Entry<K, V>>>,
+    /// The entries left in the current collision.
+    collision: std::slice::Iter<'a, (K, V)>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.collision.next() {
+                self.remaining -= 1;
+                return Some((k, v));
+            }
+            let entries = self.stack.last_mut()?;
+            match entries.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(Entry::Leaf(_, entry)) => {
+                    self.remaining -= 1;
+                    return Some((&entry.0, &entry.1));
+                }
+                Some(Entry::Collision(_, entries)) => self.collision = entries.iter(),
+                Some(Entry::Node(node)) => self.stack.push(node.entries.iter()),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for Iter<'_, K, V> {}
+impl<K, V> FusedIterator for Iter<'_, K, V> {}
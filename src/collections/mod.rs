@@ -0,0 +1,23 @@
+//! # Persistent Collections
+//!
+//! These are the collections behind [`Expr::List`](crate::Expr::List),
+//! [`Expr::Map`](crate::Expr::Map) and [`Expr::Tree`](crate::Expr::Tree).
+//! They are trees of reference counted nodes, so a clone of a collection is O(1),
+//! and an updated copy of a collection shares all but O(log n) of its nodes with
+//! the original. Evaluating expressions copies them freely, so this keeps large
+//! data cheap to pass around and to update.
+//!
+//! - [`Vector`] is a sequence, stored as a balanced tree of chunks of elements.
+//! - [`HashTrieMap`] is an unordered map, stored as a hash array mapped trie.
+//! - [`OrdMap`] is a map ordered by its keys, stored as an AVL tree.
+//!
+//! Updating a collection through `&mut self` only copies the nodes that are
+//! shared with another copy, so building up a collection that isn't shared
+//! is about as fast as with the standard collections.
+pub mod hash_trie_map;
+pub mod ord_map;
+pub mod vector;
+
+pub use hash_trie_map::HashTrieMap;
+pub use ord_map::OrdMap;
+pub use vector::Vector;
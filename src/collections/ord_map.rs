@@ -0,0 +1,517 @@
+//! A persistent map ordered by key, stored as an AVL tree.
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    hash::{Hash, Hasher},
+    iter::FusedIterator,
+    ops::Index,
+    sync::Arc,
+};
+
+/// A persistent map ordered by its keys, used for [`Expr::Tree`](crate::Expr::Tree).
+///
+/// The entries are stored in a balanced binary search tree. Cloning a map is O(1),
+/// and looking up, inserting and removing a key are O(log n). An updated map shares
+/// all of its nodes with the original, except for the path to the changed entry.
+///
+/// ```rust
+/// use sage_lisp::OrdMap;
+///
+/// let mut a = OrdMap::new();
+/// a.insert("b", 2);
+/// a.insert("a", 1);
+/// let b = a.clone();
+/// a.insert("c", 3);
+/// a.remove("a");
+///
+/// assert_eq!(a.iter().collect::<Vec<_>>(), vec![(&"b", &2), (&"c", &3)]);
+/// assert_eq!(b.keys().collect::<Vec<_>>(), vec![&"a", &"b"]);
+/// ```
+///
+/// Random sequences of operations leave a map with the same entries, in the same
+/// order, as a `BTreeMap`, while it grows and while removals shrink and rebalance
+/// it, and copies taken along the way keep the entries they had:
+///
+/// ```rust
+/// use rand::{rngs::StdRng, Rng, SeedableRng};
+/// use sage_lisp::OrdMap;
+/// use std::collections::BTreeMap;
+///
+/// let mut rng = StdRng::seed_from_u64(1);
+/// let (mut map, mut model) = (OrdMap::new(), BTreeMap::new());
+/// let mut copies = vec![];
+/// for i in 0..6000 {
+///     let key = rng.gen_range(0..2000);
+///     // Mostly insert at first, then mostly remove.
+///     let removing = rng.gen_bool(if i < 3000 { 0.2 } else { 0.8 });
+///     if removing {
+///         assert_eq!(map.remove(&key), model.remove(&key));
+///     } else if rng.gen_bool(0.9) {
+///         assert_eq!(map.insert(key, i), model.insert(key, i));
+///     } else if let (Some(value), Some(expected)) = (map.get_mut(&key), model.get_mut(&key)) {
+///         *value += 1;
+///         *expected += 1;
+///     }
+///     assert_eq!(map.len(), model.len());
+///     assert_eq!(map.get(&key), model.get(&key));
+///     assert_eq!(map.first_key_value(), model.first_key_value());
+///     assert_eq!(map.last_key_value(), model.last_key_value());
+///     if i % 250 == 0 {
+///         copies.push((map.clone(), model.clone()));
+///     }
+/// }
+/// for (map, model) in copies {
+///     assert!(map.iter().eq(model.iter()));
+///     assert!(map.keys().rev().eq(model.keys().rev()));
+/// }
+/// ```
+pub struct OrdMap<K, V> {
+    root: Link<K, V>,
+}
+
+type Link<K, V> = Option<Arc<Node<K, V>>>;
+
+#[derive(Clone)]
+struct Node<K, V> {
+    key: K,
+    value: V,
+    left: Link<K, V>,
+    right: Link<K, V>,
+    /// The length of the longest path to a leaf, counting this node.
+    height: u8,
+    /// The number of entries under this node, counting this one.
+    len: usize,
+}
+
+fn height<K, V>(link: &Link<K, V>) -> u8 {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn len<K, V>(link: &Link<K, V>) -> usize {
+    link.as_ref().map_or(0, |node| node.len)
+}
+
+impl<K, V> Node<K, V> {
+    fn new(key: K, value: V, left: Link<K, V>, right: Link<K, V>) -> Self {
+        let mut node = Node { key, value, left, right, height: 0, len: 0 };
+        node.update();
+        node
+    }
+
+    /// Recompute the height and size of this node from its children.
+    fn update(&mut self) {
+        self.height = height(&self.left).max(height(&self.right)) + 1;
+        self.len = len(&self.left) + len(&self.right) + 1;
+    }
+}
+
+impl<K: Clone, V: Clone> Node<K, V> {
+    fn rotate_right(mut node: Arc<Self>) -> Arc<Self> {
+        let n = Arc::make_mut(&mut node);
+        let mut left = n.left.take().expect("rotate a node without a left child");
+        let l = Arc::make_mut(&mut left);
+        n.left = l.right.take();
+        n.update();
+        l.right = Some(node);
+        l.update();
+        left
+    }
+
+    fn rotate_left(mut node: Arc<Self>) -> Arc<Self> {
+        let n = Arc::make_mut(&mut node);
+        let mut right = n.right.take().expect("rotate a node without a right child");
+        let r = Arc::make_mut(&mut right);
+        n.right = r.left.take();
+        n.update();
+        r.left = Some(node);
+        r.update();
+        right
+    }
+
+    /// Restore the balance of a node whose children's heights differ by at most two.
+    fn rebalance(link: &mut Link<K, V>) {
+        let Some(mut node) = link.take() else { return };
+        let balance = height(&node.left) as i16 - height(&node.right) as i16;
+        if balance > 1 {
+            let left = node.left.as_ref().expect("a taller left subtree");
+            if height(&left.left) < height(&left.right) {
+                let n = Arc::make_mut(&mut node);
+                n.left = n.left.take().map(Self::rotate_left);
+            }
+            node = Self::rotate_right(node);
+        } else if balance < -1 {
+            let right = node.right.as_ref().expect("a taller right subtree");
+            if height(&right.right) < height(&right.left) {
+                let n = Arc::make_mut(&mut node);
+                n.right = n.right.take().map(Self::rotate_right);
+            }
+            node = Self::rotate_left(node);
+        }
+        *link = Some(node);
+    }
+
+    fn insert(link: &mut Link<K, V>, key: K, value: V) -> Option<V>
+    where
+        K: Ord,
+    {
+        let Some(node) = link else {
+            *link = Some(Arc::new(Node::new(key, value, None, None)));
+            return None;
+        };
+        let n = Arc::make_mut(node);
+        let old = match key.cmp(&n.key) {
+            Ordering::Less => Self::insert(&mut n.left, key, value),
+            Ordering::Greater => Self::insert(&mut n.right, key, value),
+            Ordering::Equal => return Some(std::mem::replace(&mut n.value, value)),
+        };
+        n.update();
+        Self::rebalance(link);
+        old
+    }
+
+    fn remove<Q>(link: &mut Link<K, V>, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let node = link.as_mut()?;
+        let removed = match key.cmp(node.key.borrow()) {
+            Ordering::Less => {
+                let n = Arc::make_mut(node);
+                let removed = Self::remove(&mut n.left, key);
+                n.update();
+                removed
+            }
+            Ordering::Greater => {
+                let n = Arc::make_mut(node);
+                let removed = Self::remove(&mut n.right, key);
+                n.update();
+                removed
+            }
+            Ordering::Equal => {
+                let node = link.take().expect("the node to remove");
+                let Node { value, left, mut right, .. } = Arc::unwrap_or_clone(node);
+                *link = match (left, right.is_some()) {
+                    (left, false) => left,
+                    (None, true) => right,
+                    (left, true) => {
+                        // Replace the node with the first entry after it.
+                        let (key, next) = Self::pop_first(&mut right);
+                        Some(Arc::new(Node::new(key, next, left, right)))
+                    }
+                };
+                Some(value)
+            }
+        };
+        Self::rebalance(link);
+        removed
+    }
+
+    /// Remove the first entry under a node that isn't empty.
+    fn pop_first(link: &mut Link<K, V>) -> (K, V) {
+        let node = link.as_mut().expect("pop the first entry of an empty tree");
+        if node.left.is_none() {
+            let node = link.take().expect("the first node");
+            let Node { key, value, right, .. } = Arc::unwrap_or_clone(node);
+            *link = right;
+            return (key, value);
+        }
+        let n = Arc::make_mut(node);
+        let first = Self::pop_first(&mut n.left);
+        n.update();
+        Self::rebalance(link);
+        first
+    }
+}
+
+impl<K, V> OrdMap<K, V> {
+    /// Create an empty map.
+    #[inline]
+    pub const fn new() -> Self {
+        Self { root: None }
+    }
+
+    /// The number of entries in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        len(&self.root)
+    }
+
+    /// Is the map empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Get a reference to the value for a key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            link = match key.cmp(node.key.borrow()) {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => return Some(&node.value),
+            };
+        }
+        None
+    }
+
+    /// Is there an entry for a key?
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// The entry with the smallest key, if the map isn't empty.
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next()
+    }
+
+    /// The entry with the largest key, if the map isn't empty.
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next_back()
+    }
+
+    /// Iterate over the entries of the map, in order of their keys.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let mut iter = Iter {
+            front: Vec::new(),
+            back: Vec::new(),
+            remaining: self.len(),
+        };
+        iter.push_left(&self.root);
+        iter.push_right(&self.root);
+        iter
+    }
+
+    /// Iterate over the keys of the map, in order.
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &K> + ExactSizeIterator {
+        self.iter().map(|(k, _)| k)
+    }
+
+    /// Iterate over the values of the map, in order of their keys.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> + ExactSizeIterator {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> OrdMap<K, V> {
+    /// Insert a value for a key, and return the value it replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        Node::insert(&mut self.root, key, value)
+    }
+
+    /// Remove the entry for a key, and return its value.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        // Don't copy the path to a key that isn't there.
+        if !self.contains_key(key) {
+            return None;
+        }
+        Node::remove(&mut self.root, key)
+    }
+
+    /// Get a mutable reference to the value for a key.
+    ///
+    /// The nodes on the path to the entry are copied first, if they are shared.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let mut link = &mut self.root;
+        loop {
+            let node = Arc::make_mut(link.as_mut()?);
+            link = match key.cmp(node.key.borrow()) {
+                Ordering::Less => &mut node.left,
+                Ordering::Greater => &mut node.right,
+                Ordering::Equal => return Some(&mut node.value),
+            };
+        }
+    }
+}
+
+impl<K, V> Clone for OrdMap<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { root: self.root.clone() }
+    }
+}
+
+impl<K, V> Default for OrdMap<K, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Debug, V: Debug> Debug for OrdMap<K, V> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: PartialEq, V: PartialEq> PartialEq for OrdMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<K: Eq, V: Eq> Eq for OrdMap<K, V> {}
+
+impl<K: PartialOrd, V: PartialOrd> PartialOrd for OrdMap<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<K: Ord, V: Ord> Ord for OrdMap<K, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<K: Hash, V: Hash> Hash for OrdMap<K, V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for entry in self {
+            entry.hash(state);
+        }
+    }
+}
+
+impl<K, V, Q> Index<&Q> for OrdMap<K, V>
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> FromIterator<(K, V)> for OrdMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Extend<(K, V)> for OrdMap<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> From<BTreeMap<K, V>> for OrdMap<K, V> {
+    fn from(map: BTreeMap<K, V>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, const N: usize> From<[(K, V); N]> for OrdMap<K, V> {
+    fn from(entries: [(K, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a OrdMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Clone, V: Clone> IntoIterator for OrdMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+/// An iterator over the entries of an [`OrdMap`], in order of their keys.
+pub struct Iter<'a, K, V> {
+    /// The nodes whose entries come next from the front, nearest last.
+    front: Vec<&'a Node<K, V>>,
+    /// The nodes whose entries come next from the back, nearest last.
+    back: Vec<&'a Node<K, V>>,
+    /// The number of entries left between the front and the back.
+    remaining: usize,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn push_left(&mut self, mut link: &'a Link<K, V>) {
+        while let Some(node) = link {
+            self.front.push(node);
+            link = &node.left;
+        }
+    }
+
+    fn push_right(&mut self, mut link: &'a Link<K, V>) {
+        while let Some(node) = link {
+            self.back.push(node);
+            link = &node.right;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = self.front.pop()?;
+        self.push_left(&node.right);
+        self.remaining -= 1;
+        Some((&node.key, &node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = self.back.pop()?;
+        self.push_right(&node.left);
+        self.remaining -= 1;
+        Some((&node.key, &node.value))
+    }
+}
+
+impl<K, V> ExactSizeIterator for Iter<'_, K, V> {}
+impl<K, V> FusedIterator for Iter<'_, K, V> {}
@@ -0,0 +1,799 @@
+//! A persistent vector, stored as a balanced tree of chunks.
+use std::{
+    cmp::Ordering,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    hash::{Hash, Hasher},
+    iter::FusedIterator,
+    ops::{Bound, Index, RangeBounds},
+    sync::{Arc, Weak},
+};
+
+/// The most elements stored together in a leaf of the tree.
+const CHUNK: usize = 64;
+
+/// A persistent sequence of values, used for [`Expr::List`](crate::Expr::List).
+///
+/// The elements are stored in chunks at the leaves of a balanced binary tree,
+/// which is annotated with the number of elements under each node. Cloning a
+/// vector is O(1), and indexing, updating, splitting and concatenating vectors
+/// are O(log n). Adding to either end is amortized O(1).
+///
+/// ```rust
+/// use sage_lisp::Vector;
+///
+/// let mut a: Vector<i32> = (0..1000).collect();
+/// let b = a.clone();
+/// a.set(500, -1);
+/// a.push_front(-2);
+///
+/// assert_eq!(a.len(), 1001);
+/// assert_eq!(a[501], -1);
+/// assert_eq!(b[500], 500);
+/// assert_eq!(a.slice(1..4), Vector::from(vec![0, 1, 2]));
+/// ```
+///
+/// Random sequences of operations leave a vector with the same elements as a `Vec`,
+/// as chunks split and the tree rebalances, and copies taken along the way keep
+/// the elements they had:
+///
+/// ```rust
+/// use rand::{rngs::StdRng, Rng, SeedableRng};
+/// use sage_lisp::Vector;
+///
+/// let mut rng = StdRng::seed_from_u64(1);
+/// let (mut vector, mut model) = (Vector::new(), Vec::new());
+/// let mut copies = vec![];
+/// for i in 0..5000 {
+///     let index = rng.gen_range(0..=model.len());
+///     match rng.gen_range(0..10) {
+///         0 | 1 => {
+///             vector.push_back(i);
+///             model.push(i);
+///         }
+///         2 | 3 => {
+///             vector.push_front(i);
+///             model.insert(0, i);
+///         }
+///         4 => {
+///             vector.insert(index, i);
+///             model.insert(index, i);
+///         }
+///         5 => assert_eq!(vector.pop_back(), model.pop()),
+///         6 => assert_eq!(vector.pop_front(), (!model.is_empty()).then(|| model.remove(0))),
+///         7 if index < model.len() => assert_eq!(vector.remove(index), model.remove(index)),
+///         8 if index < model.len() => {
+///             assert_eq!(vector.set(index, i), std::mem::replace(&mut model[index], i));
+///         }
+///         _ => {
+///             let tail = vector.split_off(index);
+///             let model_tail = model.split_off(index);
+///             assert_eq!(tail.to_vec(), model_tail);
+///             vector.append(tail);
+///             model.extend(model_tail);
+///         }
+///     }
+///     assert_eq!(vector.len(), model.len());
+///     if i % 250 == 0 {
+///         assert_eq!(vector.to_vec(), model);
+///         assert!(model.iter().enumerate().all(|(j, x)| vector.get(j) == Some(x)));
+///         let (start, end) = (index / 2, index);
+///         assert_eq!(vector.slice(start..end).to_vec(), model[start..end]);
+///         copies.push((vector.clone(), model.clone()));
+///     }
+/// }
+/// for (vector, model) in copies {
+///     assert_eq!(vector.iter().cloned().collect::<Vec<_>>(), model);
+/// }
+/// ```
+pub struct Vector<T> {
+    root: Option<Node<T>>,
+}
+
+/// A node of the tree behind a [`Vector`].
+enum Node<T> {
+    /// A chunk of at most [`CHUNK`] elements, which is never empty.
+    Leaf(Arc<Vec<T>>),
+    /// Two subtrees, whose heights differ by at most one.
+    Branch(Arc<Branch<T>>),
+}
+
+pub(crate) struct Branch<T> {
+    left: Node<T>,
+    right: Node<T>,
+    /// The number of elements under this node.
+    len: usize,
+    /// The length of the longest path to a leaf.
+    height: u8,
+}
+
+impl<T> Clone for Node<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Node::Leaf(items) => Node::Leaf(items.clone()),
+            Node::Branch(branch) => Node::Branch(branch.clone()),
+        }
+    }
+}
+
+impl<T> Clone for Branch<T> {
+    fn clone(&self) -> Self {
+        Branch {
+            left: self.left.clone(),
+            right: self.right.clone(),
+            len: self.len,
+            height: self.height,
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn leaf(items: Vec<T>) -> Self {
+        Node::Leaf(Arc::new(items))
+    }
+
+    fn branch(left: Self, right: Self) -> Self {
+        let len = left.len() + right.len();
+        let height = left.height().max(right.height()) + 1;
+        Node::Branch(Arc::new(Branch { left, right, len, height }))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf(items) => items.len(),
+            Node::Branch(branch) => branch.len,
+        }
+    }
+
+    fn height(&self) -> u8 {
+        match self {
+            Node::Leaf(_) => 0,
+            Node::Branch(branch) => branch.height,
+        }
+    }
+
+    /// The subtrees of a branch. This is only called on nodes taller than a leaf.
+    fn children(&self) -> (Self, Self) {
+        match self {
+            Node::Branch(branch) => (branch.left.clone(), branch.right.clone()),
+            Node::Leaf(_) => unreachable!("a leaf has no children"),
+        }
+    }
+
+    fn get(&self, mut index: usize) -> &T {
+        let mut node = self;
+        loop {
+            match node {
+                Node::Leaf(items) => return &items[index],
+                Node::Branch(branch) => {
+                    let n = branch.left.len();
+                    if index < n {
+                        node = &branch.left;
+                    } else {
+                        index -= n;
+                        node = &branch.right;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: Clone> Node<T> {
+    /// Push a value into the last leaf, if it has room.
+    /// Otherwise, the value is given back.
+    fn push_back(&mut self, value: T) -> Option<T> {
+        match self {
+            Node::Leaf(items) if items.len() < CHUNK => {
+                Arc::make_mut(items).push(value);
+                None
+            }
+            Node::Leaf(_) => Some(value),
+            Node::Branch(branch) => {
+                let branch = Arc::make_mut(branch);
+                let rest = branch.right.push_back(value);
+                if rest.is_none() {
+                    branch.len += 1;
+                }
+                rest
+            }
+        }
+    }
+
+    /// Push a value into the first leaf, if it has room.
+    /// Otherwise, the value is given back.
+    fn push_front(&mut self, value: T) -> Option<T> {
+        match self {
+            Node::Leaf(items) if items.len() < CHUNK => {
+                Arc::make_mut(items).insert(0, value);
+                None
+            }
+            Node::Leaf(_) => Some(value),
+            Node::Branch(branch) => {
+                let branch = Arc::make_mut(branch);
+                let rest = branch.left.push_front(value);
+                if rest.is_none() {
+                    branch.len += 1;
+                }
+                rest
+            }
+        }
+    }
+}
+
+/// Join two trees whose heights differ by at most two into a balanced tree.
+fn balance<T>(left: Node<T>, right: Node<T>) -> Node<T> {
+    let (hl, hr) = (left.height(), right.height());
+    if hl > hr + 1 {
+        let (ll, lr) = left.children();
+        if ll.height() >= lr.height() {
+            Node::branch(ll, Node::branch(lr, right))
+        } else {
+            let (lrl, lrr) = lr.children();
+            Node::branch(Node::branch(ll, lrl), Node::branch(lrr, right))
+        }
+    } else if hr > hl + 1 {
+        let (rl, rr) = right.children();
+        if rr.height() >= rl.height() {
+            Node::branch(Node::branch(left, rl), rr)
+        } else {
+            let (rll, rlr) = rl.children();
+            Node::branch(Node::branch(left, rll), Node::branch(rlr, rr))
+        }
+    } else {
+        Node::branch(left, right)
+    }
+}
+
+/// Concatenate two trees of any heights.
+///
+/// The shorter tree is joined to the spine of the taller one at the level
+/// where their heights match, and the path back up is rebalanced.
+fn concat<T: Clone>(left: Node<T>, right: Node<T>) -> Node<T> {
+    let (hl, hr) = (left.height(), right.height());
+    if hl > hr + 1 {
+        let (ll, lr) = left.children();
+        balance(ll, concat(lr, right))
+    } else if hr > hl + 1 {
+        let (rl, rr) = right.children();
+        balance(concat(left, rl), rr)
+    } else {
+        match (&left, &right) {
+            // Merge small leaves, so that the chunks don't fragment.
+            (Node::Leaf(l), Node::Leaf(r)) if l.len() + r.len() <= CHUNK => {
+                let mut items = Vec::with_capacity(l.len() + r.len());
+                items.extend_from_slice(l);
+                items.extend_from_slice(r);
+                Node::leaf(items)
+            }
+            _ => Node::branch(left, right),
+        }
+    }
+}
+
+fn concat_opt<T: Clone>(left: Option<Node<T>>, right: Option<Node<T>>) -> Option<Node<T>> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(concat(left, right)),
+        (left, None) => left,
+        (None, right) => right,
+    }
+}
+
+/// Split a tree into the elements before an index, and the elements from it onwards.
+fn split<T: Clone>(node: &Node<T>, index: usize) -> (Option<Node<T>>, Option<Node<T>>) {
+    if index == 0 {
+        return (None, Some(node.clone()));
+    }
+    if index >= node.len() {
+        return (Some(node.clone()), None);
+    }
+    match node {
+        Node::Leaf(items) => (
+            Some(Node::leaf(items[..index].to_vec())),
+            Some(Node::leaf(items[index..].to_vec())),
+        ),
+        Node::Branch(branch) => {
+            let n = branch.left.len();
+            if index <= n {
+                let (before, after) = split(&branch.left, index);
+                (before, concat_opt(after, Some(branch.right.clone())))
+            } else {
+                let (before, after) = split(&branch.right, index - n);
+                (concat_opt(Some(branch.left.clone()), before), after)
+            }
+        }
+    }
+}
+
+/// Build a balanced tree from a number of subtrees of the same height.
+fn build<T>(nodes: &mut impl Iterator<Item = Node<T>>, count: usize) -> Node<T> {
+    if count == 1 {
+        return nodes.next().expect("missing subtree");
+    }
+    let left = build(nodes, count / 2);
+    let right = build(nodes, count - count / 2);
+    Node::branch(left, right)
+}
+
+impl<T> Vector<T> {
+    /// Create an empty vector.
+    #[inline]
+    pub const fn new() -> Self {
+        Self { root: None }
+    }
+
+    /// The number of elements in the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.root.as_ref().map_or(0, Node::len)
+    }
+
+    /// Is the vector empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Get a reference to the element at an index, if it is in bounds.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        match &self.root {
+            Some(root) if index < root.len() => Some(root.get(index)),
+            _ => None,
+        }
+    }
+
+    /// The first element of the vector, if it isn't empty.
+    #[inline]
+    pub fn first(&self) -> Option<&T> {
+        self.get(0)
+    }
+
+    /// The last element of the vector, if it isn't empty.
+    #[inline]
+    pub fn last(&self) -> Option<&T> {
+        self.len().checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    /// Iterate over references to the elements of the vector.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            front: self.root.iter().collect(),
+            front_items: Default::default(),
+            back: self.root.iter().collect(),
+            back_items: Default::default(),
+            remaining: self.len(),
+        }
+    }
+
+    /// Get a weak reference to the root of the tree, which identifies this vector
+    /// and its clones until one of them is changed. Empty vectors have no identity.
+    pub(crate) fn downgrade(&self) -> Option<WeakVector<T>> {
+        self.root.as_ref().map(|root| match root {
+            Node::Leaf(items) => WeakVector::Leaf(Arc::downgrade(items)),
+            Node::Branch(branch) => WeakVector::Branch(Arc::downgrade(branch)),
+        })
+    }
+}
+
+/// A weak reference to the root of a [`Vector`], from [`Vector::downgrade`].
+///
+/// The reference keeps the root's allocation, so no other vector can have the
+/// same [`id`](WeakVector::id) while it exists.
+pub(crate) enum WeakVector<T> {
+    Leaf(Weak<Vec<T>>),
+    Branch(Weak<Branch<T>>),
+}
+
+impl<T> WeakVector<T> {
+    /// The address of the root, which is the same for every clone of the vector.
+    #[inline]
+    pub(crate) fn id(&self) -> usize {
+        match self {
+            WeakVector::Leaf(items) => items.as_ptr() as *const () as usize,
+            WeakVector::Branch(branch) => branch.as_ptr() as *const () as usize,
+        }
+    }
+
+    /// Is a vector with this root still alive?
+    #[inline]
+    pub(crate) fn is_alive(&self) -> bool {
+        match self {
+            WeakVector::Leaf(items) => items.strong_count() > 0,
+            WeakVector::Branch(branch) => branch.strong_count() > 0,
+        }
+    }
+}
+
+impl<T> Clone for WeakVector<T> {
+    fn clone(&self) -> Self {
+        match self {
+            WeakVector::Leaf(items) => WeakVector::Leaf(items.clone()),
+            WeakVector::Branch(branch) => WeakVector::Branch(branch.clone()),
+        }
+    }
+}
+
+impl<T: Clone> Vector<T> {
+    /// Get a mutable reference to the element at an index, if it is in bounds.
+    ///
+    /// The nodes on the path to the element are copied first, if they are shared.
+    pub fn get_mut(&mut self, mut index: usize) -> Option<&mut T> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut node = self.root.as_mut()?;
+        loop {
+            match node {
+                Node::Leaf(items) => return Arc::make_mut(items).get_mut(index),
+                Node::Branch(branch) => {
+                    let branch = Arc::make_mut(branch);
+                    let n = branch.left.len();
+                    if index < n {
+                        node = &mut branch.left;
+                    } else {
+                        index -= n;
+                        node = &mut branch.right;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace the element at an index, and return the old one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is out of bounds.
+    pub fn set(&mut self, index: usize, value: T) -> T {
+        let len = self.len();
+        match self.get_mut(index) {
+            Some(slot) => std::mem::replace(slot, value),
+            None => panic!("index {index} out of bounds for a vector of length {len}"),
+        }
+    }
+
+    /// Add an element to the end of the vector.
+    pub fn push_back(&mut self, value: T) {
+        self.root = Some(match self.root.take() {
+            None => Node::leaf(vec![value]),
+            Some(mut root) => match root.push_back(value) {
+                None => root,
+                Some(value) => concat(root, Node::leaf(vec![value])),
+            },
+        });
+    }
+
+    /// Add an element to the start of the vector.
+    pub fn push_front(&mut self, value: T) {
+        self.root = Some(match self.root.take() {
+            None => Node::leaf(vec![value]),
+            Some(mut root) => match root.push_front(value) {
+                None => root,
+                Some(value) => concat(Node::leaf(vec![value]), root),
+            },
+        });
+    }
+
+    /// Remove the last element of the vector, and return it.
+    pub fn pop_back(&mut self) -> Option<T> {
+        let last = self.split_off(self.len().checked_sub(1)?);
+        last.first().cloned()
+    }
+
+    /// Remove the first element of the vector, and return it.
+    pub fn pop_front(&mut self) -> Option<T> {
+        let first = self.first().cloned()?;
+        *self = self.split_off(1);
+        Some(first)
+    }
+
+    /// Insert an element at an index, shifting the elements after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is greater than the length of the vector.
+    pub fn insert(&mut self, index: usize, value: T) {
+        let rest = self.split_off(index);
+        self.push_back(value);
+        self.append(rest);
+    }
+
+    /// Remove the element at an index, and return it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is out of bounds.
+    pub fn remove(&mut self, index: usize) -> T {
+        let len = self.len();
+        assert!(index < len, "index {index} out of bounds for a vector of length {len}");
+        let mut rest = self.split_off(index);
+        let value = rest.pop_front().expect("the element to remove");
+        self.append(rest);
+        value
+    }
+
+    /// Move the elements of another vector onto the end of this one.
+    pub fn append(&mut self, other: Self) {
+        self.root = concat_opt(self.root.take(), other.root);
+    }
+
+    /// Split the vector at an index. This keeps the elements before the index,
+    /// and returns a vector of the elements from the index onwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is greater than the length of the vector.
+    pub fn split_off(&mut self, at: usize) -> Self {
+        let len = self.len();
+        assert!(at <= len, "split index {at} out of bounds for a vector of length {len}");
+        let (before, after) = match &self.root {
+            Some(root) => split(root, at),
+            None => (None, None),
+        };
+        self.root = before;
+        Self { root: after }
+    }
+
+    /// Get a copy of a range of the vector, which shares its nodes with this one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Self {
+        let start = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(start <= end, "slice index starts at {start} but ends at {end}");
+        let mut result = self.clone();
+        result.split_off(end);
+        result.split_off(start)
+    }
+
+    /// Copy the elements into a standard vector.
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+}
+
+impl<T> Clone for Vector<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self { root: self.root.clone() }
+    }
+}
+
+impl<T> Default for Vector<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Debug> Debug for Vector<T> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq> PartialEq for Vector<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for Vector<T> {}
+
+impl<T: PartialOrd> PartialOrd for Vector<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<T: Ord> Ord for Vector<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<T: Hash> Hash for Vector<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for item in self {
+            item.hash(state);
+        }
+    }
+}
+
+impl<T> Index<usize> for Vector<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(item) => item,
+            None => panic!("index {index} out of bounds for a vector of length {}", self.len()),
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for Vector<T> {
+    fn from(items: Vec<T>) -> Self {
+        if items.is_empty() {
+            return Self::new();
+        }
+        let count = items.len().div_ceil(CHUNK);
+        let mut items = items.into_iter();
+        let mut leaves = (0..count).map(|_| Node::leaf(items.by_ref().take(CHUNK).collect()));
+        Self { root: Some(build(&mut leaves, count)) }
+    }
+}
+
+impl<T: Clone> From<&[T]> for Vector<T> {
+    fn from(items: &[T]) -> Self {
+        items.to_vec().into()
+    }
+}
+
+impl<T, const N: usize> From<[T; N]> for Vector<T> {
+    fn from(items: [T; N]) -> Self {
+        Vec::from(items).into()
+    }
+}
+
+impl<T: Clone> From<Vector<T>> for Vec<T> {
+    fn from(items: Vector<T>) -> Self {
+        items.into_iter().collect()
+    }
+}
+
+impl<T> FromIterator<T> for Vector<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        iter.into_iter().collect::<Vec<_>>().into()
+    }
+}
+
+impl<T: Clone> Extend<T> for Vector<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.append(iter.into_iter().collect());
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Vector<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: Clone> IntoIterator for Vector<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            remaining: self.len(),
+            stack: self.root.into_iter().collect(),
+            items: Vec::new().into_iter(),
+        }
+    }
+}
+
+/// An iterator over references to the elements of a [`Vector`].
+pub struct Iter<'a, T> {
+    /// The subtrees after the current front leaf, nearest last.
+    front: Vec<&'a Node<T>>,
+    front_items: std::slice::Iter<'a, T>,
+    /// The subtrees before the current back leaf, nearest last.
+    back: Vec<&'a Node<T>>,
+    back_items: std::slice::Iter<'a, T>,
+    /// The number of elements left between the front and the back.
+    remaining: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(item) = self.front_items.next() {
+                self.remaining -= 1;
+                return Some(item);
+            }
+            let mut node = self.front.pop()?;
+            loop {
+                match node {
+                    Node::Leaf(items) => break self.front_items = items.iter(),
+                    Node::Branch(branch) => {
+                        self.front.push(&branch.right);
+                        node = &branch.left;
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> DoubleEndedIterator for Iter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(item) = self.back_items.next_back() {
+                self.remaining -= 1;
+                return Some(item);
+            }
+            let mut node = self.back.pop()?;
+            loop {
+                match node {
+                    Node::Leaf(items) => break self.back_items = items.iter(),
+                    Node::Branch(branch) => {
+                        self.back.push(&branch.left);
+                        node = &branch.right;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> ExactSizeIterator for Iter<'_, T> {}
+impl<T> FusedIterator for Iter<'_, T> {}
+
+/// An iterator that moves the elements out of a [`Vector`].
+///
+/// Elements are moved out of the nodes that aren't shared, and cloned
+/// out of the nodes that are.
+pub struct IntoIter<T> {
+    /// The subtrees after the current leaf, nearest last.
+    stack: Vec<Node<T>>,
+    items: std::vec::IntoIter<T>,
+    remaining: usize,
+}
+
+impl<T: Clone> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.items.next() {
+                self.remaining -= 1;
+                return Some(item);
+            }
+            let mut node = self.stack.pop()?;
+            loop {
+                match node {
+                    Node::Leaf(items) => {
+                        break self.items = Arc::unwrap_or_clone(items).into_iter();
+                    }
+                    Node::Branch(branch) => {
+                        let Branch { left, right, .. } = Arc::unwrap_or_clone(branch);
+                        self.stack.push(right);
+                        node = left;
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: Clone> ExactSizeIterator for IntoIter<T> {}
+impl<T: Clone> FusedIterator for IntoIter<T> {}
@@ -0,0 +1,345 @@
+//! # Bytecode Compiler
+//!
+//! Besides walking expressions directly, an environment can compile them to
+//! bytecode with [`Env::compile`], which is run by a stack-based virtual machine.
+//! Compiled code gives the same results as the tree-walking evaluator, and calls
+//! builtins through the same interface, but it doesn't look up every expression
+//! in the environment before evaluating it, and it reads the parameters of
+//! functions from slots by position, instead of looking them up by name.
+//!
+//! The compiler compiles a few core forms inline:
+//!
+//! - `if` compiles to conditional jumps.
+//! - `do`, and blocks of code, compile to a sequence of instructions.
+//! - `quote` compiles to a constant.
+//! - `lambda` compiles the body of the function ahead of time.
+//!
+//! This only happens when the symbol is bound to the builtin from
+//! [`Env::bind_core_forms`] at compile time. All other calls are resolved when they
+//! run: for functions and [strict builtins](Builtin::with_strict_args), the compiled
+//! code evaluates the arguments itself, and other builtins and macros receive their
+//! arguments unevaluated, as usual.
+//!
+//! Like the tree-walking evaluator, compiled code only looks up symbols in the
+//! environment, unless [atom rebinding](Env::set_atom_rebinding) is enabled when
+//! it is compiled. Even then, only atoms are looked up: a list, tree or map is
+//! always evaluated, even if it is bound.
+//!
+//! With [`Env::set_compiler_enabled`], the programs evaluated by [`Env::eval_str`]
+//! and [`Env::eval_source`] are compiled before they run, and so are the functions
+//! they create.
+use std::sync::{atomic::Ordering, Arc};
+
+use super::{params::is_positional, Env, Expr};
+
+/// A core form that the compiler compiles inline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Form {
+    If,
+    Do,
+    Quote,
+    Lambda,
+}
+
+/// An instruction for the virtual machine.
+///
+/// Operands are indices into the constants of the code, unless noted otherwise.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    /// Push a constant.
+    Const(u32),
+    /// Push the value of a parameter of the function, by its slot.
+    Param(u32),
+    /// Push the value of a symbol or other atom, or the atom itself if it is unbound.
+    Global(u32),
+    /// Discard the value on top of the stack.
+    Pop,
+    /// Jump to the instruction at an index.
+    Jump(u32),
+    /// Pop a condition, and jump to the instruction at an index unless it is `true`.
+    JumpUnless(u32),
+    /// Push a new closure over the current environment, from the functions of the code.
+    Closure(u32),
+    /// Start the call in a constant, with the callee on top of the stack.
+    ///
+    /// If the callee takes its arguments unevaluated, it is called here, and
+    /// execution continues at the `end` index. Otherwise the instructions that
+    /// follow evaluate the arguments for the [`Op::Call`].
+    Dispatch { call: u32, end: u32, tail: bool },
+    /// Call the callee below the arguments on top of the stack.
+    Call { call: u32, argc: u32, tail: bool },
+    /// Build a tree from the values on top of the stack, and the list of keys in a constant.
+    Tree(u32),
+    /// Build a map from the values on top of the stack, and the list of keys in a constant.
+    Map(u32),
+    /// Return the value on top of the stack from the code.
+    Return,
+}
+
+/// An expression compiled to bytecode.
+///
+/// This is created by [`Env::compile`], and evaluated like any other expression.
+#[derive(Debug)]
+pub struct Code {
+    /// The expression the code was compiled from.
+    pub(crate) source: Expr,
+    /// The instructions.
+    pub(crate) ops: Vec<Op>,
+    /// The constant pool.
+    pub(crate) consts: Vec<Expr>,
+    /// The functions created by the code, with their parameters and compiled bodies.
+    pub(crate) functions: Vec<(Vec<Expr>, Arc<Code>)>,
+    /// The parameters stored in slots, if this is the body of a function.
+    pub(crate) params: Vec<Expr>,
+}
+
+impl Code {
+    /// The expression the code was compiled from.
+    #[inline]
+    pub fn source(&self) -> &Expr {
+        &self.source
+    }
+}
+
+/// Compiles an expression to a [`Code`].
+struct Compiler<'a> {
+    /// The environment to look up the core forms in.
+    env: &'a Env,
+    /// The parameters of this function and the functions it's nested in,
+    /// which shadow the core forms.
+    shadowed: Vec<Expr>,
+    /// The code being built.
+    code: Code,
+}
+
+impl<'a> Compiler<'a> {
+    /// Compile the body of a function with the given parameters, or
+    /// a top-level expression if there are none.
+    fn compile(env: &'a Env, source: &Expr, params: &[Expr], shadowed: &[Expr]) -> Code {
+        let mut compiler = Self {
+            env,
+            shadowed: shadowed.iter().chain(params).cloned().collect(),
+            code: Code {
+                source: source.clone(),
+                ops: vec![],
+                consts: vec![],
+                functions: vec![],
+                params: if is_positional(params) {
+                    params.to_vec()
+                } else {
+                    vec![]
+                },
+            },
+        };
+        compiler.expr(source, true);
+        compiler.emit(Op::Return);
+        compiler.code
+    }
+
+    /// Add an instruction, returning its index.
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.ops.push(op);
+        self.code.ops.len() - 1
+    }
+
+    /// Add a constant, returning its index.
+    fn constant(&mut self, expr: Expr) -> u32 {
+        self.code.consts.push(expr);
+        (self.code.consts.len() - 1) as u32
+    }
+
+    /// Point the jump at an index to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.code.ops.len() as u32;
+        match &mut self.code.ops[at] {
+            Op::Jump(end) | Op::JumpUnless(end) | Op::Dispatch { end, .. } => *end = target,
+            op => unreachable!("Cannot patch {op:?}"),
+        }
+    }
+
+    /// Compile an expression. If it is in `tail` position, the value it evaluates
+    /// to is returned from the code, so calls can reuse the current frame.
+    fn expr(&mut self, expr: &Expr, tail: bool) {
+        match expr {
+            Expr::Symbol(_) => match self.code.params.iter().position(|param| param == expr) {
+                Some(slot) => {
+                    self.emit(Op::Param(slot as u32));
+                }
+                None => {
+                    let name = self.constant(expr.clone());
+                    self.emit(Op::Global(name));
+                }
+            },
+            Expr::Quote(value) => {
+                let value = self.constant(*value.clone());
+                self.emit(Op::Const(value));
+            }
+            Expr::Many(exprs) => self.sequence(exprs, tail),
+            Expr::List(items) if !items.is_empty() => self.list(expr, &items.to_vec(), tail),
+            Expr::Tree(t) => {
+                for value in t.values() {
+                    self.expr(value, false);
+                }
+                let keys = self.constant(Expr::List(t.keys().cloned().collect()));
+                self.emit(Op::Tree(keys));
+            }
+            Expr::Map(m) => {
+                let (keys, values): (Vec<_>, Vec<_>) = m.iter().unzip();
+                for value in values {
+                    self.expr(value, false);
+                }
+                let keys = self.constant(Expr::List(keys.into_iter().cloned().collect()));
+                self.emit(Op::Map(keys));
+            }
+            Expr::Function(None, params, body) => self.function(params, body),
+            Expr::Code(code) => self.expr(code.source(), tail),
+            value if self.env.is_atom_rebinding_enabled() => {
+                let name = self.constant(value.clone());
+                self.emit(Op::Global(name));
+            }
+            value => {
+                let value = self.constant(value.clone());
+                self.emit(Op::Const(value));
+            }
+        }
+    }
+
+    /// Compile a sequence of expressions, evaluating to the last one.
+    fn sequence(&mut self, exprs: &[Expr], tail: bool) {
+        match exprs.split_last() {
+            Some((last, init)) => {
+                for expr in init {
+                    self.expr(expr, false);
+                    self.emit(Op::Pop);
+                }
+                self.expr(last, tail);
+            }
+            None => {
+                let none = self.constant(Expr::None);
+                self.emit(Op::Const(none));
+            }
+        }
+    }
+
+    /// Compile a list, which is either a core form or a call.
+    fn list(&mut self, form: &Expr, items: &[Expr], tail: bool) {
+        match (self.core_form(&items[0]), &items[1..]) {
+            (Some(Form::If), [cond, then]) => self.branch(cond, then, None, tail),
+            (Some(Form::If), [cond, then, otherwise]) => self.branch(cond, then, Some(otherwise), tail),
+            (Some(Form::Do), body) => self.sequence(body, tail),
+            (Some(Form::Quote), [value]) => {
+                let value = self.constant(value.clone());
+                self.emit(Op::Const(value));
+            }
+            (Some(Form::Lambda), [Expr::List(params), body, ..]) => self.function(&params.to_vec(), body),
+            _ => self.call(form, items, tail),
+        }
+    }
+
+    /// Get the core form a symbol is bound to, if it isn't shadowed by a parameter.
+    fn core_form(&self, head: &Expr) -> Option<Form> {
+        if !matches!(head, Expr::Symbol(_)) || self.shadowed.contains(head) {
+            return None;
+        }
+        match self.env.get(head) {
+            Some(Expr::Builtin(builtin)) => builtin.form,
+            _ => None,
+        }
+    }
+
+    /// Compile an `if` form.
+    fn branch(&mut self, cond: &Expr, then: &Expr, otherwise: Option<&Expr>, tail: bool) {
+        self.expr(cond, false);
+        let jump_to_otherwise = self.emit(Op::JumpUnless(0));
+        self.expr(then, tail);
+        let jump_to_end = self.emit(Op::Jump(0));
+        self.patch(jump_to_otherwise);
+        self.expr(otherwise.unwrap_or(&Expr::None), tail);
+        self.patch(jump_to_end);
+    }
+
+    /// Compile a call.
+    fn call(&mut self, form: &Expr, items: &[Expr], tail: bool) {
+        self.expr(&items[0], false);
+        let call = self.constant(form.clone());
+        let dispatch = self.emit(Op::Dispatch { call, end: 0, tail });
+        for arg in &items[1..] {
+            self.expr(arg, false);
+        }
+        let argc = (items.len() - 1) as u32;
+        self.emit(Op::Call { call, argc, tail });
+        self.patch(dispatch);
+    }
+
+    /// Compile a function, which is created as a closure when the code runs.
+    fn function(&mut self, params: &[Expr], body: &Expr) {
+        let body = Compiler::compile(self.env, body, params, &self.shadowed);
+        self.code.functions.push((params.to_vec(), Arc::new(body)));
+        self.emit(Op::Closure((self.code.functions.len() - 1) as u32));
+    }
+}
+
+impl Env {
+    /// Compile an expression to bytecode.
+    ///
+    /// The result is an [`Expr::Code`], which gives the same result as the original
+    /// expression when it is evaluated, usually faster. The core forms it uses must be
+    /// bound when it is compiled. See the [module documentation](crate::Code) for more.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_core_forms();
+    /// env.bind_strict_builtin("<", |env, args| {
+    ///     let a = env.eval(args[0].clone());
+    ///     let b = env.eval(args[1].clone());
+    ///     Expr::Bool(a < b)
+    /// });
+    ///
+    /// let expr = Expr::parse("((lambda (x) (if (< x 10) 'small 'large)) 5)").unwrap();
+    /// let code = env.compile(&expr);
+    /// assert_eq!(code.to_string(), expr.to_string());
+    /// assert_eq!(env.eval(code), Expr::symbol("small"));
+    /// ```
+    pub fn compile(&self, expr: &Expr) -> Expr {
+        Expr::Code(Arc::new(Compiler::compile(self, expr, &[], &[])))
+    }
+
+    /// Create a function that closes over this environment.
+    ///
+    /// If the compiler is enabled, the body of the function is compiled.
+    pub(crate) fn closure(&self, params: Vec<Expr>, body: Expr) -> Expr {
+        let body = match body {
+            Expr::Code(_) => body,
+            body if self.is_compiler_enabled() => {
+                Expr::Code(Arc::new(Compiler::compile(self, &body, &params, &[])))
+            }
+            body => body,
+        };
+        Expr::Function(Some(Box::new(self.clone())), params, Box::new(body))
+    }
+
+    /// Enable or disable compiling programs to bytecode before evaluating them.
+    ///
+    /// When enabled, [`Env::eval_str`] and [`Env::eval_source`] compile the programs they
+    /// evaluate, and functions created by `lambda`, `defun` and the like have their bodies
+    /// compiled. The setting is shared by every scope of the environment.
+    pub fn set_compiler_enabled(&mut self, enabled: bool) {
+        self.context.compiler_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Are programs compiled to bytecode before they are evaluated?
+    #[inline]
+    pub fn is_compiler_enabled(&self) -> bool {
+        self.context.compiler_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Compile an expression produced while running compiled code, like a macro expansion.
+    pub(crate) fn compile_code(&self, expr: Expr) -> Arc<Code> {
+        match expr {
+            Expr::Code(code) => code,
+            expr => Arc::new(Compiler::compile(self, &expr, &[], &[])),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! # Errors
+//!
+//! Errors raised during evaluation are represented by [`EvalError`], which is
+//! carried through the interpreter by [`Expr::Err`]. Along with the value that
+//! was raised, an error records what kind of failure it was, and a trace of the
+//! calls it unwound through on its way up the call stack.
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    hash::{Hash, Hasher},
+};
+
+use super::{Expr, Span};
+
+/// The kind of failure that an error represents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    /// A value of the wrong type was supplied to an operation.
+    Type,
+    /// A function was called with the wrong number of arguments.
+    Arity,
+    /// A symbol was called, but has no binding in the environment.
+    Unbound,
+    /// An input or output operation failed.
+    Io,
+    /// An error raised explicitly by the program, with `raise`.
+    User,
+    /// Evaluation took more steps than its [`EvalLimits`](crate::EvalLimits) allow.
+    StepLimit,
+    /// Evaluations were nested deeper than their limits allow.
+    DepthLimit,
+    /// A collection was larger than the limits allow.
+    SizeLimit,
+    /// Evaluation took longer than its limits allow.
+    Timeout,
+    /// Evaluation was stopped with an [`InterruptHandle`](crate::InterruptHandle).
+    Interrupted,
+    /// An escape continuation was called, and is unwinding to the `let/ec` or
+    /// `call/ec` that created it. This is not caught by `try`.
+    Escape,
+    /// A `match` form had no clause whose pattern matched its value,
+    /// or a generic function had no method for the types of its arguments.
+    NoMatch,
+    /// A module could not be imported, because it could not be found or
+    /// parsed, or because it imports itself.
+    Import,
+    /// Any other error raised by a builtin function.
+    Other,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Type => write!(f, "type error"),
+            Self::Arity => write!(f, "arity error"),
+            Self::Unbound => write!(f, "unbound symbol"),
+            Self::Io => write!(f, "io error"),
+            Self::User => write!(f, "user error"),
+            Self::StepLimit => write!(f, "step limit exceeded"),
+            Self::DepthLimit => write!(f, "depth limit exceeded"),
+            Self::SizeLimit => write!(f, "size limit exceeded"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::Escape => write!(f, "escape"),
+            Self::NoMatch => write!(f, "no match"),
+            Self::Import => write!(f, "import error"),
+            Self::Other => write!(f, "error"),
+        }
+    }
+}
+
+/// A call that an error unwound through.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    /// A call to a function defined in lisp.
+    Function {
+        /// The expression that was called, usually the function's name.
+        name: Expr,
+        /// The parameters of the function, with the values they were bound to.
+        args: Vec<(Expr, Expr)>,
+        /// The location of the call, if it is known.
+        span: Option<Span>,
+    },
+    /// A call to a builtin function.
+    Builtin {
+        /// The name of the builtin function.
+        name: String,
+        /// The call expression, with its arguments unevaluated.
+        call: Expr,
+        /// The location of the call, if it is known.
+        span: Option<Span>,
+    },
+}
+
+impl Frame {
+    /// The location of the call, if it is known.
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            Self::Function { span, .. } | Self::Builtin { span, .. } => span.as_ref(),
+        }
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Self::Function { name, args, .. } => {
+                write!(f, "in function {name} (")?;
+                for (i, (param, value)) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{param} = {value}")?;
+                }
+                write!(f, ")")?;
+            }
+            Self::Builtin { name, call, .. } => write!(f, "in builtin {name}: {call}")?,
+        }
+        if let Some(span) = self.span() {
+            write!(f, " at {span}")?;
+        }
+        Ok(())
+    }
+}
+
+/// An error raised during evaluation.
+#[derive(Debug, Clone)]
+pub struct EvalError {
+    /// The kind of failure.
+    pub kind: ErrorKind,
+    /// The value that was raised. For errors raised by builtins,
+    /// this is usually a string describing what went wrong.
+    pub value: Expr,
+    /// The calls that the error unwound through, innermost first.
+    pub trace: Vec<Frame>,
+    /// The location of the innermost call the error was raised in, if it is known.
+    pub span: Option<Span>,
+}
+
+impl EvalError {
+    /// Create a new error of the given kind, with no trace.
+    #[inline]
+    pub fn new(kind: ErrorKind, value: impl Into<Expr>) -> Self {
+        Self {
+            kind,
+            value: value.into(),
+            trace: vec![],
+            span: None,
+        }
+    }
+
+    /// Set the location the error was raised at.
+    #[inline]
+    pub fn at(self, span: Option<Span>) -> Self {
+        Self { span, ..self }
+    }
+
+    /// Get a human readable message describing the error.
+    ///
+    /// Strings are used as the message directly, and other values are printed.
+    pub fn message(&self) -> String {
+        match &self.value {
+            Expr::String(s) => s.clone(),
+            value => value.to_string(),
+        }
+    }
+
+    /// Record a call that the error unwound through.
+    #[inline]
+    pub(crate) fn push_frame(&mut self, frame: Frame) {
+        self.trace.push(frame);
+    }
+}
+
+/// Print the error with its location, kind, message, and trace.
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if let Some(span) = &self.span {
+            write!(f, "{span}: ")?;
+        }
+        write!(f, "{}: {}", self.kind, self.message())?;
+        for frame in &self.trace {
+            write!(f, "\n  {frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Errors are compared by their kind and value. The trace is ignored,
+/// so the same error raised from different places is still equal.
+impl PartialEq for EvalError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.value == other.value
+    }
+}
+
+impl Eq for EvalError {}
+
+impl PartialOrd for EvalError {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EvalError {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.kind
+            .cmp(&other.kind)
+            .then_with(|| self.value.cmp(&other.value))
+    }
+}
+
+impl Hash for EvalError {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+        self.value.hash(state);
+    }
+}
+
+/// Wrap an error in an error expression.
+impl From<EvalError> for Expr {
+    fn from(e: EvalError) -> Self {
+        Self::Err(Box::new(e))
+    }
+}
@@ -0,0 +1,432 @@
+//! # Core Forms
+//!
+//! These are the special forms that are provided by the interpreter itself,
+//! rather than by the standard library of the embedding application.
+//! They depend on the internals of the evaluator, so they are defined here
+//! and bound into an environment with [`Env::bind_core_forms`].
+use super::{compiler::Form, generator, Builtin, Cell, Env, ErrorKind, Expr};
+
+/// Check that a form was called with the expected number of arguments.
+macro_rules! expect_args {
+    ($name:literal, $args:expr, $n:literal) => {
+        if $args.len() != $n {
+            return Expr::error_of(
+                ErrorKind::Arity,
+                format!("{} expected {} arguments, got {}", $name, $n, $args.len()),
+            );
+        }
+    };
+}
+
+impl Env {
+    /// Bind the core special forms of the interpreter into the environment.
+    ///
+    /// This includes:
+    /// - `(if cond then else)`: evaluate `then` if the condition is `true`, and `else`
+    ///   otherwise. The `else` branch is optional, and defaults to `nil`.
+    /// - `(do a b c)`: evaluate the expressions in order, returning the last one.
+    /// - `(quote x)`: return `x` without evaluating it.
+    /// - `(lambda (params) body)`, or `(\(params) body)`: create a function that
+    ///   closes over the current scope. A parameter can be a [pattern](crate::Env::bind_pattern)
+    ///   that unpacks its argument: `(a b)` for a list, `#[key pattern ...]` for the values of
+    ///   keys of a tree, map or record, like `#[name n age a]`, or `[name age]` for the values
+    ///   of the keys with the same names.
+    /// - `(raise value)`: raise an error with the given value.
+    /// - `(try body (catch e handler) (finally cleanup))`: evaluate the body,
+    ///   and if it raises an error, bind the error value to `e` and evaluate
+    ///   the handler instead. The cleanup is always evaluated afterwards.
+    ///   Both the `catch` and `finally` clauses are optional.
+    /// - `(defmacro name (params) body)`: define a macro, which is called with
+    ///   its arguments unevaluated, and returns the code to evaluate in its place.
+    /// - `(quasiquote template)`, or `` `template ``: quote the template, except for
+    ///   the parts in `(unquote x)` or `(unquote-splicing x)`, written `,x` and `,@x`.
+    /// - `(macroexpand form)` and `(macroexpand-1 form)`: expand a macro call fully,
+    ///   or only once, without evaluating the expansion.
+    /// - `(set! name value)`: assign a new value to the nearest existing binding of `name`.
+    /// - `(box value)`, `(unbox b)` and `(set-box! b value)`: create a mutable box,
+    ///   read its value, and replace its value. Every copy of a box shares its value.
+    /// - `(let/ec k body)`: evaluate the body with `k` bound to an escape continuation.
+    ///   Calling `(k value)` in the body returns `value` from the `let/ec` immediately,
+    ///   running the `finally` clauses of any `try` forms it unwinds through.
+    ///   `(call/ec f)` does the same, calling `f` with the continuation.
+    /// - `(generator body)`: create a lazy sequence of the values passed to `(yield value)`
+    ///   by the body. The body is suspended at each `yield`, until the next value is needed.
+    /// - `(match value (pattern body) ...)`: evaluate the body of the first clause whose
+    ///   [pattern](crate::Env::match_pattern) matches the value, with the pattern's
+    ///   variables bound. A clause can have a guard, written `(pattern :when cond body)`.
+    ///   If no clause matches, a `no match` error is raised.
+    /// - `(defrecord Name (fields))`: define a [record](crate::Record) type, binding
+    ///   `make-Name` to construct one from its fields in order, `map->Name` to construct
+    ///   one from a tree or map, `Name?` to check for one, and `Name-field` for each field.
+    /// - `(defgeneric name (params))`: define a [generic function](crate::Env::bind_generic),
+    ///   whose default is the function already bound to the name, if any, and
+    ///   `(defmethod name ((param type) param) body)`: add a method to it, for arguments
+    ///   of the given types. `(type-of value)` returns the name of a value's type.
+    /// - `(import name)`: evaluate a [module](crate::ModuleResolver) once, and bind its exports
+    ///   qualified with its name, like `strings/split`. The options `:as prefix` and
+    ///   `:only (names)` change the prefix, or bind just the listed names, unqualified.
+    ///   `(require name)` returns a tree of the exports instead, and a module can limit its
+    ///   exports with `(export names)`.
+    /// - `(ns name)`: evaluate the rest of the program's top-level forms in a
+    ///   [namespace](crate::Env::namespace), and `(refer names)`: look up the definitions
+    ///   of other namespaces in the current one, after its own.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_core_forms();
+    /// let result = env.eval_str(r#"(try (raise "oops") (catch e e))"#).unwrap();
+    /// assert_eq!(result, Expr::from("oops"));
+    /// let result = env.eval_str("(let/ec k (do (k 1) 2))").unwrap();
+    /// assert_eq!(result, Expr::Int(1));
+    /// let result = env.eval_str("(match '(1 2 3) ((0 &rest _) 'zero) ((_ &rest xs) xs))").unwrap();
+    /// assert_eq!(result, Expr::parse("(2 3)").unwrap());
+    /// ```
+    pub fn bind_core_forms(&mut self) {
+        let builtin = Builtin::new(
+            |env, args| {
+                if !(2..=3).contains(&args.len()) {
+                    return Expr::error_of(
+                        ErrorKind::Arity,
+                        format!("if expected 2 or 3 arguments, got {}", args.len()),
+                    );
+                }
+                let cond = env.eval(args[0].clone());
+                if cond == Expr::Bool(true) {
+                    args[1].clone()
+                } else {
+                    args.get(2).cloned().unwrap_or_default()
+                }
+            },
+            "if",
+        );
+        self.bind_symbol("if", Expr::Builtin(builtin.with_lazy_eval(true).with_form(Form::If)));
+
+        let builtin = Builtin::new(|_, args| Expr::Many(args.into()), "do");
+        self.bind_symbol("do", Expr::Builtin(builtin.with_lazy_eval(true).with_form(Form::Do)));
+
+        let builtin = Builtin::new(
+            |_, args| {
+                expect_args!("quote", args, 1);
+                args[0].clone()
+            },
+            "quote",
+        );
+        self.bind_symbol("quote", Expr::Builtin(builtin.with_form(Form::Quote)));
+
+        let builtin = Builtin::new(
+            |env, args| {
+                if args.len() < 2 {
+                    return Expr::error_of(
+                        ErrorKind::Arity,
+                        format!("lambda expected at least 2 arguments, got {}", args.len()),
+                    );
+                }
+                match &args[0] {
+                    Expr::List(params) => env.closure(params.to_vec(), args[1].clone()),
+                    params => Expr::error_of(ErrorKind::Type, format!("Invalid params {params}")),
+                }
+            },
+            "lambda",
+        );
+        let lambda = Expr::Builtin(builtin.with_form(Form::Lambda));
+        self.bind_symbol("lambda", lambda.clone());
+        self.bind_symbol("\\", lambda);
+
+        self.bind_strict_builtin("raise", |env, args| {
+            let value = match args.first() {
+                Some(value) => env.eval(value.clone()),
+                None => Expr::None,
+            };
+            match value {
+                // Re-raising a caught error keeps the original error value.
+                Expr::Err(_) => value,
+                value => Expr::error_of(ErrorKind::User, value),
+            }
+        });
+
+        self.bind_builtin("try", |env, args| {
+            if args.is_empty() {
+                return Expr::error("try expects a body");
+            }
+
+            let mut catch = None;
+            let mut finally = None;
+            for clause in &args[1..] {
+                match clause {
+                    Expr::List(clause) if clause.first() == Some(&Expr::symbol("catch")) => {
+                        match clause.get(1) {
+                            Some(name @ Expr::Symbol(_)) => {
+                                catch = Some((name.clone(), clause.iter().skip(2).cloned().collect::<Vec<_>>()))
+                            }
+                            _ => return Expr::error(format!("Invalid catch clause {}", Expr::List(clause.clone()))),
+                        }
+                    }
+                    Expr::List(clause) if clause.first() == Some(&Expr::symbol("finally")) => {
+                        finally = Some(clause.iter().skip(1).cloned().collect::<Vec<_>>());
+                    }
+                    clause => return Expr::error(format!("Invalid try clause {clause}")),
+                }
+            }
+
+            let mut result = env.eval(args[0].clone());
+            // The error is handled here, so don't let it short-circuit the call.
+            env.take_raised();
+
+            // Escapes pass through to their continuation, after the cleanup.
+            let caught = matches!(&result, Expr::Err(err) if err.kind != ErrorKind::Escape);
+            if let (true, Expr::Err(err), Some((name, handler))) = (caught, &result, catch) {
+                let mut handler_env = env.new_scope();
+                handler_env.bind(name, err.value.clone());
+                result = Expr::None;
+                for e in handler {
+                    result = handler_env.eval(e);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+
+            if let Some(cleanup) = finally {
+                for e in cleanup {
+                    // An error in the cleanup replaces the result of the body.
+                    let cleanup_result = env.eval(e);
+                    env.take_raised();
+                    if cleanup_result.is_err() {
+                        return cleanup_result;
+                    }
+                }
+            }
+
+            result
+        });
+
+        self.bind_builtin("defmacro", |env, args| {
+            if args.len() < 3 {
+                return Expr::error_of(
+                    ErrorKind::Arity,
+                    format!("defmacro expected at least 3 arguments, got {}", args.len()),
+                );
+            }
+            let name = args[0].clone();
+            let params = match &args[1] {
+                Expr::List(params) => params.to_vec(),
+                params => return Expr::error_of(ErrorKind::Type, format!("Invalid params {params}")),
+            };
+            let body = match &args[2..] {
+                [body] => body.clone(),
+                body => Expr::Many(body.to_vec().into()),
+            };
+            let expander = Expr::Function(Some(Box::new(env.clone())), params, Box::new(body));
+            env.bind(name, Expr::Macro(Box::new(expander)));
+            Expr::None
+        });
+
+        self.bind_builtin("quasiquote", |env, args| {
+            expect_args!("quasiquote", args, 1);
+            env.quasiquote(args[0].clone(), 1)
+        });
+        self.bind_builtin("unquote", |_, _| Expr::error("unquote must be used inside quasiquote"));
+        self.bind_builtin("unquote-splicing", |_, _| {
+            Expr::error("unquote-splicing must be used inside quasiquote")
+        });
+
+        self.bind_strict_builtin("macroexpand-1", |env, args| {
+            expect_args!("macroexpand-1", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                form => env.macroexpand_1(form),
+            }
+        });
+        self.bind_strict_builtin("macroexpand", |env, args| {
+            expect_args!("macroexpand", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                form => env.macroexpand(form),
+            }
+        });
+
+        self.bind_builtin("set!", |env, args| {
+            expect_args!("set!", args, 2);
+            let name = match &args[0] {
+                name @ Expr::Symbol(_) => name,
+                name => return Expr::error_of(ErrorKind::Type, format!("Cannot set {name}, expected a symbol")),
+            };
+            let value = env.eval(args[1].clone());
+            if value.is_err() {
+                return value;
+            }
+            match env.assign(name, value) {
+                Ok(()) => Expr::None,
+                Err(e) => e.into(),
+            }
+        });
+
+        self.bind_strict_builtin("box", |env, args| {
+            expect_args!("box", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                value => Expr::Cell(Cell::new(value)),
+            }
+        });
+        self.bind_strict_builtin("unbox", |env, args| {
+            expect_args!("unbox", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::Cell(cell) => cell.get(),
+                Expr::Err(err) => Expr::Err(err),
+                value => Expr::error_of(ErrorKind::Type, format!("Cannot unbox {value}, expected a box")),
+            }
+        });
+        self.bind_strict_builtin("set-box!", |env, args| {
+            expect_args!("set-box!", args, 2);
+            let cell = match env.eval(args[0].clone()) {
+                Expr::Cell(cell) => cell,
+                Expr::Err(err) => return Expr::Err(err),
+                value => return Expr::error_of(ErrorKind::Type, format!("Cannot set {value}, expected a box")),
+            };
+            match env.eval(args[1].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                value => {
+                    cell.set(value);
+                    Expr::None
+                }
+            }
+        });
+
+        self.bind_builtin("let/ec", |env, args| {
+            let name = match args.first() {
+                Some(name @ Expr::Symbol(_)) => name.clone(),
+                _ => return Expr::error_of(ErrorKind::Type, "let/ec expects a name for the continuation"),
+            };
+            let (k, tag) = escape_continuation();
+            let mut scope = env.new_scope();
+            scope.bind(name, k);
+            let mut result = Expr::None;
+            for e in &args[1..] {
+                result = scope.eval(e.clone());
+                if result.is_err() {
+                    break;
+                }
+            }
+            catch_escape(&tag, result)
+        });
+
+        self.bind_strict_builtin("call/ec", |env, args| {
+            expect_args!("call/ec", args, 1);
+            let f = env.eval(args[0].clone());
+            if f.is_err() {
+                return f;
+            }
+            let (k, tag) = escape_continuation();
+            let result = env.eval(f.apply(&[k]));
+            // The escape is handled here, so don't let it short-circuit the call.
+            env.take_raised();
+            catch_escape(&tag, result)
+        });
+
+        self.bind_builtin("generator", |env, args| Expr::Seq(generator::generator(env, args)));
+        self.bind_strict_builtin("yield", |env, args| {
+            expect_args!("yield", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                value => generator::yield_value(value),
+            }
+        });
+
+        self.bind_builtin("match", |env, args| {
+            if args.is_empty() {
+                return Expr::error_of(ErrorKind::Arity, "match expected a value to match");
+            }
+            match env.eval(args[0].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                value => env.eval_match(value, &args[1..]),
+            }
+        });
+
+        self.bind_builtin("defrecord", |env, args| env.define_record(&args));
+
+        self.bind_builtin("defgeneric", |env, args| env.define_generic(&args));
+        self.bind_builtin("defmethod", |env, args| env.define_method(&args));
+        self.bind_strict_builtin("type-of", |env, args| {
+            expect_args!("type-of", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::Err(err) => Expr::Err(err),
+                value => Expr::symbol(value.type_name()),
+            }
+        });
+
+        self.bind_builtin("import", |env, args| env.import(&args));
+        self.bind_strict_builtin("require", |env, args| {
+            expect_args!("require", args, 1);
+            match env.eval(args[0].clone()) {
+                Expr::String(name) => env.require(&name),
+                Expr::Symbol(name) => env.require(name.name()),
+                Expr::Err(err) => Expr::Err(err),
+                other => Expr::error_of(ErrorKind::Type, format!("require expects the name of a module, got {other}")),
+            }
+        });
+        self.bind_builtin("export", |env, args| env.export(args));
+
+        // Programs switch namespaces between their top-level forms, in `Env::eval_program`.
+        self.bind_builtin("ns", |_, _| {
+            Expr::error_of(ErrorKind::Type, "ns can only be used at the top level of a program")
+        });
+        self.bind_builtin("refer", |env, args| {
+            for name in &args {
+                let Expr::Symbol(name) = name else {
+                    return Expr::error_of(ErrorKind::Type, format!("refer expects the names of namespaces, got {name}"));
+                };
+                if let Err(e) = env.refer(name.name()) {
+                    return e.into();
+                }
+            }
+            Expr::None
+        });
+    }
+}
+
+/// Create an escape continuation, and the tag that identifies its escapes.
+///
+/// The tag is a box holding whether the continuation can still be called.
+/// Escapes carry the tag along with the value, so that only the form that
+/// created the continuation catches them.
+fn escape_continuation() -> (Expr, Cell) {
+    let tag = Cell::new(Expr::Bool(true));
+    let k_tag = tag.clone();
+    let k = Builtin::new(
+        move |env, args| {
+            let value = match args.as_slice() {
+                [] => Expr::None,
+                [value] => env.eval(value.clone()),
+                _ => return Expr::error_of(
+                    ErrorKind::Arity,
+                    format!("A continuation expects 1 argument, got {}", args.len()),
+                ),
+            };
+            if value.is_err() {
+                return value;
+            }
+            if k_tag.get() != Expr::Bool(true) {
+                return Expr::error("Cannot call a continuation after its let/ec has returned");
+            }
+            Expr::error_of(ErrorKind::Escape, Expr::List(vec![Expr::Cell(k_tag.clone()), value].into()))
+        },
+        "continuation",
+    );
+    (Expr::Builtin(k.with_strict_args(true)), tag)
+}
+
+/// Return the value passed to a continuation, if the result is an escape to it.
+///
+/// Afterwards, the continuation can no longer be called.
+fn catch_escape(tag: &Cell, result: Expr) -> Expr {
+    tag.set(Expr::Bool(false));
+    match result {
+        Expr::Err(err) if err.kind == ErrorKind::Escape => match &err.value {
+            Expr::List(escape) if escape.len() == 2 && escape[0] == Expr::Cell(tag.clone()) => escape[1].clone(),
+            _ => Expr::Err(err),
+        },
+        result => result,
+    }
+}
@@ -0,0 +1,185 @@
+//! # Generators
+//!
+//! A generator is a lazy sequence whose values are produced by a body of code,
+//! which calls `(yield value)` to hand each value to the consumer. The body is
+//! suspended at each `yield` until the consumer asks for the next value, so it
+//! can produce values forever, or stream them from a paginated source.
+//!
+//! Each iteration over a generator runs its body from the start, on a thread of
+//! its own. The body and the consumer take turns: only one of them runs at once.
+//! If the consumer stops early, the next `yield` unwinds the body, running the
+//! `finally` clauses of any `try` forms it is in.
+use std::{
+    cell::RefCell,
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
+
+use super::{
+    async_eval::{self, EVAL_STACK_SIZE},
+    limits::Usage,
+    Context, Env, ErrorKind, Expr, Seq,
+};
+
+/// A message from a generator's body to its consumer.
+enum Message {
+    /// The body yielded a value, and waits to be resumed.
+    Yield(Expr),
+    /// The body finished, with its result.
+    Done(Expr),
+}
+
+/// The body's end of a running generator.
+struct Yielder {
+    /// The channel for handing values to the consumer.
+    messages: mpsc::SyncSender<Message>,
+    /// The channel the consumer resumes the body through, with the resources
+    /// used by the consumer's evaluation, which the body's steps count towards.
+    resume: mpsc::Receiver<Option<Arc<Usage>>>,
+    /// The state of the environment the body runs in.
+    context: Arc<Context>,
+}
+
+thread_local! {
+    /// The generator whose body is running on this thread, if any.
+    static YIELDER: RefCell<Option<Yielder>> = const { RefCell::new(None) };
+}
+
+/// Create a generator that evaluates the body in a new scope of the environment.
+pub(crate) fn generator(env: &Env, body: Vec<Expr>) -> Seq {
+    let env = env.clone();
+    Seq::from_fn(move || {
+        Box::new(Generator {
+            context: env.context.clone(),
+            start: Some((env.new_scope(), body.clone())),
+            messages: None,
+            resume: None,
+            thread: None,
+        })
+    })
+}
+
+/// Hand a value to the consumer of the generator running on this thread,
+/// and wait until it asks for the next one.
+pub(crate) fn yield_value(value: Expr) -> Expr {
+    YIELDER.with(|yielder| {
+        let yielder = yielder.borrow();
+        let Some(yielder) = yielder.as_ref() else {
+            return Expr::error("yield can only be called in the body of a generator");
+        };
+        let resumed = match yielder.messages.send(Message::Yield(value)) {
+            Ok(()) => yielder.resume.recv(),
+            Err(_) => Err(mpsc::RecvError),
+        };
+        match resumed {
+            Ok(usage) => {
+                yielder.context.budget.set_usage(usage);
+                Expr::None
+            }
+            // The consumer is gone, so unwind the body. This is an escape,
+            // so `try` forms in the body can clean up, but not keep it running.
+            Err(_) => Expr::error_of(ErrorKind::Escape, "The generator was closed"),
+        }
+    })
+}
+
+/// The consumer's end of a generator, which iterates over the values it yields.
+struct Generator {
+    /// The state of the environment the body runs in.
+    context: Arc<Context>,
+    /// The scope and body to evaluate, until the body is started.
+    start: Option<(Env, Vec<Expr>)>,
+    /// The channel the body hands values through, until it finishes.
+    messages: Option<mpsc::Receiver<Message>>,
+    /// The channel for resuming the body after it yields.
+    resume: Option<mpsc::SyncSender<Option<Arc<Usage>>>>,
+    /// The thread evaluating the body, once it is started.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Generator {
+    /// Start evaluating the body on a thread of its own.
+    fn spawn(&mut self, mut env: Env, body: Vec<Expr>) -> Result<(), Expr> {
+        let (message_sender, messages) = mpsc::sync_channel(0);
+        let (resume, resume_receiver) = mpsc::sync_channel(0);
+        // Let the body call async builtins, if the consumer is in an async evaluation.
+        let host = async_eval::current_host();
+        // The body's steps and timeout count towards the consumer's evaluation.
+        let (context, usage) = (self.context.clone(), self.context.budget.usage());
+        let thread = std::thread::Builder::new()
+            .name("sage-lisp-generator".to_string())
+            .stack_size(EVAL_STACK_SIZE)
+            .spawn(move || {
+                let done = message_sender.clone();
+                YIELDER.with(|yielder| {
+                    *yielder.borrow_mut() = Some(Yielder {
+                        messages: message_sender,
+                        resume: resume_receiver,
+                        context: context.clone(),
+                    })
+                });
+                let mut result = Expr::None;
+                context.budget.with_usage(usage, || {
+                    async_eval::with_host(host, || {
+                        for expr in body {
+                            result = env.eval(expr);
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+                    })
+                });
+                let _ = done.send(Message::Done(result));
+            })
+            .map_err(|e| Expr::error_of(ErrorKind::Io, e.to_string()))?;
+        self.thread = Some(thread);
+        self.messages = Some(messages);
+        self.resume = Some(resume);
+        Ok(())
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Expr;
+
+    fn next(&mut self) -> Option<Expr> {
+        if let Some((env, body)) = self.start.take() {
+            if let Err(e) = self.spawn(env, body) {
+                return Some(e);
+            }
+        } else if let Some(resume) = &self.resume {
+            // The body is waiting in `yield`, so let it continue.
+            if resume.send(self.context.budget.usage()).is_err() {
+                self.messages = None;
+            }
+        }
+
+        let message = self.messages.as_ref()?.recv();
+        match message {
+            Ok(Message::Yield(value)) => Some(value),
+            Ok(Message::Done(result)) => {
+                self.messages = None;
+                self.resume = None;
+                // An error in the body ends the sequence with the error.
+                result.is_err().then_some(result)
+            }
+            Err(_) => {
+                self.messages = None;
+                self.resume = None;
+                Some(Expr::error("The generator stopped without finishing"))
+            }
+        }
+    }
+}
+
+impl Drop for Generator {
+    fn drop(&mut self) {
+        // Close the channels, so a body suspended in `yield` unwinds,
+        // and wait for its cleanup to finish before the consumer moves on.
+        self.resume = None;
+        self.messages = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
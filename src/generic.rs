@@ -0,0 +1,302 @@
+//! # Generic Functions
+//!
+//! A generic function has methods for different types of arguments, and calls the
+//! method that fits the types of the arguments it is called with. This lets code
+//! extend a function for its own types, like records, without changing the function.
+//!
+//! ```lisp
+//! (defrecord Point (x y))
+//! (defgeneric area (shape))
+//! (defmethod area ((s Point)) 0)
+//! (defmethod area ((s list)) (len s))
+//!
+//! ;; Builtins bound with `Env::bind_generic`, like `+` and `len`, can be extended too.
+//! ;; `+` is also bound to `add`, since `defmethod +` would be read as infix addition.
+//! (defmethod add ((a Point) (b Point))
+//!     (make-Point (+ (Point-x a) (Point-x b)) (+ (Point-y a) (Point-y b))))
+//! ```
+//!
+//! A method only fits calls with a number of arguments its parameters accept. A required
+//! parameter written `(name type)` only accepts arguments of that type, as named by
+//! `type-of`, or of a category of types from [`match` patterns](crate::Env::match_pattern),
+//! like `number`. Other parameters accept anything. When several methods fit, the one
+//! whose first parameters are the most specific is called: an exact type beats a
+//! category, which beats no type at all, and then a method without `&rest` or `&key`
+//! parameters beats one with them. If no method fits, the generic function's default
+//! is called, or an error is returned if it has none.
+//!
+//! Methods with the same parameter types, but which accept different numbers of
+//! arguments, are separate methods. Defining a method with the same types and
+//! numbers of arguments as another replaces it.
+use std::sync::{Arc, RwLock};
+
+use super::{matching::has_type, Builtin, Env, ErrorKind, EvalError, Expr, Symbol};
+
+/// The numbers of arguments a method accepts.
+#[derive(Clone, Copy, PartialEq)]
+struct Arity {
+    /// The number of required parameters.
+    min: usize,
+    /// The most arguments accepted, or `None` if there are `&rest` or `&key` parameters.
+    max: Option<usize>,
+}
+
+impl Arity {
+    /// Does the method accept this many arguments?
+    #[inline]
+    fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+/// A method of a generic function.
+#[derive(Clone)]
+struct Method {
+    /// The type that each parameter accepts, or `None` if it accepts anything.
+    types: Vec<Option<Symbol>>,
+    /// The numbers of arguments the method accepts.
+    arity: Arity,
+    /// The function implementing the method.
+    f: Expr,
+}
+
+impl Method {
+    /// How specifically does the method fit the arguments, if it fits them at all?
+    ///
+    /// Each parameter is ranked 2 for an exact type, 1 for a category of types,
+    /// and 0 for any type. Rankings are compared from the first parameter, and
+    /// ties go to a method with a fixed number of parameters.
+    fn fit(&self, args: &[Expr]) -> Option<(Vec<u8>, bool)> {
+        if !self.arity.accepts(args.len()) {
+            return None;
+        }
+        let ranks = self
+            .types
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| match (ty, args.get(i)) {
+                (None, _) => Some(0),
+                (Some(ty), Some(arg)) if arg.type_name() == ty.name() => Some(2),
+                (Some(ty), Some(arg)) if has_type(ty.name(), arg) == Some(true) => Some(1),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()?;
+        Some((ranks, self.arity.max.is_some()))
+    }
+}
+
+/// The methods of a generic function.
+pub(crate) struct Generic {
+    /// The name of the generic function.
+    name: Symbol,
+    /// The methods, in the order they were defined.
+    methods: RwLock<Vec<Method>>,
+    /// The function called when no method fits the arguments, if any.
+    default: Option<Expr>,
+}
+
+impl Generic {
+    /// Call the method that fits the arguments, evaluating them first.
+    fn call(&self, env: &mut Env, args: Vec<Expr>) -> Expr {
+        let values = match env.eval_all(args) {
+            Ok(values) => values,
+            Err(err) => return err,
+        };
+        let method = {
+            let methods = self.methods.read().unwrap();
+            let mut best: Option<((Vec<u8>, bool), &Method)> = None;
+            for method in methods.iter() {
+                if let Some(fit) = method.fit(&values) {
+                    if best.as_ref().is_none_or(|(best, _)| fit > *best) {
+                        best = Some((fit, method));
+                    }
+                }
+            }
+            best.map(|(_, method)| method.f.clone())
+        };
+        let Some(f) = method.or_else(|| self.default.clone()) else {
+            let types = Expr::List(values.iter().map(|v| Expr::symbol(v.type_name())).collect());
+            return Expr::error_of(
+                ErrorKind::NoMatch,
+                format!("{} has no method for the types {types}", self.name.name()),
+            );
+        };
+        // Quote the values, so they aren't evaluated again. Literals evaluate to themselves anyway.
+        let args: Vec<Expr> = values
+            .into_iter()
+            .map(|v| if v.is_literal() { v } else { v.quote() })
+            .collect();
+        match f {
+            // Call builtins directly, so they don't appear twice in error traces.
+            Expr::Builtin(f) => f.apply(env, args),
+            f => env.eval(f.apply(&args)),
+        }
+    }
+
+    /// Add a method, replacing any method for the same types and numbers of arguments.
+    fn add_method(&self, types: Vec<Option<Symbol>>, arity: Arity, f: Expr) {
+        let mut methods = self.methods.write().unwrap();
+        match methods.iter_mut().find(|method| method.types == types && method.arity == arity) {
+            Some(method) => method.f = f,
+            None => methods.push(Method { types, arity, f }),
+        }
+    }
+}
+
+/// Create the builtin for a new generic function, with an optional default.
+fn generic_builtin(name: &str, default: Option<Expr>) -> Expr {
+    let generic = Arc::new(Generic {
+        name: Symbol::new(name),
+        methods: RwLock::default(),
+        default,
+    });
+    let dispatch = generic.clone();
+    let mut builtin = Builtin::new(move |env, args| dispatch.call(env, args), name).with_strict_args(true);
+    builtin.generic = Some(generic);
+    Expr::Builtin(builtin)
+}
+
+impl Env {
+    /// Bind a generic function to a symbol, with a default for arguments no method fits.
+    ///
+    /// The default is a strict builtin: it is called like one bound with
+    /// [`Env::bind_strict_builtin`]. Methods can be added with [`Env::bind_method`]
+    /// from Rust, or with `defmethod` from lisp.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_core_forms();
+    /// env.bind_generic("describe", |_, _| Expr::from("something"));
+    /// env.bind_method("describe", &["int"], |_, _| Expr::from("an integer")).unwrap();
+    /// env.eval_str("(defmethod describe ((s string)) (quote a-string))").unwrap();
+    ///
+    /// assert_eq!(env.eval_str("(describe 5)").unwrap(), Expr::from("an integer"));
+    /// assert_eq!(env.eval_str(r#"(describe "hi")"#).unwrap(), Expr::symbol("a-string"));
+    /// assert_eq!(env.eval_str("(describe nil)").unwrap(), Expr::from("something"));
+    ///
+    /// // Methods are also chosen by the number of arguments they accept.
+    /// env.eval_str("(defmethod describe ((n int) unit) unit)").unwrap();
+    /// assert_eq!(env.eval_str(r#"(describe 5 "cm")"#).unwrap(), Expr::from("cm"));
+    /// assert_eq!(env.eval_str("(describe 5)").unwrap(), Expr::from("an integer"));
+    /// ```
+    pub fn bind_generic(&mut self, symbol: &str, default: impl Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync + 'static) {
+        let default = Builtin::new(default, symbol).with_strict_args(true);
+        self.bind_symbol(symbol, generic_builtin(symbol, Some(Expr::Builtin(default))));
+    }
+
+    /// Add a method implemented in Rust to the generic function bound to a symbol.
+    ///
+    /// The method is called for arguments of the given types, like a strict builtin.
+    /// The type `any` accepts anything. The method also accepts any arguments after
+    /// those, so it can check them itself. An error is returned if the symbol is not
+    /// bound to a generic function.
+    pub fn bind_method(
+        &mut self,
+        symbol: &str,
+        types: &[&str],
+        f: impl Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync + 'static,
+    ) -> Result<(), EvalError> {
+        let generic = self.generic(&Expr::symbol(symbol))?;
+        let arity = Arity {
+            min: types.len(),
+            max: None,
+        };
+        let types = types
+            .iter()
+            .map(|ty| (*ty != "any").then(|| Symbol::new(ty)))
+            .collect();
+        let f = Builtin::new(f, symbol).with_strict_args(true);
+        generic.add_method(types, arity, Expr::Builtin(f));
+        Ok(())
+    }
+
+    /// Get the generic function bound to a symbol.
+    fn generic(&self, symbol: &Expr) -> Result<Arc<Generic>, EvalError> {
+        match self.get(symbol) {
+            Some(Expr::Builtin(Builtin { generic: Some(generic), .. })) => Ok(generic),
+            _ => Err(EvalError::new(
+                ErrorKind::Type,
+                format!("{symbol} is not a generic function, define it with defgeneric"),
+            )),
+        }
+    }
+
+    /// Evaluate a `(defgeneric name (params))` form.
+    ///
+    /// If the name is already bound to a function, it becomes the default of
+    /// the generic function, so builtins can be made generic from lisp.
+    pub(crate) fn define_generic(&mut self, args: &[Expr]) -> Expr {
+        let name = match args {
+            [name @ Expr::Symbol(_)] | [name @ Expr::Symbol(_), Expr::List(_)] => name.clone(),
+            _ => return Expr::error_of(ErrorKind::Type, "defgeneric expects a name and a list of parameters"),
+        };
+        let default = match self.get(&name) {
+            Some(Expr::Builtin(Builtin { generic: Some(_), .. })) => return Expr::None,
+            Some(f @ (Expr::Builtin(_) | Expr::Function(..))) => Some(f),
+            _ => None,
+        };
+        let generic = generic_builtin(&name.to_string(), default);
+        self.bind(name, generic);
+        Expr::None
+    }
+
+    /// Evaluate a `(defmethod name (params) body)` form.
+    pub(crate) fn define_method(&mut self, args: &[Expr]) -> Expr {
+        let (name, params, body) = match args {
+            [name @ Expr::Symbol(_), Expr::List(params), body @ ..] if !body.is_empty() => (name, params, body),
+            _ => return Expr::error_of(ErrorKind::Type, "defmethod expects a name, a list of parameters and a body"),
+        };
+        let generic = match self.generic(name) {
+            Ok(generic) => generic,
+            Err(e) => return e.into(),
+        };
+
+        let (mut names, mut types) = (vec![], vec![]);
+        let (mut optional, mut variadic) = (0, false);
+        // The lambda list keyword of the section of parameters, after the required ones.
+        let mut section: Option<&str> = None;
+        for param in params {
+            match param {
+                Expr::Symbol(keyword) if keyword.name().starts_with('&') => {
+                    section = Some(keyword.name());
+                    variadic |= keyword.name() != "&optional";
+                    names.push(param.clone());
+                }
+                // Only required parameters are dispatched on, so `(name default)` is left alone.
+                Expr::List(typed) if section.is_none() && typed.len() == 2 && matches!(typed[1], Expr::Symbol(_)) => {
+                    let Expr::Symbol(ty) = &typed[1] else { unreachable!() };
+                    names.push(typed[0].clone());
+                    types.push((ty.name() != "any").then(|| ty.clone()));
+                }
+                param => {
+                    names.push(param.clone());
+                    match section {
+                        None => types.push(None),
+                        Some("&optional") => optional += 1,
+                        Some(_) => {}
+                    }
+                }
+            }
+        }
+        let arity = Arity {
+            min: types.len(),
+            max: (!variadic).then_some(types.len() + optional),
+        };
+        while types.last() == Some(&None) {
+            types.pop();
+        }
+
+        let body = match body {
+            [body] => body.clone(),
+            body => Expr::symbol("do").apply(body),
+        };
+        let f = self.eval(Expr::Function(None, names, Box::new(body)));
+        if f.is_err() {
+            return f;
+        }
+        generic.add_method(types, arity, f);
+        Expr::None
+    }
+}
@@ -20,8 +20,19 @@
 //! - **Simple Lisp Interpreter**: A simple core lisp interpreter that can evaluate lisp expressions.
 //! - **Built-in Functions**: Extend the language with new functionality using built-in functions.
 //! - **Symbol Interning**: Symbols are interned to ensure that they are unique and fast to compare.
+//! - **Lexical Scoping**: Function calls get their own scope, chained to the scope where the function was defined.
 //! - **Tail Recursion**: Uses tail recursion to evaluate deeply nested function calls without stack overflow.
 //! - **Lazy Evaluation**: Supports lazy evaluation of expressions, for defining special forms.
+//! - **Lazy Sequences**: Infinite and very large sequences, with values computed on demand, and generators that `yield` them.
+//! - **Macros**: Define new syntax in lisp with `defmacro` and quasiquote templates.
+//! - **Modules**: Split programs into modules with `import` and `export`, found by a pluggable `ModuleResolver`.
+//! - **Namespaces**: Keep definitions apart with `ns`, qualified symbols like `str/split`, and `refer`.
+//! - **Records**: Define record types with `defrecord`, which generates a constructor, a predicate and field accessors.
+//! - **Generic Functions**: Extend functions like `+` and `len` for new types with `defgeneric` and `defmethod`.
+//! - **Pattern Matching**: Match values against literal, list, map and type patterns with guards, using `match`.
+//! - **Parallel Evaluation**: Environments are thread-safe, and lists can be evaluated on several threads with `Env::eval_parallel`.
+//! - **Async Evaluation**: Bind async builtins that await the host's I/O, and evaluate programs with `Env::eval_async`.
+//! - **Bytecode Compiler**: Optionally compile code to bytecode for a stack VM, with the same builtin interface.
 //! - **Serde Integration**: Serialize and deserialize lisp expressions using Serde.
 //! - **Error Handling**: Provides helpful error messages for parsing and evaluation errors.
 //! - **Expanded Syntax**: Introduces infix operators, code block syntax, syntax for hashmaps and ordered maps, and more.
@@ -82,15 +93,13 @@
 //!                 (Expr::Int(a), Expr::Float(b)) => sum = Expr::Float(a as f64 + b),
 //!                 (Expr::Float(a), Expr::Int(b)) => sum = Expr::Float(a + b as f64),
 //!                 (Expr::String(a), Expr::String(b)) => sum = Expr::String(format!("{}{}", a, b)),
-//!                 (Expr::List(a), Expr::List(b)) => {
-//!                     let mut list = a.clone();
-//!                     list.extend(b);
-//!                     sum = Expr::List(list);
+//!                 (Expr::List(mut a), Expr::List(b)) => {
+//!                     a.append(b);
+//!                     sum = Expr::List(a);
 //!                 }
-//!                 (Expr::List(a), b) => {
-//!                     let mut list = a.clone();
-//!                     list.push(b);
-//!                     sum = Expr::List(list);
+//!                 (Expr::List(mut a), b) => {
+//!                     a.push_back(b);
+//!                     sum = Expr::List(a);
 //!                 }
 //!                 // Return an error if the expression is invalid
 //!                 (a, b) => return Expr::error(format!("Invalid expr {} + {}", a, b)),
@@ -139,18 +148,22 @@
 
 use std::{
     // Import BTreeMap and HashMap from the standard library.
-    // BTreeMap is used for tree expressions, which are ordered maps.
-    // HashMap is used for map expressions, which are unordered maps,
-    // and for the symbol table plus environment bindings.
+    // HashMap is used for the symbol table and environment bindings,
+    // and BTreeMap for comparing and hashing unordered maps.
     collections::{BTreeMap, HashMap},
     // Import the necessary types and traits for formatting our output.
     fmt::{Debug, Display, Formatter, Result as FmtResult},
     // Import hash types and traits for hashing our expressions,
-    // to allow them to be used as keys in a hash map.
-    hash::{Hash, Hasher},
+    // to allow them to be used as keys in a hash map, and for
+    // picking the shard of the symbol table that a symbol belongs in.
+    hash::{DefaultHasher, Hash, Hasher},
     // Import atomic reference counting for shared ownership of symbols,
-    // and read-write locks for the symbol table.
-    sync::{Arc, RwLock},
+    // weak references and read-write locks for the symbol table, and
+    // atomic flags for settings shared between threads.
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, RwLock, Weak,
+    },
 };
 
 // Import some nom functions and types for handling parsing errors,
@@ -175,6 +188,64 @@ use lazy_static::lazy_static;
 mod parser;
 pub use parser::*;
 
+// Import the error type for errors raised during evaluation.
+mod error;
+pub use error::*;
+
+// Import the core special forms that are provided by the interpreter itself.
+mod forms;
+
+// Import the limits on evaluation, for running untrusted programs.
+mod limits;
+pub use limits::{EvalLimits, InterruptHandle};
+use limits::Budget;
+
+// Import macro expansion and quasiquote templates.
+mod macros;
+
+// Import the binding of function parameters and destructuring patterns.
+mod params;
+
+// Import pattern matching, for the `match` form.
+mod matching;
+
+// Import the bytecode compiler, and the virtual machine that runs its output.
+mod compiler;
+pub use compiler::Code;
+use compiler::Form;
+mod vm;
+
+// Import async evaluation, for awaiting builtins on the host's executor.
+mod async_eval;
+
+// Import lazy sequences, which compute their values on demand.
+mod seq;
+pub use seq::{Seq, SeqFn, SeqIter};
+
+// Import generators, lazy sequences produced by code that yields values.
+mod generator;
+
+// Import record types, defined with `defrecord`.
+mod record;
+pub use record::Record;
+
+// Import generic functions, which dispatch on the types of their arguments.
+mod generic;
+use generic::Generic;
+
+// Import namespaces, which keep the top-level bindings of programs apart.
+mod namespaces;
+use namespaces::Namespace;
+
+// Import modules, and the resolvers that find their code.
+mod modules;
+pub use modules::{FileResolver, MemoryResolver, ModuleResolver, ModuleSource};
+use modules::Modules;
+
+// Import the persistent collections used for lists, maps and trees.
+pub mod collections;
+pub use collections::{HashTrieMap, OrdMap, Vector};
+
 
 ///////////////////////////////////////////////////////////////
 // SYMBOLS AND SYMBOL TABLE
@@ -184,21 +255,77 @@ pub use parser::*;
  * The symbol table is a hash map that maps strings to symbols.
  * It uses string interning to ensure that symbols are unique,
  * and to allow for fast comparison of symbols.
+ *
+ * The table is split into shards, each behind its own read-write lock,
+ * so that threads interning different names rarely touch the same lock.
+ * Looking up a symbol that already exists only takes a read lock.
+ *
+ * The table only holds weak references to the symbols' names, so a symbol
+ * is freed as soon as the last `Symbol` for it is dropped. The dead entries
+ * left behind are swept out of a shard whenever it has doubled in size since
+ * its last sweep, which keeps the table proportional to the live symbols.
  */
 
+/// The number of shards in the symbol table
+const SYMBOL_SHARDS: usize = 16;
+
+/// One shard of the symbol table
+#[derive(Default)]
+struct SymbolShard {
+    /// The names interned in this shard, and weak references to their symbols
+    symbols: HashMap<Box<str>, Weak<str>>,
+    /// The number of entries left after the last sweep of dead symbols
+    swept_len: usize,
+}
+
+impl SymbolShard {
+    /// Remove the entries for symbols that have been dropped,
+    /// if the shard has doubled in size since it was last swept.
+    fn sweep(&mut self) {
+        if self.symbols.len() >= 2 * self.swept_len.max(32) {
+            self.symbols.retain(|_, symbol| symbol.strong_count() > 0);
+            self.swept_len = self.symbols.len();
+        }
+    }
+}
+
 lazy_static! {
     /// The symbol table that maps strings to symbols
     /// 
     /// This is a global variable that is shared between all environments.
-    /// It is a read-write lock that allows for multiple environments to
-    /// read from the symbol table at the same time, but only one environment
-    /// to write to the symbol table at a time.
-    static ref SYMBOLS: RwLock<HashMap<String, Symbol>> = RwLock::new(HashMap::new());
+    /// Each shard is a read-write lock that allows for multiple environments to
+    /// read from it at the same time, but only one environment to write to it at a time.
+    static ref SYMBOLS: [RwLock<SymbolShard>; SYMBOL_SHARDS] = std::array::from_fn(|_| RwLock::default());
+
+    /// Stands in for the scope of a closure that is bound in that same scope.
+    ///
+    /// See [`Scope::detach`] for why it is needed.
+    static ref ENCLOSING: Arc<Scope> = Arc::default();
+}
+
+/// Get the shard of the symbol table that a name belongs in
+fn symbol_shard(name: &str) -> &'static RwLock<SymbolShard> {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    &SYMBOLS[hasher.finish() as usize % SYMBOL_SHARDS]
 }
 
 /// A symbol that uses string interning
-#[derive(Clone, Hash, Eq, Ord)]
-pub struct Symbol(Arc<String>);
+/// 
+/// Interned symbols are reclaimed once every copy of them has been dropped.
+/// 
+/// ```
+/// # use sage_lisp::Symbol;
+/// let a = Symbol::new("interned");
+/// let b = Symbol::new("interned");
+/// assert_eq!(a, b);
+/// assert!(Symbol::is_interned("interned"));
+/// 
+/// drop((a, b));
+/// assert!(!Symbol::is_interned("interned"));
+/// ```
+#[derive(Clone, Eq)]
+pub struct Symbol(Arc<str>);
 
 impl Symbol {
     /// Create a new symbol from a string
@@ -206,18 +333,39 @@ impl Symbol {
     /// If the symbol already exists in the symbol table, it will return the existing symbol.
     /// Otherwise, it will create a new symbol and add it to the symbol table.
     pub fn new(name: &str) -> Self {
-        // Check if the symbol already exists
-        let mut symbols = SYMBOLS.write().unwrap();
-        // If the symbol already exists, return it
-        if let Some(symbol) = symbols.get(name) {
-            return symbol.clone();
+        let shard = symbol_shard(name);
+        // If the symbol already exists, return it without taking the write lock
+        if let Some(symbol) = shard.read().unwrap().symbols.get(name).and_then(Weak::upgrade) {
+            return Symbol(symbol);
+        }
+
+        let mut shard = shard.write().unwrap();
+        // Check again, in case another thread added the symbol in the meantime
+        if let Some(symbol) = shard.symbols.get(name).and_then(Weak::upgrade) {
+            return Symbol(symbol);
         }
 
         // Otherwise, create a new symbol
-        let symbol = Symbol(Arc::new(name.to_string()));
-        // Add the symbol to the symbol table
-        symbols.insert(name.to_string(), symbol.clone());
-        symbol
+        let symbol: Arc<str> = Arc::from(name);
+        // Add the symbol to the symbol table, making room by sweeping out dropped symbols
+        shard.sweep();
+        shard.symbols.insert(name.into(), Arc::downgrade(&symbol));
+        Symbol(symbol)
+    }
+
+    /// Check if a symbol with the given name is currently interned
+    /// 
+    /// This is false once every copy of the symbol has been dropped.
+    pub fn is_interned(name: &str) -> bool {
+        symbol_shard(name).read().unwrap().symbols.get(name)
+            .is_some_and(|symbol| symbol.strong_count() > 0)
+    }
+
+    /// Get the number of symbols that are currently interned
+    pub fn interned_count() -> usize {
+        SYMBOLS.iter()
+            .map(|shard| shard.read().unwrap().symbols.values().filter(|symbol| symbol.strong_count() > 0).count())
+            .sum()
     }
 
     /// Get the name of the symbol as a string
@@ -226,6 +374,22 @@ impl Symbol {
     pub fn name(&self) -> &str {
         &self.0
     }
+
+    /// Split a qualified symbol, like `str/split`, into its namespace and its name.
+    ///
+    /// Returns `None` if the symbol is not qualified, like `split`, or `/`.
+    ///
+    /// ```
+    /// # use sage_lisp::Symbol;
+    /// assert_eq!(Symbol::new("str/split").qualified(), Some(("str", "split")));
+    /// assert_eq!(Symbol::new("split").qualified(), None);
+    /// assert_eq!(Symbol::new("/").qualified(), None);
+    /// ```
+    pub fn qualified(&self) -> Option<(&str, &str)> {
+        self.0
+            .split_once('/')
+            .filter(|(namespace, name)| !namespace.is_empty() && !name.is_empty())
+    }
 }
 
 /// Convert a &str to a symbol conveniently
@@ -270,17 +434,35 @@ impl PartialEq for Symbol {
     }
 }
 
+/// Hash a symbol by its name.
+///
+/// This agrees with the equality comparison, which falls back
+/// to comparing the names of the symbols.
+impl Hash for Symbol {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
 /// Compare two symbols for ordering.
 /// 
 /// If the two symbols are the same object in memory, they are equal.
 /// Otherwise, it compares the internal strings of the symbols.
-impl PartialOrd for Symbol {
+impl Ord for Symbol {
     #[inline]
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         if Arc::ptr_eq(&self.0, &other.0) {
-            return Some(std::cmp::Ordering::Equal);
+            return std::cmp::Ordering::Equal;
         }
-        self.0.partial_cmp(&other.0)
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Symbol {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -317,13 +499,98 @@ impl Debug for Symbol {
 
 
 /// An environment for evaluating lisp expressions
-#[derive(Debug, Default, Clone)]
+///
+/// The environment is a chain of lexical scopes. Each scope holds its own
+/// bindings, and a link to the scope it was created in. Looking up a value
+/// walks outward from the innermost scope until a binding is found.
+///
+/// Cloning an environment is cheap: the clone shares the same scopes, so
+/// definitions made through one handle are visible through the other.
+/// A clone is not an independent copy of the bindings, and there is no
+/// deep copy: use [`Env::new_scope`] to create a child scope whose bindings
+/// stay local, and leave the parent untouched.
+///
+/// ```rust
+/// use sage_lisp::{Env, Expr};
+///
+/// let mut env = Env::new();
+/// let mut shared = env.clone();
+/// shared.bind_symbol("x", Expr::Int(1));
+/// assert_eq!(env.get(&Expr::symbol("x")), Some(Expr::Int(1)));
+///
+/// let mut child = env.new_scope();
+/// child.bind_symbol("y", Expr::Int(2));
+/// assert_eq!(env.get(&Expr::symbol("y")), None);
+/// ```
+///
+/// Environments and expressions are `Send` and `Sync`, so a clone can be moved
+/// into another thread and evaluate expressions there. Every scope is guarded
+/// by a lock, so threads can define and assign bindings at the same time, but
+/// the order in which their changes happen is up to the scheduler.
+///
+/// ```rust
+/// use sage_lisp::{Env, Expr};
+///
+/// let mut env = Env::new();
+/// env.bind_symbol("x", Expr::Int(5));
+///
+/// let mut worker = env.clone();
+/// let handle = std::thread::spawn(move || worker.eval_str("x").unwrap());
+/// assert_eq!(handle.join().unwrap(), Expr::Int(5));
+/// ```
+#[derive(Default, Clone)]
 pub struct Env {
-    /// The bindings in the environment.
-    /// 
+    /// The innermost scope of the environment.
+    scope: Arc<Scope>,
+    /// The state shared by every scope of the environment.
+    context: Arc<Context>,
+    /// The first error returned by an evaluation through this handle.
+    ///
+    /// Builtins evaluate their own arguments, so this is how the interpreter
+    /// finds out that one of them failed, and short-circuits the call.
+    raised: Option<Box<Expr>>,
+}
+
+/// The state shared by every scope of an environment.
+#[derive(Default)]
+struct Context {
+    /// The locations of the expressions parsed from registered sources.
+    sources: RwLock<SourceMap>,
+    /// The limits on evaluation, and the resources used so far.
+    budget: Budget,
+    /// The handle for interrupting evaluation from another thread.
+    interrupt: InterruptHandle,
+    /// Are programs and closures compiled to bytecode before they are evaluated?
+    compiler_enabled: AtomicBool,
+    /// Can atoms other than symbols, like numbers and strings, be bound to values?
+    atom_rebinding: AtomicBool,
+    /// The resolver for imported modules, and the modules loaded so far.
+    modules: Modules,
+    /// The scopes of the namespaces created so far, by their names.
+    namespaces: RwLock<HashMap<Symbol, Arc<Scope>>>,
+}
+
+/// A single frame of bindings in the environment chain.
+#[derive(Default)]
+struct Scope {
+    /// The parameters of the function call that created this scope.
+    ///
+    /// When a function only takes plain symbols as parameters, they are
+    /// stored by position instead of in the bindings, so that compiled code
+    /// can read them without looking them up.
+    params: Vec<Expr>,
+    /// The values of the parameters, in the same order. A parameter that
+    /// has been unbound is `None`.
+    args: RwLock<Vec<Option<Expr>>>,
+    /// The other bindings in this scope.
+    ///
     /// This can store variable bindings to values, but also bindings from
     /// other atoms. For example, the atom `5` can be bound to the atom `10`.
-    bindings: Arc<HashMap<Expr, Arc<Expr>>>,
+    bindings: RwLock<HashMap<Expr, Expr>>,
+    /// The enclosing scope, if any.
+    parent: Option<Arc<Scope>>,
+    /// The namespace this scope holds the top-level bindings of, if any.
+    namespace: Option<Box<Namespace>>,
 }
 
 impl Env {
@@ -336,6 +603,54 @@ impl Env {
         Self::default()
     }
 
+    /// Create a new child scope of this environment.
+    ///
+    /// Bindings made in the child scope shadow the bindings of this environment,
+    /// and are not visible from it. Lookups that fail in the child scope
+    /// continue in this environment.
+    #[inline]
+    pub fn new_scope(&self) -> Self {
+        Self {
+            scope: Arc::new(Scope {
+                parent: Some(self.scope.clone()),
+                ..Scope::default()
+            }),
+            context: self.context.clone(),
+            raised: None,
+        }
+    }
+
+    /// Create a new child scope with parameters stored by position.
+    ///
+    /// The parameters must be distinct symbols, one for each argument.
+    #[inline]
+    pub(crate) fn new_param_scope(&self, params: Vec<Expr>, args: Vec<Expr>) -> Self {
+        Self {
+            scope: Arc::new(Scope {
+                params,
+                args: RwLock::new(args.into_iter().map(Some).collect()),
+                bindings: RwLock::default(),
+                parent: Some(self.scope.clone()),
+                namespace: None,
+            }),
+            context: self.context.clone(),
+            raised: None,
+        }
+    }
+
+    /// Get the value of a parameter of the innermost scope, by its position.
+    ///
+    /// If the scope has no such parameter, the `name` is looked up instead.
+    #[inline]
+    pub(crate) fn get_param(&self, index: usize, name: &Expr) -> Option<Expr> {
+        if self.scope.params.get(index) == Some(name) {
+            if let Some(value) = &self.scope.args.read().unwrap()[index] {
+                return Some(self.scope.attach(value.clone()));
+            }
+        }
+        self.get(name)
+    }
+
     /// Bind a symbol to a value in the environment.
     #[inline]
     pub fn bind_symbol(&mut self, symbol: &str, value: Expr) {
@@ -343,8 +658,28 @@ impl Env {
     }
 
     /// Bind a builtin function to a symbol in the environment.
+    ///
+    /// The function can be a closure that captures state from the host application.
+    ///
+    /// ```rust
+    /// use std::sync::{Arc, atomic::{AtomicI64, Ordering}};
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let counter = Arc::new(AtomicI64::new(0));
+    /// let mut env = Env::new();
+    /// let count = counter.clone();
+    /// env.bind_builtin("tick", move |_, _| Expr::Int(count.fetch_add(1, Ordering::SeqCst) + 1));
+    ///
+    /// env.eval_str("(tick)").unwrap();
+    /// assert_eq!(env.eval_str("(tick)").unwrap(), Expr::Int(2));
+    /// assert_eq!(counter.load(Ordering::SeqCst), 2);
+    /// ```
     #[inline]
-    pub fn bind_builtin(&mut self, symbol: &'static str, f: fn(&mut Env, Vec<Expr>) -> Expr) {
+    pub fn bind_builtin(
+        &mut self,
+        symbol: &str,
+        f: impl Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync + 'static,
+    ) {
         self.bind_symbol(symbol, Expr::Builtin(Builtin::new(f, symbol)));
     }
 
@@ -354,21 +689,44 @@ impl Env {
     /// after the function is called. Instead, it returns the result without
     /// evaluating it. This is helpful for defining certain special forms.
     #[inline]
-    pub fn bind_lazy_builtin(&mut self, symbol: &'static str, f: fn(&mut Env, Vec<Expr>) -> Expr) {
+    pub fn bind_lazy_builtin(
+        &mut self,
+        symbol: &str,
+        f: impl Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync + 'static,
+    ) {
         self.bind_symbol(
             symbol,
             Expr::Builtin(Builtin::new(f, symbol).with_lazy_eval(true)),
         );
     }
 
+    /// Bind a strict builtin function to a symbol in the environment.
+    ///
+    /// A strict builtin evaluates each of its arguments once, in order, and only
+    /// uses their values, like most operators and library functions. This lets
+    /// [compiled code](Env::compile) evaluate the arguments itself.
+    /// See [`Builtin::with_strict_args`].
+    #[inline]
+    pub fn bind_strict_builtin(
+        &mut self,
+        symbol: &str,
+        f: impl Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync + 'static,
+    ) {
+        self.bind_symbol(
+            symbol,
+            Expr::Builtin(Builtin::new(f, symbol).with_strict_args(true)),
+        );
+    }
+
     /// Merge the bindings of another environment into this one.
     /// 
-    /// This will overwrite any existing bindings with the same key, preferring
-    /// the bindings from the incoming `other` environment.
+    /// All of the bindings visible from `other` are copied into the innermost
+    /// scope of this environment. This will overwrite any existing bindings with
+    /// the same key, preferring the bindings from the incoming `other` environment.
     #[inline]
     pub fn merge(&mut self, other: &Env) {
-        for (k, v) in other.bindings.iter() {
-            self.bind(k.clone(), (**v).clone());
+        for (k, v) in other.get_bindings() {
+            self.bind(k, v);
         }
     }
 
@@ -380,46 +738,136 @@ impl Env {
         let from = from.into();
         let to = to.into();
         if let Some(value) = self.get(&Expr::Symbol(from)) {
-            self.bind(Expr::Symbol(to), value.clone());
+            self.bind(Expr::Symbol(to), value);
         }
     }
 
     /// Get the bindings in the environment.
     /// 
-    /// This returns a copy of the bindings in the environment, from expressions
-    /// to their assigned values. This is useful for debugging and introspection.
+    /// This returns a copy of all the bindings visible from the environment, from
+    /// expressions to their assigned values. Bindings in inner scopes shadow the
+    /// bindings in outer scopes. This is useful for debugging and introspection.
     pub fn get_bindings(&self) -> HashMap<Expr, Expr> {
-        self.bindings
-            .iter()
-            .map(|(k, v)| (k.clone(), (**v).clone()))
-            .collect()
+        let mut scopes = vec![];
+        let mut scope = Some(&self.scope);
+        while let Some(s) = scope {
+            scopes.push(s);
+            scope = s.parent.as_ref();
+        }
+
+        let mut result = HashMap::new();
+        for scope in scopes.into_iter().rev() {
+            for (k, v) in scope.bindings.read().unwrap().iter() {
+                result.insert(k.clone(), scope.attach(v.clone()));
+            }
+            for (k, v) in scope.params.iter().zip(scope.args.read().unwrap().iter()) {
+                if let Some(v) = v {
+                    result.insert(k.clone(), scope.attach(v.clone()));
+                }
+            }
+        }
+        result
     }
 
     /// Bind a value to another value in the environment.
     /// Typically this is used to bind a symbol to a value.
     /// 
-    /// This will overwrite any existing binding for the symbol.
+    /// The binding is made in the innermost scope of the environment.
+    /// This will overwrite any existing binding for the symbol in that scope.
     #[inline]
     pub fn bind(&mut self, symbol: Expr, value: Expr) {
-        if self.get(&symbol) == Some(&value) {
-            return;
+        let value = self.scope.detach(value);
+        match self.scope.param_index(&symbol) {
+            Some(i) => self.scope.args.write().unwrap()[i] = Some(value),
+            None => {
+                self.scope.bindings.write().unwrap().insert(symbol, value);
+            }
         }
-        let bindings = Arc::make_mut(&mut self.bindings);
-        bindings.insert(symbol, Arc::new(value));
+    }
+
+    /// Allow binding atoms other than symbols, like `5` or `"hello"`, to values.
+    ///
+    /// By default, only symbols are looked up when an expression is evaluated, and
+    /// literals evaluate to themselves without touching the environment. When this is
+    /// enabled, every expression is looked up before it is evaluated, so a binding
+    /// made with [`Env::bind`] for any expression takes its place. The setting is shared
+    /// by every scope of the environment.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind(Expr::Int(5), Expr::Int(6));
+    /// assert_eq!(env.eval(Expr::Int(5)), Expr::Int(5));
+    ///
+    /// env.set_atom_rebinding(true);
+    /// assert_eq!(env.eval(Expr::Int(5)), Expr::Int(6));
+    /// ```
+    pub fn set_atom_rebinding(&mut self, enabled: bool) {
+        self.context.atom_rebinding.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Can atoms other than symbols be bound to values?
+    #[inline]
+    pub fn is_atom_rebinding_enabled(&self) -> bool {
+        self.context.atom_rebinding.load(Ordering::Relaxed)
     }
 
     /// Get the value assigned to an expression in the environment.
+    ///
+    /// This searches the scopes from the innermost to the outermost,
+    /// and returns a copy of the first value found.
     #[inline]
-    pub fn get(&self, symbol: &Expr) -> Option<&Expr> {
-        self.bindings.get(symbol).map(|v| v.as_ref())
+    pub fn get(&self, symbol: &Expr) -> Option<Expr> {
+        let mut scope = Some(&self.scope);
+        while let Some(s) = scope {
+            if let Some(value) = s.lookup(symbol) {
+                return Some(value);
+            }
+            // Namespaces see the namespaces they refer to, before the core.
+            if let Some(value) = s.namespace.as_ref().and_then(|ns| ns.lookup_referred(symbol)) {
+                return Some(value);
+            }
+            scope = s.parent.as_ref();
+        }
+        self.get_qualified(symbol)
     }
 
-    /// Remove a binding from the environment. This will unbind the value assigned
-    /// to the expression, if it exists, so that it is no longer accessible.
+    /// Remove a binding from the innermost scope of the environment. This will unbind
+    /// the value assigned to the expression, if it exists, so that it is no longer accessible.
     pub fn unbind(&mut self, symbol: &Expr) {
-        let bindings = Arc::make_mut(&mut self.bindings);
-        bindings.remove(symbol);
-        self.bindings = Arc::new(bindings.clone());
+        match self.scope.param_index(symbol) {
+            Some(i) => self.scope.args.write().unwrap()[i] = None,
+            None => {
+                self.scope.bindings.write().unwrap().remove(symbol);
+            }
+        }
+    }
+
+    /// Assign a new value to an existing binding.
+    ///
+    /// Unlike [`Env::bind`], this updates the binding in the nearest scope that
+    /// has one, so the change is seen by every closure that shares that scope.
+    /// If there is no binding for the symbol, an error is returned.
+    pub fn assign(&mut self, symbol: &Expr, value: Expr) -> Result<(), EvalError> {
+        let mut scope = Some(&self.scope);
+        while let Some(s) = scope {
+            if let Some(i) = s.param_index(symbol) {
+                if let Some(slot) = &mut s.args.write().unwrap()[i] {
+                    *slot = s.detach(value);
+                    return Ok(());
+                }
+            }
+            if let Some(slot) = s.bindings.write().unwrap().get_mut(symbol) {
+                *slot = s.detach(value);
+                return Ok(());
+            }
+            scope = s.parent.as_ref();
+        }
+        Err(EvalError::new(
+            ErrorKind::Unbound,
+            format!("Cannot set {symbol}, it is not defined"),
+        ))
     }
 
     /// Evaluate a string as an expression. This parses the input string and evaluates
@@ -428,7 +876,66 @@ impl Env {
     pub fn eval_str(&mut self, input: impl ToString) -> Result<Expr, String> {
         let input = input.to_string();
         let expr = Expr::parse(&input)?;
-        Ok(self.eval(expr))
+        Ok(self.eval_program(expr))
+    }
+
+    /// Evaluate the source code of a program, such as the contents of a file.
+    ///
+    /// This is like [`Env::eval_str`], but the locations of the parsed expressions are
+    /// recorded under the name `source`, so errors raised by the program say where they
+    /// happened, like `script.lisp:12:5`.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// let result = env.eval_source("script.lisp", "{\n  (undefined 1)\n}").unwrap();
+    /// match result {
+    ///     Expr::Err(e) => assert_eq!(e.span.unwrap().to_string(), "script.lisp:2:3"),
+    ///     _ => panic!("expected an error"),
+    /// }
+    ///
+    /// // The same expression in another source has its own location.
+    /// let result = env.eval_source("other.lisp", "{ 1 (undefined 1) }").unwrap();
+    /// match result {
+    ///     Expr::Err(e) => assert_eq!(e.span.unwrap().to_string(), "other.lisp:1:5"),
+    ///     _ => panic!("expected an error"),
+    /// }
+    /// ```
+    pub fn eval_source(&mut self, source: &str, input: &str) -> Result<Expr, String> {
+        let (expr, spans) = Expr::parse_source(input, source)?;
+        self.add_source_map(spans);
+        Ok(self.eval_program(expr))
+    }
+
+    /// Evaluate a parsed program, compiling it first if the [compiler is enabled](Env::set_compiler_enabled).
+    ///
+    /// This is how [`Env::eval_str`] and [`Env::eval_source`] evaluate their input.
+    pub fn eval_program(&mut self, expr: Expr) -> Expr {
+        // A program that switches namespaces evaluates its top-level forms one at a time.
+        if let Some(forms) = namespaces::split_program(&expr) {
+            return self.eval_namespaced(forms);
+        }
+        let expr = if self.is_compiler_enabled() {
+            self.compile(&expr)
+        } else {
+            expr
+        };
+        self.eval(expr)
+    }
+
+    /// Register the locations of parsed expressions with the environment.
+    ///
+    /// Errors raised while evaluating these expressions will report their location.
+    pub fn add_source_map(&mut self, spans: SourceMap) {
+        if !spans.is_empty() {
+            self.context.sources.write().unwrap().extend(spans);
+        }
+    }
+
+    /// Get the location of an expression in a registered source, if it is known.
+    pub fn span_of(&self, expr: &Expr) -> Option<Span> {
+        self.context.sources.read().unwrap().get(expr).cloned()
     }
 
     /// Evaluate an expression in this environment.
@@ -436,134 +943,446 @@ impl Env {
     /// This will evaluate the expression in the current environment, and return the result.
     /// It uses tail recursion to evaluate the expression, so that it can handle deeply nested
     /// function calls without overflowing the stack.
-    pub fn eval(&mut self, mut expr: Expr) -> Expr {
+    ///
+    /// Function calls are evaluated in a new scope created from the function's closure,
+    /// so the bindings of the caller are never modified by the callee.
+    ///
+    /// Errors propagate: if the function or any argument of a call evaluates to an
+    /// [`Expr::Err`], the call is abandoned and the error is returned instead.
+    ///
+    /// If the environment has [`EvalLimits`], exceeding them also returns an error.
+    pub fn eval(&mut self, expr: Expr) -> Expr {
+        // Quoted values, like the arguments that compiled code passes
+        // to strict builtins, evaluate to themselves.
+        let expr = match expr {
+            Expr::Quote(value) if !value.is_err() => return *value,
+            // Literals evaluate to themselves, unless they might be bound.
+            expr if expr.is_literal() && !self.is_atom_rebinding_enabled() => return expr,
+            expr => expr,
+        };
+        let result = if self.context.budget.is_enabled() {
+            self.eval_limited(expr)
+        } else {
+            self.eval_expr(expr, None)
+        };
+        if result.is_err() && self.raised.is_none() {
+            self.raised = Some(Box::new(result.clone()));
+        }
+        result
+    }
+
+    /// Take the error raised by an evaluation through this handle, if any.
+    ///
+    /// Builtins that handle errors themselves (like `try`) use this to stop
+    /// the error from propagating out of the call.
+    #[inline]
+    pub(crate) fn take_raised(&mut self) -> Option<Expr> {
+        self.raised.take().map(|e| *e)
+    }
+
+    /// Evaluate a list of expressions in order, stopping at the first error.
+    ///
+    /// This is useful for builtin functions that evaluate all of their arguments.
+    pub fn eval_all(&mut self, exprs: impl IntoIterator<Item = Expr>) -> Result<Vec<Expr>, Expr> {
+        let exprs = exprs.into_iter();
+        let mut values = Vec::with_capacity(exprs.size_hint().0);
+        for expr in exprs {
+            let value = self.eval(expr);
+            if value.is_err() {
+                return Err(value);
+            }
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Evaluate a list of expressions in parallel, keeping the order of the results.
+    ///
+    /// The expressions are split between worker threads, and each worker
+    /// evaluates its share in a clone of this environment. If any of them
+    /// evaluates to an error, the error of the first failing expression in
+    /// the list is returned, and the workers after it stop early.
+    ///
+    /// The workers share this environment's [`EvalLimits`] and [`InterruptHandle`].
+    /// The steps they take count towards the budget of the evaluation that called this,
+    /// and an interrupt stops all of them. Each worker has its own depth.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_builtin("square", |env, args| match env.eval(args[0].clone()) {
+    ///     Expr::Int(n) => Expr::Int(n * n),
+    ///     _ => Expr::error("expected an integer"),
+    /// });
+    ///
+    /// let calls = (0..100).map(|n| Expr::parse(&format!("(square {n})")).unwrap());
+    /// let squares = env.eval_parallel(calls).unwrap();
+    /// assert_eq!(squares, (0..100).map(|n| Expr::Int(n * n)).collect::<Vec<_>>());
+    ///
+    /// let calls = vec![Expr::parse("(square 2)").unwrap(), Expr::parse("(square x)").unwrap()];
+    /// assert!(env.eval_parallel(calls).unwrap_err().is_err());
+    /// ```
+    pub fn eval_parallel(&mut self, exprs: impl IntoIterator<Item = Expr>) -> Result<Vec<Expr>, Expr> {
+        let exprs: Vec<Expr> = exprs.into_iter().collect();
+        let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if workers <= 1 || exprs.len() <= 1 {
+            return self.eval_all(exprs);
+        }
+
+        // Each worker takes a contiguous chunk, so the results can be joined back in order.
+        let chunk_size = exprs.len().div_ceil(workers);
+        // The index of the first chunk that failed. Only the chunks after it stop early,
+        // so the error reported is always the one from the first failing expression.
+        let first_failed = AtomicUsize::new(usize::MAX);
+        let results: Vec<Result<Vec<Expr>, Expr>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = exprs
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(index, chunk)| {
+                    let mut env = Env { raised: None, ..self.clone() };
+                    let first_failed = &first_failed;
+                    // Let the workers call async builtins, if this is an async evaluation.
+                    let host = async_eval::current_host();
+                    // The workers' steps and timeout count towards this evaluation's budget.
+                    let (context, usage) = (self.context.clone(), self.context.budget.usage());
+                    scope.spawn(move || context.budget.with_usage(usage, || async_eval::with_host(host, || {
+                        let mut values = Vec::with_capacity(chunk.len());
+                        for expr in chunk {
+                            // An earlier chunk failed, so these results will be thrown away.
+                            if first_failed.load(Ordering::Relaxed) < index {
+                                break;
+                            }
+                            let value = env.eval(expr.clone());
+                            if value.is_err() {
+                                first_failed.fetch_min(index, Ordering::Relaxed);
+                                return Err(value);
+                            }
+                            values.push(value);
+                        }
+                        Ok(values)
+                    })))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+
+        let mut values = Vec::with_capacity(exprs.len());
+        for result in results {
+            match result {
+                Ok(chunk) => values.extend(chunk),
+                Err(err) => {
+                    self.raised.get_or_insert_with(|| Box::new(err.clone()));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// Evaluate an expression in this environment, returning any error as a `Result`.
+    ///
+    /// This is the same as [`Env::eval`], but separates errors from successful
+    /// results, which is convenient when calling the interpreter from Rust.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, ErrorKind, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_core_forms();
+    /// let body = Expr::parse("(raise x)").unwrap();
+    /// env.bind_symbol("fail", Expr::Function(None, vec![Expr::symbol("x")], Box::new(body)));
+    ///
+    /// let err = env.try_eval(Expr::parse("(fail 5)").unwrap()).unwrap_err();
+    /// assert_eq!(err.kind, ErrorKind::User);
+    /// assert_eq!(err.value, Expr::Int(5));
+    /// // The trace records the `raise` builtin, and then the call to `fail`.
+    /// assert_eq!(err.trace.len(), 2);
+    /// ```
+    pub fn try_eval(&mut self, expr: Expr) -> Result<Expr, EvalError> {
+        match self.eval(expr) {
+            Expr::Err(e) => Err(*e),
+            value => Ok(value),
+        }
+    }
+
+    /// The evaluation loop behind [`Env::eval`].
+    ///
+    /// If `limits` are given, each iteration takes a step from the environment's budget.
+    fn eval_expr(&mut self, mut expr: Expr, limits: Option<&EvalLimits>) -> Expr {
         use Expr::*;
-        let saved_bindings = self.bindings.clone();
-        let mut is_in_new_env = false;
-        loop {
-            if let Some(value) = self.get(&expr) {
-                return value.clone();
+        // The environment the current expression is evaluated in.
+        // Tail calls replace this with the scope of the called function.
+        let mut env = self.clone();
+        // The function call being evaluated, if any: the call expression, the
+        // expression that was called, and the parameters of the function. This
+        // is recorded in the trace of any error that escapes from the call.
+        let mut call: Option<(Expr, Expr, Vec<Expr>)> = Option::None;
+        let mut result = loop {
+            if let Result::Err(err) = env.context.interrupt.check() {
+                break err.into();
+            }
+            if let Some(limits) = limits {
+                if let Result::Err(err) = env.context.budget.step(limits) {
+                    break err.at(env.span_of(&expr)).into();
+                }
+            }
+
+            // Compiled code is run by the virtual machine. It's never bound
+            // to anything, so don't pay for looking it up.
+            if let Code(code) = &expr {
+                break env.run(code.clone(), limits);
+            }
+            // Only symbols are bound, unless atom rebinding is enabled.
+            // Looking up anything else would hash it for nothing, and
+            // hashing a large list literal walks every element.
+            if matches!(expr, Symbol(_)) || env.is_atom_rebinding_enabled() {
+                if let Some(value) = env.get(&expr) {
+                    break value;
+                }
             }
 
             match &expr {
                 List(l) => {
                     if l.is_empty() {
-                        return expr;
+                        break expr;
                     }
 
-                    let mut args = l.clone();
-                    let func = args.remove(0);
-                    let func = self.eval(func);
+                    let head = l[0].clone();
+                    let args: Vec<Expr> = l.iter().skip(1).cloned().collect();
+                    let func = env.eval(head.clone());
+                    if func.is_err() {
+                        break func;
+                    }
 
                     match func {
-                        Function(env, params, body) => {
-                            // saved_bindings = self.bindings.clone();
-                            is_in_new_env = true;
-                            if let Some(new_env) = env {
-                                self.merge(&new_env);
+                        Function(closure, params, body) => {
+                            let values = match env.eval_all(args) {
+                                Ok(values) => values,
+                                Result::Err(err) => break err,
+                            };
+
+                            // Create a new scope for the call, enclosed by the closure's scope.
+                            let closure = closure.map(|closure| *closure);
+                            let new_env = match closure.as_ref().unwrap_or(&env).call_scope(&head, &params, values) {
+                                Ok(new_env) => new_env,
+                                Result::Err(err) => break err.at(env.span_of(&expr)).into(),
+                            };
+
+                            env = new_env;
+                            let form = std::mem::replace(&mut expr, *body);
+                            call = Some((form, head, params));
+                        }
+                        Builtin(f) => {
+                            env.raised = Option::None;
+                            let mut result = f.apply(&mut env, args);
+                            // If one of the arguments failed, return its error
+                            // instead of whatever the builtin made of it.
+                            if let Some(err) = env.take_raised() {
+                                result = err;
                             }
-
-                            if params.len() != args.len() {
-                                return Expr::Err(Box::new(Expr::String(format!(
-                                    "Expected {} arguments, got {}",
-                                    params.len(),
-                                    args.len()
-                                ))));
+                            if let Err(e) = &mut result {
+                                let span = env.span_of(&expr);
+                                if e.span.is_none() {
+                                    e.span = span.clone();
+                                }
+                                e.push_frame(Frame::Builtin {
+                                    name: f.name.to_string(),
+                                    call: expr,
+                                    span,
+                                });
+                                break result;
                             }
-
-                            let args = args
-                                .into_iter()
-                                .map(|arg| self.eval(arg))
-                                .collect::<Vec<_>>();
-
-                            for (param, arg) in params.into_iter().zip(args.into_iter()) {
-                                self.bind(param, arg);
+                            expr = result;
+                            if !f.lazy_eval {
+                                break expr;
                             }
-
-                            expr = *body;
                         }
-                        Builtin(f) => {
-                            expr = (f.f)(self, args);
-                            if !f.lazy_eval {
-                                break;
+                        Macro(expander) => {
+                            // Evaluate the expansion in place of the call.
+                            let mut expansion = env.expand_macro(&head, &expander, args);
+                            if let Err(e) = &mut expansion {
+                                if e.span.is_none() {
+                                    e.span = env.span_of(&expr);
+                                }
+                                break expansion;
                             }
+                            expr = expansion;
                         }
                         Tree(t) => {
                             // Get the element of the tree
-                            let key = self.eval(args.get(0).unwrap().clone());
-
-                            expr = t.get(&key).cloned().unwrap_or(Expr::None);
-                            break;
+                            let key = match args.first() {
+                                Some(key) => env.eval(key.clone()),
+                                Option::None => break EvalError::new(ErrorKind::Arity, "Expected a key to index the tree").into(),
+                            };
+                            if key.is_err() {
+                                break key;
+                            }
+                            break t.get(&key).cloned().unwrap_or(Expr::None);
                         }
                         Map(m) => {
                             // Get the element of the map
-                            let key = self.eval(args.get(0).unwrap().clone());
-                            expr = m.get(&key).cloned().unwrap_or(Expr::None);
-                            break;
+                            let key = match args.first() {
+                                Some(key) => env.eval(key.clone()),
+                                Option::None => break EvalError::new(ErrorKind::Arity, "Expected a key to index the map").into(),
+                            };
+                            if key.is_err() {
+                                break key;
+                            }
+                            break m.get(&key).cloned().unwrap_or(Expr::None);
                         }
                         Symbol(s) => {
-                            if let Some(value) = self.get(&expr) {
-                                expr = value.clone();
+                            if let Some(value) = env.get(&expr) {
+                                expr = value;
                             } else {
-                                expr = Expr::Err(Box::new(Expr::String(format!(
-                                    "Symbol {} not found",
-                                    s.name()
-                                ))));
+                                break EvalError::new(
+                                    ErrorKind::Unbound,
+                                    format!("Symbol {} not found", s.name()),
+                                )
+                                .at(env.span_of(&expr))
+                                .into();
                             }
                         }
 
                         _result => {
-                            break;
+                            break expr;
                         }
                     }
                 }
                 Many(d) => {
                     if d.is_empty() {
-                        return Expr::None;
+                        break Expr::None;
                     }
 
                     // Eval the first expression
+                    let mut failed = Option::None;
                     for e in d.iter().take(d.len() - 1) {
-                        self.eval(e.clone());
+                        let result = env.eval(e.clone());
+                        if result.is_err() {
+                            failed = Some(result);
+                            break;
+                        }
+                    }
+                    match failed {
+                        Some(err) => break err,
+                        Option::None => expr = d.last().unwrap().clone(),
                     }
-                    expr = d.last().unwrap().clone();
                 }
                 Map(m) => {
-                    let mut new_map = HashMap::new();
-                    for (k, v) in m.iter() {
-                        new_map.insert(k.clone(), self.eval(v.clone()));
+                    let (keys, values): (Vec<_>, Vec<_>) = m.iter().map(|(k, v)| (k.clone(), v.clone())).unzip();
+                    match env.eval_all(values) {
+                        Ok(values) => break Expr::Map(keys.into_iter().zip(values).collect()),
+                        Result::Err(err) => break err,
                     }
-                    expr = Expr::Map(new_map);
-                    break;
                 }
                 Tree(t) => {
-                    let mut new_tree = BTreeMap::new();
-                    for (k, v) in t.iter() {
-                        new_tree.insert(k.clone(), self.eval(v.clone()));
+                    let (keys, values): (Vec<_>, Vec<_>) = t.iter().map(|(k, v)| (k.clone(), v.clone())).unzip();
+                    match env.eval_all(values) {
+                        Ok(values) => break Expr::Tree(keys.into_iter().zip(values).collect()),
+                        Result::Err(err) => break err,
                     }
-                    expr = Expr::Tree(new_tree);
-                    break;
                 }
                 Quote(e) => {
-                    expr = *e.clone();
-                    break;
+                    break *e.clone();
                 }
                 Function(Option::None, args, body) => {
-                    // Replace the environment with a new one
-                    let mut new_env = self.clone();
-                    for arg in args.iter() {
-                        new_env.unbind(arg);
-                    }
-                    expr = Function(Some(Box::new(new_env)), args.clone(), body.clone());
-                    break;
+                    // Capture the current scope as the function's closure
+                    break env.closure(args.clone(), *body.clone());
                 }
-                _ => return expr,
+                _ => break expr,
+            }
+        };
+
+        // Record the function call that the error escaped from.
+        if let (Err(e), Some((form, name, params))) = (&mut result, call) {
+            let args = params
+                .into_iter()
+                .map(|param| {
+                    let value = env.scope.lookup(&param).unwrap_or_default();
+                    (param, value)
+                })
+                .collect();
+            let span = env.span_of(&form);
+            if e.span.is_none() {
+                e.span = span.clone();
+            }
+            e.push_frame(Frame::Function { name, args, span });
+        }
+        result
+    }
+}
+
+impl Scope {
+    /// The position of a parameter of this scope.
+    #[inline]
+    fn param_index(&self, symbol: &Expr) -> Option<usize> {
+        self.params.iter().position(|param| param == symbol)
+    }
+
+    /// Get the value bound to an expression in this scope only.
+    #[inline]
+    fn lookup(self: &Arc<Self>, symbol: &Expr) -> Option<Expr> {
+        if let Some(i) = self.param_index(symbol) {
+            if let Some(value) = &self.args.read().unwrap()[i] {
+                return Some(self.attach(value.clone()));
+            }
+        }
+        let value = self.bindings.read().unwrap().get(symbol).cloned();
+        value.map(|value| self.attach(value))
+    }
+
+    /// Prepare a value to be stored in this scope.
+    ///
+    /// A closure holds the scope it was defined in, so when it is bound in
+    /// that same scope, like a function defined inside another function,
+    /// the two would keep each other alive and never be freed. The closure
+    /// is stored with [`ENCLOSING`] in place of its scope instead, and
+    /// [`Scope::attach`] puts the scope back when the closure is looked up.
+    fn detach(self: &Arc<Self>, value: Expr) -> Expr {
+        match value {
+            Expr::Function(Some(mut env), params, body) if Arc::ptr_eq(&env.scope, self) => {
+                env.scope = ENCLOSING.clone();
+                Expr::Function(Some(env), params, body)
+            }
+            value => value,
+        }
+    }
+
+    /// Restore a value stored in this scope by [`Scope::detach`].
+    fn attach(self: &Arc<Self>, value: Expr) -> Expr {
+        match value {
+            Expr::Function(Some(mut env), params, body) if Arc::ptr_eq(&env.scope, &ENCLOSING) => {
+                env.scope = self.clone();
+                Expr::Function(Some(env), params, body)
             }
+            value => value,
         }
-        if is_in_new_env {
-            self.bindings = saved_bindings;
+    }
+}
+
+// Environments and expressions are shared between threads, so make sure they stay thread-safe.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Env>();
+    assert_send_sync::<Expr>();
+};
+
+/// Print an environment as debug output.
+///
+/// The bindings are not printed: closures capture their environment,
+/// so printing them would recurse through every function in scope.
+impl Debug for Env {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut depth = 0;
+        let mut scope = self.scope.parent.as_ref();
+        while let Some(s) = scope {
+            depth += 1;
+            scope = s.parent.as_ref();
         }
-        expr
+        f.debug_struct("Env").field("depth", &depth).finish_non_exhaustive()
     }
 }
 
@@ -582,36 +1401,56 @@ impl Env {
  * of functions, and to override the default behavior of the interpreter.
  * 
  * Builtin functions can be defined with the `Builtin::new` constructor, which
- * takes a function pointer and a name for the function. You can also set the
+ * takes a function or closure and a name for the function. You can also set the
  * `lazy_eval` flag to true, to make the function's return value lazy-evaluated.
  */
 
+/// The signature of the Rust function behind a builtin.
+pub type BuiltinFn = dyn Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync;
+
  /// A builtin function that can be called from the lisp environment.
  /// 
  /// This is a wrapped Rust function that can implement special forms,
  /// operators, or standard library functions.
-#[derive(Debug, Clone)]
+ ///
+ /// Builtins are compared and hashed by the identity of their function, so
+ /// clones of the same builtin are equal, but two separately created builtins
+ /// are not, even if they have the same name.
+#[derive(Clone)]
 pub struct Builtin {
-    /// The function pointer for the builtin function.
+    /// The function for the builtin.
     /// 
     /// This is a Rust function that takes the calling environment and a list of arguments,
     /// and returns the result of the function. The function can perform any operation
-    /// on the arguments, and can return any expression as a result.
-    pub f: fn(&mut Env, Vec<Expr>) -> Expr,
+    /// on the arguments, and can return any expression as a result. It can be a closure
+    /// that captures state from the host application.
+    pub f: Arc<BuiltinFn>,
     /// The name of the builtin function.
-    pub name: &'static str,
+    pub name: Arc<str>,
     /// Whether the builtin function should be evaluated lazily, or immediately after calling.
     pub(crate) lazy_eval: bool,
+    /// Whether the builtin evaluates all of its arguments, so compiled code can evaluate them instead.
+    pub(crate) strict: bool,
+    /// The core form the builtin implements, if the compiler knows how to compile it inline.
+    pub(crate) form: Option<Form>,
+    /// The methods of the generic function the builtin dispatches to, if it is one.
+    pub(crate) generic: Option<Arc<Generic>>,
 }
 
 impl Builtin {
-    /// Create a new builtin function from a function pointer and a name.
+    /// Create a new builtin function from a function or closure and a name.
     #[inline]
-    pub fn new(f: fn(&mut Env, Vec<Expr>) -> Expr, name: &'static str) -> Self {
+    pub fn new(
+        f: impl Fn(&mut Env, Vec<Expr>) -> Expr + Send + Sync + 'static,
+        name: impl Into<Arc<str>>,
+    ) -> Self {
         Self {
-            f,
-            name,
+            f: Arc::new(f),
+            name: name.into(),
             lazy_eval: false,
+            strict: false,
+            form: None,
+            generic: None,
         }
     }
 
@@ -624,11 +1463,49 @@ impl Builtin {
         Self { lazy_eval, ..self }
     }
 
+    /// Set the strict flag for the builtin function.
+    ///
+    /// A strict builtin promises to evaluate each of its arguments once, in order,
+    /// and to use only their values. Compiled code evaluates the arguments of calls
+    /// to strict builtins itself, and passes the values to the builtin quoted.
+    /// Builtins that look at the syntax of their arguments, or only evaluate some
+    /// of them, like `define` or `and`, must not be strict.
+    #[inline]
+    pub fn with_strict_args(self, strict: bool) -> Self {
+        Self { strict, ..self }
+    }
+
+    /// Mark the builtin as the implementation of a core form that the compiler
+    /// compiles inline, instead of calling the builtin.
+    #[inline]
+    pub(crate) fn with_form(self, form: Form) -> Self {
+        Self {
+            form: Some(form),
+            ..self
+        }
+    }
+
     /// Apply the builtin function to a list of arguments in the given environment.
     #[inline]
     pub fn apply(&self, env: &mut Env, args: Vec<Expr>) -> Expr {
         (self.f)(env, args)
     }
+
+    /// The address of the builtin's function, which identifies it.
+    #[inline]
+    fn addr(&self) -> usize {
+        Arc::as_ptr(&self.f) as *const () as usize
+    }
+}
+
+impl Debug for Builtin {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Builtin")
+            .field("name", &self.name)
+            .field("lazy_eval", &self.lazy_eval)
+            .field("strict", &self.strict)
+            .finish()
+    }
 }
 
 /// Implement display for builtin functions.
@@ -642,6 +1519,71 @@ impl Display for Builtin {
 }
 
 
+///////////////////////////////////////////////////////////////
+// REFERENCE CELLS
+///////////////////////////////////////////////////////////////
+
+/*
+ * Values in lisp are immutable, and copied into each binding. To share
+ * mutable state between closures, a value can be put in a box: a cell
+ * that can be read and updated through any copy of the box.
+ *
+ * Boxes are created with `box`, read with `unbox`, and updated with `set-box!`.
+ */
+
+/// A mutable cell holding a value, shared by every copy of the box.
+///
+/// Boxes are compared and hashed by identity, so a box is only equal to copies of itself.
+///
+/// ```rust
+/// use sage_lisp::{Cell, Expr};
+///
+/// let cell = Cell::new(Expr::Int(1));
+/// let copy = Expr::Cell(cell.clone());
+/// cell.set(Expr::Int(2));
+/// assert_eq!(copy, Expr::Cell(cell.clone()));
+/// assert_ne!(copy, Expr::Cell(Cell::new(Expr::Int(2))));
+/// if let Expr::Cell(copy) = copy {
+///     assert_eq!(copy.get(), Expr::Int(2));
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Cell(Arc<RwLock<Expr>>);
+
+impl Cell {
+    /// Create a new box holding a value.
+    #[inline]
+    pub fn new(value: Expr) -> Self {
+        Self(Arc::new(RwLock::new(value)))
+    }
+
+    /// Get a copy of the value in the box.
+    #[inline]
+    pub fn get(&self) -> Expr {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the value in the box, returning the old value.
+    #[inline]
+    pub fn set(&self, value: Expr) -> Expr {
+        std::mem::replace(&mut self.0.write().unwrap(), value)
+    }
+
+    /// The address of the box, which identifies it.
+    #[inline]
+    fn addr(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const () as usize
+    }
+}
+
+/// Print a box with its current value.
+impl Display for Cell {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "<box {}>", self.get())
+    }
+}
+
+
 ///////////////////////////////////////////////////////////////
 // LISP EXPRESSIONS
 ///////////////////////////////////////////////////////////////
@@ -682,18 +1624,21 @@ pub enum Expr {
     /// When used as a data structure, this is used to represent a list of values.
     /// It can be indexed by position, and can be used to store a sequence of values.
     /// 
-    /// This is stored as a vector, not a linked list, so that it can be indexed efficiently.
-    List(Vec<Expr>),
+    /// This is stored as a persistent [`Vector`], not a linked list, so that it can be
+    /// indexed efficiently, and copied in O(1) time.
+    List(Vector<Expr>),
     /// An ordered map of expressions.
     /// 
     /// This is helpful when the user desires the ordered properties of a BTreeMap,
     /// such as ordering by key, but with worse time complexities than a HashMap.
-    Tree(BTreeMap<Expr, Expr>),
+    /// It is stored as a persistent [`OrdMap`].
+    Tree(OrdMap<Expr, Expr>),
     /// A map of expressions.
     /// 
     /// This is helpful when the user wants the time complexities associated with
-    /// a HashMap, such as O(1) for insertion, deletion, and lookup, but with no ordering.
-    Map(HashMap<Expr, Expr>),
+    /// a HashMap, such as fast insertion, deletion, and lookup, but with no ordering.
+    /// It is stored as a persistent [`HashTrieMap`].
+    Map(HashTrieMap<Expr, Expr>),
 
     /// A block of expressions to be evaluated in order.
     /// 
@@ -714,7 +1659,11 @@ pub enum Expr {
     /// 
     /// When an error occurs during evaluation, this is used to wrap an error value
     /// that is propagated up the call stack. This allows for error handling in the interpreter.
-    Err(Box<Self>),
+    ///
+    /// Evaluation short-circuits when an argument evaluates to an error, until the
+    /// error is caught by a `try` form. The error records its kind, and the calls
+    /// it unwound through.
+    Err(Box<EvalError>),
 
     /// A function closure.
     /// 
@@ -728,6 +1677,30 @@ pub enum Expr {
     /// 
     /// This is used to represent a function that is defined in Rust, and can be called from lisp.
     Builtin(Builtin),
+    /// A mutable box, shared by every copy of it.
+    ///
+    /// This is used for state that is updated by closures, like counters or memo tables.
+    Cell(Cell),
+    /// A macro, defined with `defmacro`.
+    ///
+    /// This wraps the function that expands calls to the macro. The function is called
+    /// with the unevaluated arguments of the call, and the expression it returns is
+    /// evaluated in place of the call.
+    Macro(Box<Expr>),
+    /// An expression compiled to bytecode, with [`Env::compile`].
+    ///
+    /// Evaluating it runs the bytecode, which gives the same result as evaluating
+    /// the expression it was compiled from. It is compared, hashed and printed
+    /// as that expression.
+    Code(Arc<Code>),
+    /// A lazy sequence, whose values are computed when they are needed.
+    ///
+    /// Like a box, it is compared and hashed by identity.
+    Seq(Seq),
+    /// A value of a record type, defined with `defrecord`.
+    ///
+    /// It carries the name of its type, and the values of its fields.
+    Record(Record),
 }
 
 /// Convert a String to an Expr conveniently.
@@ -777,6 +1750,27 @@ where
     }
 }
 
+/// Convert a persistent vector of expressions to a list.
+impl From<Vector<Expr>> for Expr {
+    fn from(v: Vector<Expr>) -> Self {
+        Self::List(v)
+    }
+}
+
+/// Convert a persistent ordered map of expressions to a tree.
+impl From<OrdMap<Expr, Expr>> for Expr {
+    fn from(t: OrdMap<Expr, Expr>) -> Self {
+        Self::Tree(t)
+    }
+}
+
+/// Convert a persistent hash map of expressions to a map.
+impl From<HashTrieMap<Expr, Expr>> for Expr {
+    fn from(m: HashTrieMap<Expr, Expr>) -> Self {
+        Self::Map(m)
+    }
+}
+
 /// Allow Serde to convert a serde_json::Value to an Expr.
 /// 
 /// This is a convenience method for converting JSON data into Lisp expressions,
@@ -837,6 +1831,11 @@ impl From<Expr> for serde_json::Value {
                     })
                     .collect(),
             ),
+            Expr::Record(r) => Object(
+                r.fields()
+                    .map(|(k, v)| (k.name().to_string(), v.clone().into()))
+                    .collect(),
+            ),
             _ => Null,
         }
     }
@@ -867,7 +1866,66 @@ impl Expr {
     /// This is useful for propagating errors up the call stack, and for handling errors in the interpreter.
     #[inline]
     pub fn error(message: impl Into<Self>) -> Self {
-        Self::Err(Box::new(message.into()))
+        Self::error_of(ErrorKind::Other, message)
+    }
+
+    /// Wrap another expression in an error value of the given kind.
+    #[inline]
+    pub fn error_of(kind: ErrorKind, message: impl Into<Self>) -> Self {
+        EvalError::new(kind, message).into()
+    }
+
+    /// Is this expression an error value?
+    #[inline]
+    pub fn is_err(&self) -> bool {
+        matches!(self, Self::Err(_))
+    }
+
+    /// Is this expression a literal, which always evaluates to itself?
+    ///
+    /// This is true for numbers, strings, booleans, `nil`, and values that
+    /// are already evaluated, like functions and builtins.
+    #[inline]
+    pub fn is_literal(&self) -> bool {
+        matches!(
+            self,
+            Self::None
+                | Self::Int(_)
+                | Self::Float(_)
+                | Self::String(_)
+                | Self::Bool(_)
+                | Self::Builtin(_)
+                | Self::Cell(_)
+                | Self::Seq(_)
+                | Self::Record(_)
+                | Self::Function(Some(_), ..)
+        )
+    }
+
+    /// Get the name of the expression's type, which generic functions dispatch on.
+    ///
+    /// This is the name of the record type for records, like `Point`, and one of
+    /// `nil`, `int`, `float`, `string`, `symbol`, `bool`, `list`, `tree`, `map`,
+    /// `function`, `macro`, `box`, `seq`, `error` or `code` for anything else.
+    pub fn type_name(&self) -> &str {
+        match self {
+            Self::None => "nil",
+            Self::Int(_) => "int",
+            Self::Float(_) => "float",
+            Self::String(_) => "string",
+            Self::Symbol(_) => "symbol",
+            Self::Bool(_) => "bool",
+            Self::List(_) => "list",
+            Self::Tree(_) => "tree",
+            Self::Map(_) => "map",
+            Self::Function(..) | Self::Builtin(_) => "function",
+            Self::Macro(_) => "macro",
+            Self::Cell(_) => "box",
+            Self::Seq(_) => "seq",
+            Self::Err(_) => "error",
+            Self::Record(r) => r.type_name().name(),
+            Self::Many(_) | Self::Quote(_) | Self::Code(_) => "code",
+        }
     }
 
     /// Quote an expression to prevent it from being evaluated.
@@ -881,7 +1939,7 @@ impl Expr {
     pub fn apply(&self, args: &[Self]) -> Self {
         let mut result = vec![self.clone()];
         result.extend(args.to_vec());
-        Self::List(result)
+        Self::List(result.into())
     }
 
     /// Parse a string into a Lisp expression.
@@ -890,13 +1948,30 @@ impl Expr {
     /// If the string is not a valid Lisp expression, it will return an error message.
     pub fn parse(input: &str) -> Result<Expr, String> {
         let input = Self::remove_comments(input);
-        let result = parser::parse_program::<VerboseError<&str>>(input.trim())
+        Self::parse_stripped(&input)
+    }
+
+    /// Parse a string into a Lisp expression, and record where each list in it starts.
+    ///
+    /// The `source` is the name of the input, such as its file path, which is used in
+    /// the locations. Register the returned source map with [`Env::add_source_map`], so
+    /// that errors raised while evaluating the expression report their location.
+    pub fn parse_source(input: &str, source: &str) -> Result<(Expr, SourceMap), String> {
+        // Comments are removed up to the end of their line, so the lines
+        // and columns of the remaining code are unchanged.
+        let input = Self::remove_comments(input);
+        let (result, spans) = parser::record_spans(&input, source, Self::parse_stripped);
+        result.map(|expr| (expr, spans))
+    }
+
+    /// Parse a string that has already had its comments removed.
+    fn parse_stripped(input: &str) -> Result<Expr, String> {
+        parser::parse_program::<VerboseError<&str>>(input.trim())
             .map(|(_, expr)| expr)
             .map_err(|e| match e {
-                Err::Error(e) | Err::Failure(e) => convert_error::<&str>(&input, e),
+                Err::Error(e) | Err::Failure(e) => convert_error::<&str>(input, e),
                 Err::Incomplete(e) => unreachable!("Incomplete: {:?}", e),
-            });
-        result
+            })
     }
 
     /// Strip the comments from an input string.
@@ -951,7 +2026,7 @@ impl PartialEq for Expr {
         use Expr::*;
         match (self, other) {
             (None, None) => true,
-            (Builtin(f1), Builtin(f2)) => f1 as *const _ == f2 as *const _,
+            (Builtin(f1), Builtin(f2)) => f1.addr() == f2.addr(),
             (Float(f1), Float(f2)) => f1.to_bits() == f2.to_bits(),
             (Int(i1), Int(i2)) => i1 == i2,
             (Int(i), Float(f)) | (Float(f), Int(i)) => *f == *i as f64,
@@ -964,6 +2039,11 @@ impl PartialEq for Expr {
                 args1 == args2 && body1 == body2
             }
             (Quote(e1), Quote(e2)) => e1 == e2,
+            (Macro(m1), Macro(m2)) => m1 == m2,
+            (Cell(c1), Cell(c2)) => c1.addr() == c2.addr(),
+            (Seq(s1), Seq(s2)) => s1.addr() == s2.addr(),
+            (Record(r1), Record(r2)) => r1 == r2,
+            (Code(c1), Code(c2)) => c1.source() == c2.source(),
             (Err(e1), Err(e2)) => e1 == e2,
             (Bool(b1), Bool(b2)) => b1 == b2,
             (Many(d1), Many(d2)) => d1 == d2,
@@ -976,6 +2056,10 @@ impl PartialEq for Expr {
 /// 
 /// This allows you to compare two expressions using the `<`, `>`, `<=`, and `>=` operators,
 /// as well as to sort expressions in a collection.
+///
+/// Expressions of different kinds are not comparable, so that comparisons
+/// like `(< "a" 1)` are false in both directions.
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for Expr {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         use Expr::*;
@@ -1003,9 +2087,12 @@ impl PartialOrd for Expr {
                 }
             }
             (Err(e1), Err(e2)) => e1.partial_cmp(e2),
-            (Builtin(f1), Builtin(f2)) => {
-                (f1 as *const _ as usize).partial_cmp(&(f2 as *const _ as usize))
-            }
+            (Builtin(f1), Builtin(f2)) => f1.addr().partial_cmp(&f2.addr()),
+            (Macro(m1), Macro(m2)) => m1.partial_cmp(m2),
+            (Cell(c1), Cell(c2)) => c1.addr().partial_cmp(&c2.addr()),
+            (Seq(s1), Seq(s2)) => s1.addr().partial_cmp(&s2.addr()),
+            (Record(r1), Record(r2)) => r1.partial_cmp(r2),
+            (Code(c1), Code(c2)) => c1.source().partial_cmp(c2.source()),
             (Bool(b1), Bool(b2)) => b1.partial_cmp(b2),
             (Many(d1), Many(d2)) => d1.partial_cmp(d2),
             _ => Option::None,
@@ -1046,6 +2133,11 @@ impl Hash for Expr {
             Err(_) => 11,
             Function(_, _, _) => 12,
             Builtin(_) => 13,
+            Macro(_) => 14,
+            Cell(_) => 15,
+            Code(_) => 16,
+            Seq(_) => 17,
+            Record(_) => 18,
         });
 
         match self {
@@ -1065,7 +2157,12 @@ impl Hash for Expr {
                 args.hash(state);
                 body.hash(state);
             }
-            Builtin(f) => (f as *const _ as usize).hash(state),
+            Builtin(f) => f.addr().hash(state),
+            Macro(m) => m.hash(state),
+            Cell(c) => c.addr().hash(state),
+            Code(c) => c.source().hash(state),
+            Seq(s) => s.addr().hash(state),
+            Record(r) => r.hash(state),
         }
     }
 }
@@ -1084,7 +2181,7 @@ impl Display for Expr {
             String(s) => write!(f, "\"{}\"", s),
             Symbol(s) => write!(f, "{}", s.name()),
             Quote(e) => write!(f, "'{}", e),
-            Err(e) => write!(f, "<error: {}>", e),
+            Err(e) => write!(f, "<error: {}>", e.value),
             Many(d) => {
                 write!(f, "{{ ")?;
                 for (i, e) in d.iter().enumerate() {
@@ -1136,6 +2233,11 @@ impl Display for Expr {
                 write!(f, ") {})", body)
             }
             Builtin(b) => write!(f, "<builtin {}>", b.name),
+            Macro(m) => write!(f, "<macro {}>", m),
+            Cell(c) => write!(f, "{}", c),
+            Code(c) => write!(f, "{}", c.source()),
+            Seq(s) => write!(f, "{:?}", s),
+            Record(r) => write!(f, "{}", r),
         }
     }
 }
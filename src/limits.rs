@@ -0,0 +1,443 @@
+//! # Limits
+//!
+//! When running untrusted programs, an environment can be given [`EvalLimits`]
+//! to stop runaway evaluation. When a limit is exceeded, evaluation returns an
+//! [`Expr::Err`] with the corresponding [`ErrorKind`], instead of hanging, or
+//! aborting the process by overflowing the stack.
+//!
+//! The step budget and the timeout apply to each top-level call to [`Env::eval`],
+//! so an embedding application can evaluate several programs in the same
+//! environment, each with their own budget, even at the same time on different
+//! threads. Work that an evaluation hands to other threads, like the workers of
+//! `pmap` or the body of a generator, counts towards the evaluation's budget.
+//!
+//! Evaluation can also be stopped from another thread, with the [`InterruptHandle`]
+//! returned by [`Env::interrupt_handle`].
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{Env, ErrorKind, EvalError, Expr};
+
+/// How many steps are taken between checks of the clock.
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+/// The limits on evaluating expressions in an environment.
+///
+/// All limits are disabled by default.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use sage_lisp::{Env, ErrorKind, EvalLimits, Expr};
+///
+/// let mut env = Env::new();
+/// let body = Expr::parse("(loop x)").unwrap();
+/// let closure = Some(Box::new(env.clone()));
+/// env.bind_symbol("loop", Expr::Function(closure, vec![Expr::symbol("x")], Box::new(body)));
+/// env.set_limits(
+///     EvalLimits::default()
+///         .with_max_steps(10_000)
+///         .with_timeout(Duration::from_secs(1)),
+/// );
+///
+/// match env.eval_str("(loop 1)").unwrap() {
+///     Expr::Err(e) => assert_eq!(e.kind, ErrorKind::StepLimit),
+///     result => panic!("expected an error, got {result}"),
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvalLimits {
+    /// The maximum number of evaluation steps.
+    ///
+    /// Each iteration of the evaluator, such as evaluating a symbol, or calling
+    /// a function, counts as a step.
+    pub max_steps: Option<u64>,
+    /// The maximum depth of nested evaluations.
+    ///
+    /// Every expression that is evaluated inside another, like the arguments of
+    /// a call, or a call that is not in tail position, adds a level. Tail calls
+    /// do not. This should be set low enough that the stack of the evaluating
+    /// thread does not overflow. Each thread has a depth of its own.
+    pub max_depth: Option<usize>,
+    /// The maximum number of elements in a list, tree or map, or bytes in a string,
+    /// produced by an evaluation.
+    pub max_collection_size: Option<usize>,
+    /// The maximum time a top-level evaluation can take.
+    pub timeout: Option<Duration>,
+}
+
+impl EvalLimits {
+    /// Set the maximum number of evaluation steps.
+    #[inline]
+    pub fn with_max_steps(self, max_steps: u64) -> Self {
+        Self {
+            max_steps: Some(max_steps),
+            ..self
+        }
+    }
+
+    /// Set the maximum depth of nested evaluations.
+    #[inline]
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        Self {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+
+    /// Set the maximum size of a collection produced by an evaluation.
+    #[inline]
+    pub fn with_max_collection_size(self, max_collection_size: usize) -> Self {
+        Self {
+            max_collection_size: Some(max_collection_size),
+            ..self
+        }
+    }
+
+    /// Set the maximum time a top-level evaluation can take.
+    #[inline]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Are any of the limits enabled?
+    #[inline]
+    pub fn is_limited(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+/// The limits on the evaluations in an environment.
+///
+/// The resources used are tracked per thread: each thread evaluating in the
+/// environment has its own depth, and shares the [`Usage`] of the top-level
+/// evaluation it belongs to.
+#[derive(Default)]
+pub(crate) struct Budget {
+    /// Is there a limit to enforce? When this is false, the rest is ignored,
+    /// so that unlimited evaluation doesn't pay for the bookkeeping.
+    enabled: AtomicBool,
+    /// The limits being enforced.
+    limits: Mutex<EvalLimits>,
+}
+
+/// The resources used by a top-level evaluation, shared with the threads it starts.
+pub(crate) struct Usage {
+    /// The steps taken so far.
+    steps: AtomicU64,
+    /// The time the evaluation must finish by.
+    deadline: Option<Instant>,
+}
+
+/// An evaluation in an environment, running on the current thread.
+struct Running {
+    /// The address of the environment's budget.
+    budget: usize,
+    /// The depth of nested evaluations on this thread.
+    depth: usize,
+    /// The resources used by the top-level evaluation this one belongs to.
+    usage: Arc<Usage>,
+    /// Was the usage handed over by another thread? Then it is kept when the depth
+    /// returns to 0, because the thread is still working for that evaluation.
+    inherited: bool,
+}
+
+thread_local! {
+    /// The evaluations running on this thread, one for each environment.
+    static RUNNING: RefCell<Vec<Running>> = const { RefCell::new(Vec::new()) };
+}
+
+impl Budget {
+    /// Is there a limit to enforce?
+    #[inline]
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Get the limits being enforced.
+    pub(crate) fn limits(&self) -> EvalLimits {
+        *self.limits.lock().unwrap()
+    }
+
+    /// The key of this budget in [`RUNNING`].
+    #[inline]
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Call a function with this thread's evaluation in the environment, if there is one.
+    fn with_running<T>(&self, f: impl FnOnce(&mut Running) -> T) -> Option<T> {
+        RUNNING.with(|running| {
+            let mut running = running.borrow_mut();
+            running.iter_mut().rev().find(|r| r.budget == self.id()).map(f)
+        })
+    }
+
+    /// Enter a nested evaluation, starting a new budget if it is a top-level one.
+    ///
+    /// Every call must be paired with a call to [`Budget::exit`], even if it fails.
+    pub(crate) fn enter(&self, limits: &EvalLimits) -> Result<(), EvalError> {
+        let depth = match self.with_running(|r| {
+            r.depth += 1;
+            r.depth
+        }) {
+            Some(depth) => depth,
+            None => {
+                let usage = Usage {
+                    steps: AtomicU64::new(0),
+                    deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+                };
+                RUNNING.with(|running| {
+                    running.borrow_mut().push(Running {
+                        budget: self.id(),
+                        depth: 1,
+                        usage: Arc::new(usage),
+                        inherited: false,
+                    })
+                });
+                1
+            }
+        };
+        match limits.max_depth {
+            Some(max_depth) if depth > max_depth => Err(EvalError::new(
+                ErrorKind::DepthLimit,
+                format!("Exceeded the maximum evaluation depth of {max_depth}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Leave a nested evaluation.
+    pub(crate) fn exit(&self) {
+        RUNNING.with(|running| {
+            let mut running = running.borrow_mut();
+            if let Some(i) = running.iter().rposition(|r| r.budget == self.id()) {
+                running[i].depth -= 1;
+                if running[i].depth == 0 && !running[i].inherited {
+                    running.remove(i);
+                }
+            }
+        });
+    }
+
+    /// Get the resources used by the evaluation running on this thread, if any,
+    /// to hand them over to a thread that does part of its work.
+    pub(crate) fn usage(&self) -> Option<Arc<Usage>> {
+        self.with_running(|r| r.usage.clone())
+    }
+
+    /// Call a function as part of the evaluation that used the given resources.
+    ///
+    /// Its steps and timeout count towards that evaluation, but it has a depth of
+    /// its own, since it runs on this thread's stack.
+    pub(crate) fn with_usage<T>(&self, usage: Option<Arc<Usage>>, f: impl FnOnce() -> T) -> T {
+        let Some(usage) = usage else {
+            return f();
+        };
+        RUNNING.with(|running| {
+            running.borrow_mut().push(Running {
+                budget: self.id(),
+                depth: 0,
+                usage,
+                inherited: true,
+            })
+        });
+        let result = f();
+        RUNNING.with(|running| {
+            let mut running = running.borrow_mut();
+            if let Some(i) = running.iter().rposition(|r| r.budget == self.id() && r.inherited) {
+                running.remove(i);
+            }
+        });
+        result
+    }
+
+    /// Switch the evaluation running on this thread over to other resources,
+    /// such as when a generator is resumed by a different evaluation.
+    pub(crate) fn set_usage(&self, usage: Option<Arc<Usage>>) {
+        if let Some(usage) = usage {
+            self.with_running(|r| r.usage = usage);
+        }
+    }
+
+    /// Take an evaluation step.
+    pub(crate) fn step(&self, limits: &EvalLimits) -> Result<(), EvalError> {
+        let Some((steps, deadline)) = self.with_running(|r| {
+            (r.usage.steps.fetch_add(1, Ordering::Relaxed) + 1, r.usage.deadline)
+        }) else {
+            return Ok(());
+        };
+        if let Some(max_steps) = limits.max_steps {
+            if steps > max_steps {
+                return Err(EvalError::new(
+                    ErrorKind::StepLimit,
+                    format!("Exceeded the maximum of {max_steps} evaluation steps"),
+                ));
+            }
+        }
+        if let Some(timeout) = limits.timeout {
+            if steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(EvalError::new(
+                    ErrorKind::Timeout,
+                    format!("Evaluation took longer than {timeout:?}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the size of a collection against the limit.
+    pub(crate) fn check_size(limits: &EvalLimits, size: usize) -> Result<(), EvalError> {
+        match limits.max_collection_size {
+            Some(max_size) if size > max_size => Err(EvalError::new(
+                ErrorKind::SizeLimit,
+                format!("Collection of size {size} exceeds the maximum of {max_size}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the size of a result against the limit, if it is a collection.
+    pub(crate) fn check_result(limits: &EvalLimits, result: &Expr) -> Result<(), EvalError> {
+        match collection_size(result) {
+            Some(size) => Self::check_size(limits, size),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A handle for interrupting the evaluations in an environment from another thread.
+///
+/// Once triggered, every evaluation in the environment stops at its next step with
+/// an [`ErrorKind::Interrupted`] error, until the handle is [reset](InterruptHandle::reset).
+/// Because the interrupt stays triggered, a `try` in the program cannot keep it running.
+///
+/// ```rust
+/// use sage_lisp::{Env, ErrorKind, Expr};
+///
+/// let mut env = Env::new();
+/// let handle = env.interrupt_handle();
+/// handle.interrupt();
+/// match env.eval_str("(f 1)").unwrap() {
+///     Expr::Err(e) => assert_eq!(e.kind, ErrorKind::Interrupted),
+///     result => panic!("expected an error, got {result}"),
+/// }
+///
+/// handle.reset();
+/// assert_eq!(env.eval_str("1").unwrap(), Expr::Int(1));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Interrupt the evaluations in the environment.
+    #[inline]
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Has the handle been triggered?
+    #[inline]
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clear the interrupt, so the environment can evaluate expressions again.
+    #[inline]
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    /// Return an error if the handle has been triggered.
+    #[inline]
+    pub(crate) fn check(&self) -> Result<(), EvalError> {
+        if self.is_interrupted() {
+            Err(EvalError::new(ErrorKind::Interrupted, "Evaluation was interrupted"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Env {
+    /// Get a handle for interrupting evaluations in the environment from another thread.
+    ///
+    /// The handle is shared by every scope of the environment.
+    #[inline]
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.context.interrupt.clone()
+    }
+
+    /// Return an error if the environment has been interrupted.
+    ///
+    /// Evaluation checks this at every step. Builtins that loop for a long time
+    /// without evaluating anything can call this to stop early.
+    #[inline]
+    pub fn check_interrupt(&self) -> Result<(), EvalError> {
+        self.context.interrupt.check()
+    }
+
+    /// Set the limits on evaluating expressions in the environment.
+    ///
+    /// The limits are shared by every scope of the environment, and every
+    /// closure created in it.
+    pub fn set_limits(&mut self, limits: EvalLimits) {
+        let budget = &self.context.budget;
+        *budget.limits.lock().unwrap() = limits;
+        budget.enabled.store(limits.is_limited(), Ordering::Relaxed);
+    }
+
+    /// Get the limits on evaluating expressions in the environment.
+    pub fn limits(&self) -> EvalLimits {
+        self.context.budget.limits()
+    }
+
+    /// Check that a collection of the given size is within the limits of the environment.
+    ///
+    /// Results are checked automatically after they are produced. Builtins that
+    /// build large collections can call this beforehand, to fail before allocating.
+    pub fn check_collection_size(&self, size: usize) -> Result<(), EvalError> {
+        let budget = &self.context.budget;
+        if budget.is_enabled() {
+            Budget::check_size(&budget.limits(), size)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Evaluate an expression while enforcing the limits of the environment.
+    pub(crate) fn eval_limited(&mut self, expr: Expr) -> Expr {
+        let context = self.context.clone();
+        let budget = &context.budget;
+        let limits = budget.limits();
+        let result = match budget.enter(&limits) {
+            Ok(()) => self.eval_expr(expr, Some(&limits)),
+            Err(e) => e.into(),
+        };
+        budget.exit();
+
+        match Budget::check_result(&limits, &result) {
+            Ok(()) => result,
+            Err(e) => e.into(),
+        }
+    }
+}
+
+/// The size of an expression, if it is a collection.
+fn collection_size(expr: &Expr) -> Option<usize> {
+    match expr {
+        Expr::String(s) => Some(s.len()),
+        Expr::List(l) => Some(l.len()),
+        Expr::Tree(t) => Some(t.len()),
+        Expr::Map(m) => Some(m.len()),
+        _ => None,
+    }
+}
@@ -0,0 +1,198 @@
+//! # Macros
+//!
+//! Macros are functions that transform code before it is evaluated. They are
+//! defined with `defmacro`, and when a macro is called, it receives its arguments
+//! unevaluated. The expression it returns, called the expansion, is evaluated in
+//! place of the call.
+//!
+//! Expansions are usually written with quasiquote templates. A quasiquoted expression
+//! is quoted, except for the parts marked with unquote, which are evaluated and
+//! inserted, and the parts marked with unquote-splicing, which are evaluated and
+//! spliced into the surrounding list.
+//!
+//! ```lisp
+//! (defmacro unless (cond &rest body)
+//!     `(if ,cond nil (do ,@body)))
+//! ```
+//!
+//! The reader turns `` `x ``, `,x` and `,@x` into `(quasiquote x)`, `(unquote x)`
+//! and `(unquote-splicing x)`.
+use super::{Env, ErrorKind, Expr, HashTrieMap, OrdMap};
+
+impl Env {
+    /// Expand a call to a macro once, without evaluating the expansion.
+    ///
+    /// The `name` is the expression that was called, which is used in errors.
+    pub(crate) fn expand_macro(&self, name: &Expr, expander: &Expr, args: Vec<Expr>) -> Expr {
+        match expander {
+            Expr::Function(closure, params, body) => {
+                let mut expander_env = match closure {
+                    Some(closure) => closure.new_scope(),
+                    None => self.new_scope(),
+                };
+                if let Err(err) = expander_env.bind_params(name, params, args) {
+                    return err.into();
+                }
+                expander_env.eval(*body.clone())
+            }
+            expander => Expr::error_of(ErrorKind::Type, format!("Invalid macro {expander}")),
+        }
+    }
+
+    /// If the form is a call to a macro, expand it once.
+    ///
+    /// Returns `None` if the form is not a macro call.
+    fn try_macroexpand_1(&self, form: &Expr) -> Option<Expr> {
+        match form {
+            Expr::List(l) => match l.first().and_then(|head| self.get(head)) {
+                Some(Expr::Macro(expander)) => {
+                    Some(self.expand_macro(&l[0], &expander, l.iter().skip(1).cloned().collect()))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Expand a form once, if it is a call to a macro bound in the environment.
+    ///
+    /// Other forms are returned unchanged.
+    pub fn macroexpand_1(&self, form: Expr) -> Expr {
+        self.try_macroexpand_1(&form).unwrap_or(form)
+    }
+
+    /// Expand a form repeatedly, until it is no longer a call to a macro.
+    ///
+    /// Only the outermost form is expanded, not the forms nested inside it.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_core_forms();
+    /// env.eval_str("(defmacro twice (x) `(list ,x ,x))").unwrap();
+    /// let expansion = env.macroexpand(Expr::parse("(twice (f))").unwrap());
+    /// assert_eq!(expansion, Expr::parse("(list (f) (f))").unwrap());
+    /// ```
+    pub fn macroexpand(&self, mut form: Expr) -> Expr {
+        while let Some(expansion) = self.try_macroexpand_1(&form) {
+            if expansion.is_err() {
+                return expansion;
+            }
+            form = expansion;
+        }
+        form
+    }
+
+    /// Evaluate a quasiquote template.
+    ///
+    /// The `depth` is the number of quasiquotes the template is nested in, so
+    /// that only the unquotes belonging to the outermost quasiquote are evaluated.
+    pub(crate) fn quasiquote(&mut self, template: Expr, depth: usize) -> Expr {
+        match template {
+            Expr::List(items) => {
+                if items.len() == 2 {
+                    let (head, arg) = (&items[0], &items[1]);
+                    if *head == Expr::symbol("unquote") {
+                        return if depth == 1 {
+                            self.eval(arg.clone())
+                        } else {
+                            self.quasiquote_nested(head, arg, depth - 1)
+                        };
+                    }
+                    if *head == Expr::symbol("unquote-splicing") {
+                        return if depth == 1 {
+                            Expr::error("unquote-splicing must be used inside a list")
+                        } else {
+                            self.quasiquote_nested(head, arg, depth - 1)
+                        };
+                    }
+                    if *head == Expr::symbol("quasiquote") {
+                        return self.quasiquote_nested(head, arg, depth + 1);
+                    }
+                }
+                match self.quasiquote_items(items.into_iter(), depth) {
+                    Ok(items) => Expr::List(items.into()),
+                    Err(err) => err,
+                }
+            }
+            Expr::Many(items) => match self.quasiquote_items(items.iter().cloned(), depth) {
+                Ok(items) => Expr::Many(items.into()),
+                Err(err) => err,
+            },
+            Expr::Tree(t) => {
+                let mut result = OrdMap::new();
+                for (k, v) in t {
+                    let k = self.quasiquote(k, depth);
+                    let v = self.quasiquote(v, depth);
+                    if let Some(err) = [&k, &v].into_iter().find(|e| e.is_err()) {
+                        return err.clone();
+                    }
+                    result.insert(k, v);
+                }
+                Expr::Tree(result)
+            }
+            Expr::Map(m) => {
+                let mut result = HashTrieMap::new();
+                for (k, v) in m {
+                    let k = self.quasiquote(k, depth);
+                    let v = self.quasiquote(v, depth);
+                    if let Some(err) = [&k, &v].into_iter().find(|e| e.is_err()) {
+                        return err.clone();
+                    }
+                    result.insert(k, v);
+                }
+                Expr::Map(result)
+            }
+            Expr::Quote(e) => match self.quasiquote(*e, depth) {
+                Expr::Err(err) => Expr::Err(err),
+                e => e.quote(),
+            },
+            template => template,
+        }
+    }
+
+    /// Rebuild a nested `(quasiquote x)` or `(unquote x)` form at a new depth.
+    fn quasiquote_nested(&mut self, head: &Expr, arg: &Expr, depth: usize) -> Expr {
+        match self.quasiquote(arg.clone(), depth) {
+            Expr::Err(err) => Expr::Err(err),
+            arg => Expr::List(vec![head.clone(), arg].into()),
+        }
+    }
+
+    /// Evaluate the items of a list in a quasiquote template, splicing in
+    /// the values of any `(unquote-splicing x)` forms.
+    fn quasiquote_items(
+        &mut self,
+        items: impl ExactSizeIterator<Item = Expr>,
+        depth: usize,
+    ) -> Result<Vec<Expr>, Expr> {
+        let mut result = Vec::with_capacity(items.len());
+        for item in items {
+            match &item {
+                Expr::List(l)
+                    if depth == 1
+                        && l.len() == 2
+                        && l[0] == Expr::symbol("unquote-splicing") =>
+                {
+                    match self.eval(l[1].clone()) {
+                        Expr::List(values) => result.extend(values),
+                        Expr::None => {}
+                        Expr::Err(err) => return Err(Expr::Err(err)),
+                        value => {
+                            return Err(Expr::error_of(
+                                ErrorKind::Type,
+                                format!("Cannot splice {value} into a list, expected a list"),
+                            ))
+                        }
+                    }
+                }
+                _ => match self.quasiquote(item, depth) {
+                    Expr::Err(err) => return Err(Expr::Err(err)),
+                    item => result.push(item),
+                },
+            }
+        }
+        Ok(result)
+    }
+}
@@ -15,23 +15,28 @@ pub struct Program {
     // A string to evaluate.
     #[arg(short = 'c', long)]
     program: Option<String>,
+    // Compile the program to bytecode before evaluating it.
+    #[arg(long)]
+    compile: bool,
 }
 
 fn make_env() -> Env {
     let mut env = Env::new();
-    env.bind_builtin("env", |env, args| {
+    env.bind_core_forms();
+    env.bind_strict_builtin("env", |env, args| {
         // Get the env as a map
         if args.is_empty() {
-            return Expr::Map(env.get_bindings());
+            return Expr::Map(env.get_bindings().into());
         }
         let a = env.eval(args[0].clone());
-        env.get(&a).cloned().unwrap_or(Expr::None)
+        env.get(&a).unwrap_or(Expr::None)
     });
 
-    env.bind_builtin("+", |env, exprs| {
+    // Generic, so programs can add methods for their own types with `defmethod`.
+    env.bind_generic("+", |env, exprs| {
         let mut sum = Expr::default();
         for e in exprs {
-            let e = env.eval(e.clone());
+            let e = env.eval_realized(e.clone());
             // sum += env.eval(e);
             match (sum, e) {
                 (Expr::None, b) => sum = b,
@@ -40,24 +45,22 @@ fn make_env() -> Env {
                 (Expr::Int(a), Expr::Float(b)) => sum = Expr::Float(a as f64 + b),
                 (Expr::Float(a), Expr::Int(b)) => sum = Expr::Float(a + b as f64),
                 (Expr::String(a), Expr::String(b)) => sum = Expr::String(format!("{}{}", a, b)),
-                (Expr::List(a), Expr::List(b)) => {
-                    let mut list = a.clone();
-                    list.extend(b);
-                    sum = Expr::List(list);
+                (Expr::List(mut a), Expr::List(b)) => {
+                    a.append(b);
+                    sum = Expr::List(a);
                 }
-                (Expr::List(a), b) => {
-                    let mut list = a.clone();
-                    list.push(b);
-                    sum = Expr::List(list);
+                (Expr::List(mut a), b) => {
+                    a.push_back(b);
+                    sum = Expr::List(a);
                 }
-                (a, b) => return Expr::error(format!("Invalid expr {} + {}", a, b)),
+                (a, b) => return Expr::error_of(ErrorKind::Type, format!("Invalid expr {} + {}", a, b)),
             }
         }
         sum
     });
     env.alias("+", "add");
 
-    env.bind_builtin("-", |env, exprs| {
+    env.bind_strict_builtin("-", |env, exprs| {
         let mut diff = Expr::default();
         for e in exprs {
             let e = env.eval(e.clone());
@@ -67,14 +70,14 @@ fn make_env() -> Env {
                 (Expr::Float(a), Expr::Float(b)) => diff = Expr::Float(a - b),
                 (Expr::Int(a), Expr::Float(b)) => diff = Expr::Float(a as f64 - b),
                 (Expr::Float(a), Expr::Int(b)) => diff = Expr::Float(a - b as f64),
-                (a, b) => return Expr::error(format!("Invalid expr {} - {}", a, b)),
+                (a, b) => return Expr::error_of(ErrorKind::Type, format!("Invalid expr {} - {}", a, b)),
             }
         }
         diff
     });
     env.alias("-", "sub");
 
-    env.bind_builtin("*", |env, exprs| {
+    env.bind_strict_builtin("*", |env, exprs| {
         let mut product = Expr::default();
         for e in exprs {
             let e = env.eval(e.clone());
@@ -87,18 +90,18 @@ fn make_env() -> Env {
                 (Expr::List(a), Expr::Int(b)) => {
                     let mut list = a.clone();
                     for _ in 0..b {
-                        list.extend(a.clone());
+                        list.append(a.clone());
                     }
                     product = Expr::List(list);
                 }
-                (a, b) => return Expr::error(format!("Invalid expr {} * {}", a, b)),
+                (a, b) => return Expr::error_of(ErrorKind::Type, format!("Invalid expr {} * {}", a, b)),
             }
         }
         product
     });
     env.alias("*", "mul");
 
-    env.bind_builtin("/", |env, exprs| {
+    env.bind_strict_builtin("/", |env, exprs| {
         let mut quotient = Expr::default();
         for e in exprs {
             let e = env.eval(e.clone());
@@ -108,14 +111,14 @@ fn make_env() -> Env {
                 (Expr::Float(a), Expr::Float(b)) => quotient = Expr::Float(a / b),
                 (Expr::Int(a), Expr::Float(b)) => quotient = Expr::Float(a as f64 / b),
                 (Expr::Float(a), Expr::Int(b)) => quotient = Expr::Float(a / b as f64),
-                (a, b) => return Expr::error(format!("Invalid expr {} / {}", a, b)),
+                (a, b) => return Expr::error_of(ErrorKind::Type, format!("Invalid expr {} / {}", a, b)),
             }
         }
         quotient
     });
     env.alias("/", "div");
 
-    env.bind_builtin("%", |env, exprs| {
+    env.bind_strict_builtin("%", |env, exprs| {
         let mut quotient = Expr::default();
         for e in exprs {
             let e = env.eval(e.clone());
@@ -125,14 +128,14 @@ fn make_env() -> Env {
                 (Expr::Float(a), Expr::Float(b)) => quotient = Expr::Float(a % b),
                 (Expr::Int(a), Expr::Float(b)) => quotient = Expr::Float(a as f64 % b),
                 (Expr::Float(a), Expr::Int(b)) => quotient = Expr::Float(a % b as f64),
-                (a, b) => return Expr::error(format!("Invalid expr {} % {}", a, b)),
+                (a, b) => return Expr::error_of(ErrorKind::Type, format!("Invalid expr {} % {}", a, b)),
             }
         }
         quotient
     });
     env.alias("%", "rem");
 
-    env.bind_builtin("=", |env, exprs| {
+    env.bind_strict_builtin("=", |env, exprs| {
         let a = env.eval(exprs[0].clone());
         let b = env.eval(exprs[1].clone());
 
@@ -140,65 +143,48 @@ fn make_env() -> Env {
     });
     env.alias("=", "==");
 
-    env.bind_builtin("!=", |env, exprs| {
+    env.bind_strict_builtin("!=", |env, exprs| {
         let a = env.eval(exprs[0].clone());
         let b = env.eval(exprs[1].clone());
 
         Expr::Bool(a != b)
     });
 
-    env.bind_builtin("<=", |env, exprs| {
+    env.bind_strict_builtin("<=", |env, exprs| {
         let a = env.eval(exprs[0].clone());
         let b = env.eval(exprs[1].clone());
 
         Expr::Bool(a <= b)
     });
 
-    env.bind_builtin(">=", |env, exprs| {
+    env.bind_strict_builtin(">=", |env, exprs| {
         let a = env.eval(exprs[0].clone());
         let b = env.eval(exprs[1].clone());
 
         Expr::Bool(a >= b)
     });
 
-    env.bind_builtin("<", |env, exprs| {
+    env.bind_strict_builtin("<", |env, exprs| {
         let a = env.eval(exprs[0].clone());
         let b = env.eval(exprs[1].clone());
 
         Expr::Bool(a < b)
     });
 
-    env.bind_builtin(">", |env, exprs| {
+    env.bind_strict_builtin(">", |env, exprs| {
         let a = env.eval(exprs[0].clone());
         let b = env.eval(exprs[1].clone());
 
         Expr::Bool(a > b)
     });
 
-    env.bind_lazy_builtin("if", |env, exprs| {
-        let cond = env.eval(exprs[0].clone());
-        let then = exprs[1].clone();
-        if exprs.len() < 3 {
-            if cond == Expr::Bool(true) {
-                then
-            } else {
-                Expr::None
-            }
-        } else {
-            let else_ = exprs[2].clone();
-            if cond == Expr::Bool(true) {
-                then
-            } else {
-                else_
-            }
-        }
-    });
-
     env.bind_builtin("define", |env, exprs| {
         let name = exprs[0].clone();
         let value = env.eval(exprs[1].clone());
-        env.bind(name, value);
-        Expr::None
+        match env.bind_pattern(name, value) {
+            Ok(()) => Expr::None,
+            Err(e) => e.into(),
+        }
     });
 
     env.bind_builtin("undefine", |env, exprs| {
@@ -212,18 +198,26 @@ fn make_env() -> Env {
         let params = args[1].clone();
         let body = args[2].clone();
         if let Expr::List(params) = params {
-            let f = env.eval(Expr::Function(None, params, Box::new(body)));
+            let f = env.eval(Expr::Function(None, params.to_vec(), Box::new(body)));
             env.bind(name, f);
             Expr::None
         } else {
-            return Expr::error(format!("Invalid params {:?}", params));
+            Expr::error_of(ErrorKind::Type, format!("Invalid params {:?}", params))
         }
     });
 
-    env.bind_builtin("println", |env, exprs| {
+    env.bind_strict_builtin("println", |env, exprs| {
+        let mut values = vec![];
         for e in exprs {
             let e = env.eval(e.clone());
+            // Don't print anything if one of the arguments failed.
+            if e.is_err() {
+                return e;
+            }
+            values.push(e);
+        }
 
+        for e in values {
             match e {
                 Expr::String(s) => print!("{}", s),
                 Expr::Symbol(s) => print!("{}", s.name()),
@@ -234,29 +228,16 @@ fn make_env() -> Env {
         Expr::None
     });
 
-    // env.bind_builtin("do", |env, exprs| {
-    //     let mut result = Expr::default();
-    //     for e in exprs {
-    //         result = env.eval(e.clone());
-    //     }
-    //     result
-    // });
-
-    env.bind_lazy_builtin("do", |_env, exprs| {
-        use std::sync::Arc;
-        Expr::Many(Arc::new(Vec::from(exprs)))
-    });
-
-    env.bind_builtin("sqrt", |env, expr| {
+    env.bind_strict_builtin("sqrt", |env, expr| {
         let e = env.eval(expr[0].clone());
         match e {
             Expr::Int(i) => Expr::Float((i as f64).sqrt()),
             Expr::Float(f) => Expr::Float(f.sqrt()),
-            e => Expr::error(format!("Invalid expr sqrt {}", e)),
+            e => Expr::error_of(ErrorKind::Type, format!("Invalid expr sqrt {}", e)),
         }
     });
 
-    env.bind_builtin("^", |env, expr| {
+    env.bind_strict_builtin("^", |env, expr| {
         let a = env.eval(expr[0].clone());
         let b = env.eval(expr[1].clone());
         match (a, b) {
@@ -264,86 +245,67 @@ fn make_env() -> Env {
             (Expr::Float(a), Expr::Float(b)) => Expr::Float(a.powf(b)),
             (Expr::Int(a), Expr::Float(b)) => Expr::Float((a as f64).powf(b)),
             (Expr::Float(a), Expr::Int(b)) => Expr::Float(a.powf(b as f64)),
-            (a, b) => Expr::error(format!("Invalid expr {} ^ {}", a, b)),
+            (a, b) => Expr::error_of(ErrorKind::Type, format!("Invalid expr {} ^ {}", a, b)),
         }
     });
 
     env.alias("^", "pow");
 
-    let lambda = |env: &mut Env, expr: Vec<Expr>| {
-        let params = expr[0].clone();
-        let body = expr[1].clone();
-        if let Expr::List(params) = params {
-            Expr::Function(Some(Box::new(env.clone())), params, Box::new(body))
-        } else {
-            return Expr::error(format!("Invalid params {:?}", params));
-        }
-    };
-    env.bind_builtin("lambda", lambda);
-    env.bind_builtin("\\", lambda);
-
-    env.bind_builtin("apply", |env, expr| {
+    env.bind_strict_builtin("apply", |env, expr| {
         let f = env.eval(expr[0].clone());
-        let args = env.eval(expr[1].clone());
+        let args = env.eval_realized(expr[1].clone());
         if let Expr::List(args) = args {
             match f {
-                Expr::Function(Some(mut env), params, body) => {
-                    let mut new_env = env.clone();
-                    for (param, arg) in params.iter().zip(args.iter()) {
-                        new_env.bind(param.clone(), env.eval(arg.clone()));
-                    }
-                    new_env.eval(*body.clone())
-                }
-                Expr::Function(None, params, body) => {
-                    let mut new_env = env.clone();
-                    for (param, arg) in params.iter().zip(args.iter()) {
-                        new_env.bind(param.clone(), env.eval(arg.clone()));
-                    }
-                    new_env.eval(*body.clone())
+                Expr::Function(..) | Expr::Builtin(_) => {
+                    // Quote the arguments so they aren't evaluated again by the call.
+                    let args = args.iter().map(Expr::quote).collect::<Vec<_>>();
+                    env.eval(f.apply(&args))
                 }
-                Expr::Builtin(f) => f.apply(&mut env.clone(), args),
-                f => Expr::error(format!("Invalid function {f} apply {}", Expr::from(args))),
+                f => Expr::error_of(ErrorKind::Type, format!("Invalid function {f} apply {}", Expr::from(args))),
             }
         } else {
-            Expr::error(format!("Invalid function {f} apply {}", Expr::from(args)))
+            Expr::error_of(ErrorKind::Type, format!("Invalid function {f} apply {}", args))
         }
     });
 
-    env.bind_builtin("cons", |env, expr| {
+    env.bind_strict_builtin("cons", |env, expr| {
         let a = env.eval(expr[0].clone());
-        let b = env.eval(expr[1].clone());
+        let b = env.eval_realized(expr[1].clone());
         // Create a new list with a as the head and b as the tail.
-        if let Expr::List(b) = b {
-            let mut list = vec![a];
-            list.extend(b);
-            Expr::List(list)
+        if let Expr::List(mut b) = b {
+            b.push_front(a);
+            Expr::List(b)
         } else if b == Expr::None {
-            Expr::List(vec![a])
+            Expr::List(vec![a].into())
         } else {
-            Expr::List(vec![a, b])
+            Expr::List(vec![a, b].into())
         }
     });
     let head = |env: &mut Env, expr: Vec<Expr>| {
         let a = env.eval(expr[0].clone());
         if let Expr::List(a) = a {
             a[0].clone()
+        } else if let Expr::Seq(a) = a {
+            a.first(env).map_or_else(|e| e, Option::unwrap_or_default)
         } else {
-            Expr::error(format!("Invalid head {a}"))
+            Expr::error_of(ErrorKind::Type, format!("Invalid head {a}"))
         }
     };
     let tail = |env: &mut Env, expr: Vec<Expr>| {
         let a = env.eval(expr[0].clone());
         if let Expr::List(a) = a {
-            Expr::List(a[1..].to_vec())
+            Expr::List(a.slice(1..))
+        } else if let Expr::Seq(a) = a {
+            Expr::Seq(a.skip(1))
         } else {
-            Expr::error(format!("Invalid tail {a}"))
+            Expr::error_of(ErrorKind::Type, format!("Invalid tail {a}"))
         }
     };
 
-    env.bind_builtin("car", head);
-    env.bind_builtin("head", head);
-    env.bind_builtin("cdr", tail);
-    env.bind_builtin("tail", tail);
+    env.bind_strict_builtin("car", head);
+    env.bind_strict_builtin("head", head);
+    env.bind_strict_builtin("cdr", tail);
+    env.bind_strict_builtin("tail", tail);
 
     let format = |env: &mut Env, expr: Vec<Expr>| {
         let format = env.eval(expr[0].clone());
@@ -352,7 +314,7 @@ fn make_env() -> Env {
 
         let mut format = match format {
             Expr::String(s) => s,
-            e => return Expr::error(format!("Invalid format {e}")),
+            e => return Expr::error_of(ErrorKind::Type, format!("Invalid format {e}")),
         };
 
         // Find all of the format specifiers.
@@ -377,7 +339,7 @@ fn make_env() -> Env {
             }
             let name = Expr::Symbol(Symbol::new(name));
 
-            let value = env.eval(name.clone());
+            let value = env.eval_realized(name.clone());
             let specifier = format!("{{{name}}}");
             match value {
                 Expr::String(s) => {
@@ -396,10 +358,10 @@ fn make_env() -> Env {
                 continue;
             }
             if i >= args.len() {
-                return Expr::error("Too few arguments");
+                return Expr::error_of(ErrorKind::Arity, "Too few arguments");
             }
-            let specifier = format!("{{}}");
-            let value = env.eval(args[i].clone());
+            let specifier = "{}".to_string();
+            let value = env.eval_realized(args[i].clone());
             match value {
                 Expr::String(s) => {
                     format = format.replacen(&specifier, &s, 1);
@@ -413,41 +375,41 @@ fn make_env() -> Env {
         }
 
         if i < args.len() {
-            return Expr::error("Too many arguments");
+            return Expr::error_of(ErrorKind::Arity, "Too many arguments");
         }
 
         Expr::String(format)
     };
 
-    env.bind_builtin("format", format);
+    env.bind_strict_builtin("format", format);
 
-    env.bind_builtin("list", |env, expr| {
+    env.bind_strict_builtin("list", |env, expr| {
         let mut list = vec![];
         for e in expr {
             list.push(env.eval(e.clone()));
         }
-        Expr::List(list)
+        Expr::List(list.into())
     });
 
-    env.bind_builtin("append", |env, expr| {
-        let mut list = vec![];
+    env.bind_strict_builtin("append", |env, expr| {
+        let mut list = Vector::new();
         for e in expr {
-            let e = env.eval(e.clone());
+            let e = env.eval_realized(e.clone());
             if let Expr::List(l) = e {
-                list.extend(l);
+                list.append(l);
             } else {
-                return Expr::error(format!("Invalid append {e}"));
+                return Expr::error_of(ErrorKind::Type, format!("Invalid append {e}"));
             }
         }
         Expr::List(list)
     });
 
-    env.bind_builtin("eval", |env, expr| {
+    env.bind_strict_builtin("eval", |env, expr| {
         let e = env.eval(expr[0].clone());
         env.eval(e)
     });
 
-    env.bind_builtin("exit", |env, expr| match env.eval(expr[0].clone()) {
+    env.bind_strict_builtin("exit", |env, expr| match env.eval(expr[0].clone()) {
         Expr::Int(i) => std::process::exit(i as i32),
         Expr::String(s) => {
             eprintln!("{s}");
@@ -459,8 +421,6 @@ fn make_env() -> Env {
         }
     });
 
-    env.bind_builtin("quote", |_env, expr| expr[0].clone());
-
     env.bind_builtin("or", |env, expr| {
         for e in expr {
             let e = env.eval(e.clone());
@@ -481,27 +441,38 @@ fn make_env() -> Env {
         Expr::Bool(true)
     });
 
-    env.bind_builtin("not", |env, expr| {
+    env.bind_strict_builtin("not", |env, expr| {
         let e = env.eval(expr[0].clone());
         match e {
             Expr::Bool(b) => Expr::Bool(!b),
-            e => return Expr::error(format!("Invalid not {e}")),
+            e => Expr::error_of(ErrorKind::Type, format!("Invalid not {e}")),
         }
     });
 
-    env.bind_builtin("len", |env, expr| {
-        let e = env.eval(expr[0].clone());
+    // Generic, like `+`.
+    env.bind_generic("len", |env, expr| {
+        let e = env.eval_realized(expr[0].clone());
         match e {
             Expr::String(s) => Expr::Int(s.len() as i64),
             Expr::List(l) => Expr::Int(l.len() as i64),
             Expr::Map(m) => Expr::Int(m.len() as i64),
             Expr::Tree(t) => Expr::Int(t.len() as i64),
-            e => Expr::error(format!("Invalid len {e}")),
+            e => Expr::error_of(ErrorKind::Type, format!("Invalid len {e}")),
+        }
+    });
+
+    env.bind_strict_builtin("list?", |env, expr| {
+        let e = env.eval(expr[0].clone());
+        if e.is_err() {
+            return e;
         }
+        Expr::Bool(matches!(e, Expr::List(_)))
     });
 
+    // Bind each pattern to the value of its expression, like function parameters:
+    // (let ((x 1) ((a b) pair) (#[name n age a] person) ([name age] person)) body)
     env.bind_builtin("let", |env, expr| {
-        let mut new_env = env.clone();
+        let mut new_env = env.new_scope();
         let bindings = expr[0].clone();
         let body = expr[1].clone();
         match bindings {
@@ -510,28 +481,35 @@ fn make_env() -> Env {
                     if let Expr::List(binding) = binding {
                         let name = binding[0].clone();
                         let value = env.eval(binding[1].clone());
-                        new_env.bind(name, value);
+                        if let Err(e) = new_env.bind_pattern(name, value) {
+                            return e.into();
+                        }
                     } else {
-                        return Expr::error(format!("Invalid binding {binding}"));
+                        return Expr::error_of(ErrorKind::Type, format!("Invalid binding {binding}"));
                     }
                 }
             }
             Expr::Map(bindings) => {
                 for (name, value) in bindings {
-                    new_env.bind(name, env.eval(value));
+                    if let Err(e) = new_env.bind_pattern(name, env.eval(value)) {
+                        return e.into();
+                    }
                 }
             }
             Expr::Tree(bindings) => {
                 for (name, value) in bindings {
-                    new_env.bind(name, env.eval(value));
+                    if let Err(e) = new_env.bind_pattern(name, env.eval(value)) {
+                        return e.into();
+                    }
                 }
             }
-            bindings => return Expr::error(format!("Invalid bindings {bindings}")),
+            bindings => return Expr::error_of(ErrorKind::Type, format!("Invalid bindings {bindings}")),
         }
         new_env.eval(body)
     });
 
-    env.bind_builtin("get", |env, expr| {
+    // Generic, like `+`.
+    env.bind_generic("get", |env, expr| {
         let a = env.eval(expr[0].clone());
         let b = env.eval(expr[1].clone());
 
@@ -540,6 +518,7 @@ fn make_env() -> Env {
                 Expr::String(a.chars().nth(b as usize).unwrap_or('\0').to_string())
             }
             (Expr::List(a), Expr::Int(b)) => a.get(b as usize).cloned().unwrap_or(Expr::None),
+            (Expr::Seq(a), Expr::Int(b)) if b >= 0 => a.nth(env, b as usize).map_or_else(|e| e, Option::unwrap_or_default),
             (Expr::Map(a), Expr::Symbol(b)) => {
                 // a.get(&b).cloned().unwrap_or(Expr::None)
                 a.get(&Expr::Symbol(b.clone())).cloned().unwrap_or_else(|| {
@@ -550,13 +529,22 @@ fn make_env() -> Env {
             }
             (Expr::Map(a), b) => a.get(&b).cloned().unwrap_or(Expr::None),
             (Expr::Tree(a), b) => a.get(&b).cloned().unwrap_or(Expr::None),
-            (a, b) => return Expr::error(format!("Invalid expr get {} {}", a, b)),
+            (a, b) => Expr::error_of(ErrorKind::Type, format!("Invalid expr get {} {}", a, b)),
         }
     });
+    // Records get their fields by name, like a map imported with serde.
+    env.bind_method("get", &["record", "any"], |env, expr| match env.eval(expr[0].clone()) {
+        Expr::Record(a) => {
+            let b = env.eval(expr[1].clone());
+            a.get_key(&b).cloned().unwrap_or(Expr::None)
+        }
+        a => Expr::error_of(ErrorKind::Type, format!("Invalid expr get {a}")),
+    })
+    .expect("get is generic");
     env.alias("get", "@");
 
-    env.bind_builtin("set", |env, expr| {
-        let a = env.eval(expr[0].clone());
+    env.bind_strict_builtin("set", |env, expr| {
+        let a = env.eval_realized(expr[0].clone());
         let b = env.eval(expr[1].clone());
         let c = env.eval(expr[2].clone());
 
@@ -574,10 +562,10 @@ fn make_env() -> Env {
                 Expr::String(a)
             }
             (Expr::List(mut a), Expr::Int(b)) => {
-                if b as usize >= a.len() {
-                    a.resize(b as usize + 1, Expr::None);
+                while b as usize >= a.len() {
+                    a.push_back(Expr::None);
                 }
-                a[b as usize] = c;
+                a.set(b as usize, c);
                 Expr::List(a)
             }
             (Expr::Map(mut a), b) => {
@@ -588,140 +576,168 @@ fn make_env() -> Env {
                 a.insert(b, c);
                 Expr::Tree(a)
             }
-            (a, b) => return Expr::error(format!("Invalid expr set {} {} {}", a, b, c)),
+            (a, b) => Expr::error_of(ErrorKind::Type, format!("Invalid expr set {} {} {}", a, b, c)),
         }
     });
 
-    env.bind_builtin("zip", |env, expr| {
+    env.bind_strict_builtin("zip", |env, expr| {
         let a = env.eval(expr[0].clone());
         let b = env.eval(expr[1].clone());
 
         match (a, b) {
             (Expr::List(a), Expr::List(b)) => {
                 let mut list = vec![];
-                for (a, b) in a.into_iter().zip(b.into_iter()) {
-                    list.push(Expr::List(vec![a, b]));
+                for (a, b) in a.into_iter().zip(b) {
+                    list.push(Expr::List(vec![a, b].into()));
+                }
+                Expr::List(list.into())
+            }
+            // Zip lazily, so that a list can be zipped with an infinite sequence.
+            (a @ (Expr::List(_) | Expr::Seq(_)), b @ (Expr::List(_) | Expr::Seq(_))) => {
+                let seq = |e: Expr| match e {
+                    Expr::List(l) => Seq::from(l),
+                    Expr::Seq(s) => s,
+                    _ => unreachable!(),
+                };
+                let mut list = vec![];
+                for (a, b) in seq(a).iter(env).zip(seq(b).iter(env)) {
+                    match (a, b) {
+                        (Ok(a), Ok(b)) => list.push(Expr::List(vec![a, b].into())),
+                        (Err(e), _) | (_, Err(e)) => return e,
+                    }
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
-            (a, b) => return Expr::error(format!("Invalid expr zip {} {}", a, b)),
+            (a, b) => Expr::error_of(ErrorKind::Type, format!("Invalid expr zip {} {}", a, b)),
         }
     });
 
     // Convert a list of pairs into a map.
-    env.bind_builtin("to-map", |env, expr| {
-        let a = env.eval(expr[0].clone());
+    env.bind_strict_builtin("to-map", |env, expr| {
+        let a = env.eval_realized(expr[0].clone());
         match a {
             Expr::List(a) => {
-                let mut map = std::collections::HashMap::new();
+                let mut map = HashTrieMap::new();
                 for e in a {
                     if let Expr::List(e) = e {
                         if e.len() == 2 {
                             map.insert(e[0].clone(), e[1].clone());
                         } else {
-                            return Expr::error(format!("Invalid pair {}", Expr::from(e)));
+                            return Expr::error_of(ErrorKind::Type, format!("Invalid pair {}", Expr::from(e)));
                         }
                     } else {
-                        return Expr::error(format!("Invalid pair {}", Expr::from(e)));
+                        return Expr::error_of(ErrorKind::Type, format!("Invalid pair {}", e));
                     }
                 }
                 Expr::Map(map)
             }
-            Expr::Map(a) => return Expr::Map(a),
-            Expr::Tree(a) => return Expr::Map(a.into_iter().collect()),
-            a => return Expr::error(format!("Invalid expr to-map {}", Expr::from(a))),
+            Expr::Map(a) => Expr::Map(a),
+            Expr::Tree(a) => Expr::Map(a.into_iter().collect()),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr to-map {}", a)),
         }
     });
 
     // Convert a list of pairs into a tree.
-    env.bind_builtin("to-tree", |env, expr| {
-        let a = env.eval(expr[0].clone());
+    env.bind_strict_builtin("to-tree", |env, expr| {
+        let a = env.eval_realized(expr[0].clone());
         match a {
             Expr::List(a) => {
-                let mut tree = std::collections::BTreeMap::new();
+                let mut tree = OrdMap::new();
                 for e in a {
                     if let Expr::List(e) = e {
                         if e.len() == 2 {
                             tree.insert(e[0].clone(), e[1].clone());
                         } else {
-                            return Expr::error(format!("Invalid pair {}", Expr::from(e)));
+                            return Expr::error_of(ErrorKind::Type, format!("Invalid pair {}", Expr::from(e)));
                         }
                     } else {
-                        return Expr::error(format!("Invalid pair {}", Expr::from(e)));
+                        return Expr::error_of(ErrorKind::Type, format!("Invalid pair {}", e));
                     }
                 }
                 Expr::Tree(tree)
             }
-            Expr::Map(a) => return Expr::Tree(a.into_iter().collect()),
-            Expr::Tree(a) => return Expr::Tree(a),
-            a => return Expr::error(format!("Invalid expr to-tree {}", Expr::from(a))),
+            Expr::Map(a) => Expr::Tree(a.into_iter().collect()),
+            Expr::Tree(a) => Expr::Tree(a),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr to-tree {}", a)),
         }
     });
 
-    env.bind_builtin("to-list", |env, expr| {
-        let a = env.eval(expr[0].clone());
+    env.bind_strict_builtin("to-list", |env, expr| {
+        let a = env.eval_realized(expr[0].clone());
         match a {
             Expr::Map(a) => {
                 let mut list = vec![];
                 for (k, v) in a {
-                    list.push(Expr::List(vec![k, v]));
+                    list.push(Expr::List(vec![k, v].into()));
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
             Expr::Tree(a) => {
                 let mut list = vec![];
                 for (k, v) in a {
-                    list.push(Expr::List(vec![k, v]));
+                    list.push(Expr::List(vec![k, v].into()));
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
-            Expr::List(a) => return Expr::List(a),
-            a => return Expr::error(format!("Invalid expr to-list {}", a)),
+            Expr::List(a) => Expr::List(a),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr to-list {}", a)),
         }
     });
 
-    env.bind_builtin("map", |env, expr| {
+    // Generic, like `+`.
+    env.bind_generic("map", |env, expr| {
         let f = env.eval(expr[0].clone());
         let a = env.eval(expr[1].clone());
         match a {
             Expr::List(a) => {
                 let mut list = vec![];
                 for e in a {
-                    list.push(env.eval(Expr::List(vec![f.clone(), e])));
+                    let x = env.eval(Expr::List(vec![f.clone(), e].into()));
+                    if x.is_err() {
+                        return x;
+                    }
+                    list.push(x);
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
             Expr::Map(a) => {
-                let mut map = std::collections::HashMap::new();
+                let mut map = HashTrieMap::new();
                 for (k, v) in a {
-                    // map.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v])));
-                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    // map.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v].into())));
+                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
+                    if pair.is_err() {
+                        return pair;
+                    }
                     if let Expr::List(pair) = pair {
                         map.insert(pair[0].clone(), pair[1].clone());
                     } else {
-                        return Expr::error(format!("Invalid pair {}", pair));
+                        return Expr::error_of(ErrorKind::Type, format!("Invalid pair {}", pair));
                     }
                 }
                 Expr::Map(map)
             }
             Expr::Tree(a) => {
-                let mut tree = std::collections::BTreeMap::new();
+                let mut tree = OrdMap::new();
                 for (k, v) in a {
-                    // tree.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v])));
-                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    // tree.insert(k.clone(), env.eval(Expr::List(vec![f.clone(), k, v].into())));
+                    let pair = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
+                    if pair.is_err() {
+                        return pair;
+                    }
                     if let Expr::List(pair) = pair {
                         tree.insert(pair[0].clone(), pair[1].clone());
                     } else {
-                        return Expr::error(format!("Invalid pair {}", pair));
+                        return Expr::error_of(ErrorKind::Type, format!("Invalid pair {}", pair));
                     }
                 }
                 Expr::Tree(tree)
             }
-            a => return Expr::error(format!("Invalid expr map {}", a)),
+            Expr::Seq(a) => Expr::Seq(a.map(f)),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr map {}", a)),
         }
     });
 
-    env.bind_builtin("filter", |env, expr| {
+    env.bind_strict_builtin("filter", |env, expr| {
         let f = env.eval(expr[0].clone());
         let a = env.eval(expr[1].clone());
 
@@ -729,16 +745,23 @@ fn make_env() -> Env {
             Expr::List(a) => {
                 let mut list = vec![];
                 for e in a {
-                    if env.eval(Expr::List(vec![f.clone(), e.clone()])) == Expr::Bool(true) {
+                    let x = env.eval(Expr::List(vec![f.clone(), e.clone()].into()));
+                    if x.is_err() {
+                        return x;
+                    }
+                    if x == Expr::Bool(true) {
                         list.push(e);
                     }
                 }
-                Expr::List(list)
+                Expr::List(list.into())
             }
             Expr::Map(a) => {
-                let mut map = std::collections::HashMap::new();
+                let mut map = HashTrieMap::new();
                 for (k, v) in a {
-                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
+                    if x.is_err() {
+                        return x;
+                    }
                     if x == Expr::Bool(true) {
                         map.insert(k, v);
                     }
@@ -746,20 +769,62 @@ fn make_env() -> Env {
                 Expr::Map(map)
             }
             Expr::Tree(a) => {
-                let mut tree = std::collections::BTreeMap::new();
+                let mut tree = OrdMap::new();
                 for (k, v) in a {
-                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()]));
+                    let x = env.eval(Expr::List(vec![f.clone(), k.quote(), v.quote()].into()));
+                    if x.is_err() {
+                        return x;
+                    }
                     if x == Expr::Bool(true) {
                         tree.insert(k, v);
                     }
                 }
                 Expr::Tree(tree)
             }
-            a => return Expr::error(format!("Invalid expr filter {}", a)),
+            Expr::Seq(a) => Expr::Seq(a.filter(f)),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr filter {}", a)),
+        }
+    });
+
+    // Map a pure function over a list, calling it on several threads at once.
+    env.bind_strict_builtin("pmap", |env, expr| {
+        let f = env.eval(expr[0].clone());
+        let a = env.eval_realized(expr[1].clone());
+        match a {
+            Expr::List(a) => {
+                let calls = a.into_iter().map(|e| Expr::List(vec![f.clone(), e].into()));
+                match env.eval_parallel(calls) {
+                    Ok(list) => Expr::List(list.into()),
+                    Err(err) => err,
+                }
+            }
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr pmap {}", a)),
+        }
+    });
+
+    // Filter a list with a pure predicate, calling it on several threads at once.
+    env.bind_strict_builtin("pfilter", |env, expr| {
+        let f = env.eval(expr[0].clone());
+        let a = env.eval_realized(expr[1].clone());
+        match a {
+            Expr::List(a) => {
+                let calls = a.iter().map(|e| Expr::List(vec![f.clone(), e.clone()].into()));
+                match env.eval_parallel(calls) {
+                    Ok(keep) => a
+                        .into_iter()
+                        .zip(keep)
+                        .filter(|(_, keep)| *keep == Expr::Bool(true))
+                        .map(|(e, _)| e)
+                        .collect::<Vector<_>>()
+                        .into(),
+                    Err(err) => err,
+                }
+            }
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr pfilter {}", a)),
         }
     });
 
-    env.bind_builtin("reduce", |env, expr| {
+    env.bind_strict_builtin("reduce", |env, expr| {
         let f = env.eval(expr[0].clone());
         let a = env.eval(expr[1].clone());
         let b = env.eval(expr[2].clone());
@@ -768,31 +833,57 @@ fn make_env() -> Env {
             Expr::List(a) => {
                 let mut acc = b;
                 for e in a {
-                    acc = env.eval(Expr::List(vec![f.clone(), acc, e]));
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, e].into()));
+                    if acc.is_err() {
+                        return acc;
+                    }
                 }
                 acc
             }
             Expr::Map(a) => {
                 let mut acc = b;
                 for (k, v) in a {
-                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v]));
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v].into()));
+                    if acc.is_err() {
+                        return acc;
+                    }
                 }
                 acc
             }
             Expr::Tree(a) => {
                 let mut acc = b;
                 for (k, v) in a {
-                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v]));
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, k, v].into()));
+                    if acc.is_err() {
+                        return acc;
+                    }
                 }
                 acc
             }
-            a => return Expr::error(format!("Invalid expr reduce {}", a)),
+            Expr::Seq(a) => {
+                let mut acc = b;
+                for e in a.iter(env) {
+                    let e = match e {
+                        Ok(e) => e,
+                        Err(err) => return err,
+                    };
+                    acc = env.eval(Expr::List(vec![f.clone(), acc, e.quote()].into()));
+                    if acc.is_err() {
+                        return acc;
+                    }
+                }
+                acc
+            }
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr reduce {}", a)),
         }
     });
 
-    env.bind_builtin("range", |env, expr| {
+    // (range start end step) lists the integers from start to end, inclusive. The step
+    // defaults to 1. With a negative step, it counts down from start to end instead,
+    // like (range 5 1 (- 0 2)) => (5 3 1). Without an end, it counts forever, lazily.
+    env.bind_strict_builtin("range", |env, expr| {
         let a = env.eval(expr[0].clone());
-        let b = env.eval(expr[1].clone());
+        let b = expr.get(1).map_or(Expr::None, |b| env.eval(b.clone()));
         // Check if there is a step
         let c = if expr.len() == 3 {
             env.eval(expr[2].clone())
@@ -800,41 +891,147 @@ fn make_env() -> Env {
             Expr::Int(1)
         };
 
+        // Without an upper bound, count forever, lazily.
+        if b == Expr::None {
+            return match (a, c) {
+                (Expr::Int(a), Expr::Int(c)) => Expr::Seq(Seq::range(a, c, None)),
+                (a, c) => Expr::error_of(ErrorKind::Type, format!("Invalid expr range {} {}", a, c)),
+            };
+        }
+
         let (a, b) = match (a, b) {
                 (Expr::Int(a), Expr::Int(b)) => (a, b),
                 (Expr::Float(a), Expr::Float(b)) => (a as i64, b as i64),
                 (Expr::Int(a), Expr::Float(b)) => (a, b as i64),
                 (Expr::Float(a), Expr::Int(b)) => (a as i64, b),
-                (a, b) => return Expr::error(format!("Invalid expr range {} {}", a, b)),
+                (a, b) => return Expr::error_of(ErrorKind::Type, format!("Invalid expr range {} {}", a, b)),
         };
 
         let c = match c {
             Expr::Int(c) => c,
             Expr::Float(c) => c as i64,
-            c => return Expr::error(format!("Invalid expr range {}", c)),
+            c => return Expr::error_of(ErrorKind::Type, format!("Invalid expr range {}", c)),
         };
 
+        if c == 0 {
+            return Expr::error_of(ErrorKind::Type, "range expected a step other than 0");
+        }
+
+        // A negative step counts down to the end instead, like a lazy range.
+        let in_range = move |i: i64| if c > 0 { i <= b } else { i >= b };
+
+        // Fail before allocating a range that's too large for the limits.
+        // The size is computed in i128, so ranges near the ends of i64 can't overflow.
+        let size = if in_range(a) {
+            (b as i128 - a as i128) / c as i128 + 1
+        } else {
+            0
+        };
+        if let Err(e) = env.check_collection_size(usize::try_from(size).unwrap_or(usize::MAX)) {
+            return e.into();
+        }
+
         let mut list = vec![];
-        let mut i = a;
-        while i <= b {
+        let mut next = Some(a);
+        while let Some(i) = next.filter(|&i| in_range(i)) {
+            // Let the host stop a long range early.
+            if list.len() % 4096 == 0 {
+                if let Err(e) = env.check_interrupt() {
+                    return e.into();
+                }
+            }
             list.push(Expr::Int(i));
-            i += c;
+            next = i.checked_add(c);
         }
-        Expr::List(list)
+        Expr::List(list.into())
     });
 
-    env.bind_builtin("rev", |env, expr| {
-        let a = env.eval(expr[0].clone());
+    // View a list as a lazy sequence.
+    env.bind_strict_builtin("seq", |env, expr| match env.eval(expr[0].clone()) {
+        Expr::List(a) => Expr::Seq(Seq::from(a)),
+        Expr::Seq(a) => Expr::Seq(a),
+        a => Expr::error_of(ErrorKind::Type, format!("Invalid expr seq {}", a)),
+    });
+
+    env.bind_strict_builtin("seq?", |env, expr| {
+        let e = env.eval(expr[0].clone());
+        if e.is_err() {
+            return e;
+        }
+        Expr::Bool(matches!(e, Expr::Seq(_)))
+    });
+
+    // Apply a function over and over to a value, lazily: (x (f x) (f (f x)) ...)
+    env.bind_strict_builtin("iterate", |env, expr| {
+        let f = env.eval(expr[0].clone());
+        let x = env.eval(expr[1].clone());
+        Expr::Seq(Seq::iterate(f, x))
+    });
+
+    // Repeat the items of a list forever, lazily.
+    env.bind_strict_builtin("cycle", |env, expr| match env.eval_realized(expr[0].clone()) {
+        Expr::List(a) => Expr::Seq(Seq::cycle(a)),
+        a => Expr::error_of(ErrorKind::Type, format!("Invalid expr cycle {}", a)),
+    });
+
+    // Take the first n items of a list or sequence, as a list.
+    env.bind_strict_builtin("take", |env, expr| {
+        let n = env.eval(expr[0].clone());
+        let a = env.eval(expr[1].clone());
+        match (n, a) {
+            (Expr::Int(n), Expr::List(a)) if n >= 0 => Expr::List(a.slice(..(n as usize).min(a.len()))),
+            (Expr::Int(n), Expr::Seq(a)) if n >= 0 => match a.take(n as usize).realize(env) {
+                Ok(list) => Expr::List(list),
+                Err(err) => err,
+            },
+            (n, a) => Expr::error_of(ErrorKind::Type, format!("Invalid expr take {} {}", n, a)),
+        }
+    });
+
+    // Skip the first n items of a list, or lazily of a sequence.
+    env.bind_strict_builtin("drop", |env, expr| {
+        let n = env.eval(expr[0].clone());
+        let a = env.eval(expr[1].clone());
+        match (n, a) {
+            (Expr::Int(n), Expr::List(a)) if n >= 0 => Expr::List(a.slice((n as usize).min(a.len())..)),
+            (Expr::Int(n), Expr::Seq(a)) if n >= 0 => Expr::Seq(a.skip(n as usize)),
+            (n, a) => Expr::error_of(ErrorKind::Type, format!("Invalid expr drop {} {}", n, a)),
+        }
+    });
+
+    // Take the items of a list until the predicate fails, or lazily of a sequence.
+    env.bind_strict_builtin("take-while", |env, expr| {
+        let f = env.eval(expr[0].clone());
+        let a = env.eval(expr[1].clone());
         match a {
-            Expr::List(mut a) => {
-                a.reverse();
-                Expr::List(a)
+            Expr::List(a) => {
+                let mut list = vec![];
+                for e in a {
+                    let x = env.eval(Expr::List(vec![f.clone(), e.clone()].into()));
+                    if x.is_err() {
+                        return x;
+                    }
+                    if x != Expr::Bool(true) {
+                        break;
+                    }
+                    list.push(e);
+                }
+                Expr::List(list.into())
             }
-            a => return Expr::error(format!("Invalid expr rev {}", a)),
+            Expr::Seq(a) => Expr::Seq(a.take_while(f)),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr take-while {}", a)),
+        }
+    });
+
+    env.bind_strict_builtin("rev", |env, expr| {
+        let a = env.eval_realized(expr[0].clone());
+        match a {
+            Expr::List(a) => Expr::List(a.iter().rev().cloned().collect()),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr rev {}", a)),
         }
     });
 
-    env.bind_builtin("rand", |env, expr| {
+    env.bind_strict_builtin("rand", |env, expr| {
         use rand::Rng;
         let low = env.eval(expr[0].clone());
         let high = env.eval(expr[1].clone());
@@ -855,31 +1052,55 @@ fn make_env() -> Env {
                 let mut rng = rand::thread_rng();
                 Expr::Float(rng.gen_range(low..=high as f64))
             }
-            (a, b) => return Expr::error(format!("Invalid expr rand {} {}", a, b)),
+            (a, b) => Expr::error_of(ErrorKind::Type, format!("Invalid expr rand {} {}", a, b)),
         }
     });
 
-    env.bind_builtin("read", |env, expr| {
+    env.bind_strict_builtin("read", |env, expr| {
         // Read a file
         let path = env.eval(expr[0].clone());
 
         match path {
             Expr::String(path) => {
-                let path = std::path::Path::new(&path);
-                let file = std::fs::File::open(path).unwrap();
+                let file = match std::fs::File::open(&path) {
+                    Ok(file) => file,
+                    Err(e) => return Expr::error_of(ErrorKind::Io, format!("Could not read {path}: {e}")),
+                };
                 let reader = std::io::BufReader::new(file);
                 let mut code = String::new();
                 for line in reader.lines() {
-                    code.push_str(&line.unwrap());
-                    code.push_str("\n");
+                    match line {
+                        Ok(line) => code.push_str(&line),
+                        Err(e) => return Expr::error_of(ErrorKind::Io, format!("Could not read {path}: {e}")),
+                    }
+                    code.push('\n');
                 }
                 Expr::String(code)
             }
-            a => return Expr::error(format!("Invalid expr read {}", a)),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr read {}", a)),
+        }
+    });
+
+    // Read the lines of a file lazily, so files larger than memory can be processed.
+    env.bind_strict_builtin("read-lines", |env, expr| {
+        let path = env.eval(expr[0].clone());
+
+        match path {
+            Expr::String(path) => Expr::Seq(Seq::from_fn(move || {
+                let path = path.clone();
+                match std::fs::File::open(&path) {
+                    Ok(file) => Box::new(std::io::BufReader::new(file).lines().map(move |line| match line {
+                        Ok(line) => Expr::String(line),
+                        Err(e) => Expr::error_of(ErrorKind::Io, format!("Could not read {path}: {e}")),
+                    })),
+                    Err(e) => Box::new(std::iter::once(Expr::error_of(ErrorKind::Io, format!("Could not read {path}: {e}")))),
+                }
+            })),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr read-lines {}", a)),
         }
     });
 
-    env.bind_builtin("write", |env, expr| {
+    env.bind_strict_builtin("write", |env, expr| {
         // Write a file
         use std::io::Write;
         let path = env.eval(expr[0].clone());
@@ -888,16 +1109,16 @@ fn make_env() -> Env {
 
         match (path, content) {
             (Expr::String(path), Expr::String(content)) => {
-                let path = std::path::Path::new(&path);
-                let mut file = std::fs::File::create(path).unwrap();
-                file.write_all(content.as_bytes()).unwrap();
-                Expr::None
+                match std::fs::File::create(&path).and_then(|mut file| file.write_all(content.as_bytes())) {
+                    Ok(()) => Expr::None,
+                    Err(e) => Expr::error_of(ErrorKind::Io, format!("Could not write {path}: {e}")),
+                }
             }
-            (a, b) => return Expr::error(format!("Invalid expr write {} {}", a, b)),
+            (a, b) => Expr::error_of(ErrorKind::Type, format!("Invalid expr write {} {}", a, b)),
         }
     });
 
-    env.bind_builtin("shell", |env, expr| {
+    env.bind_strict_builtin("shell", |env, expr| {
         // Run a shell command
         let cmd = env.eval(expr[0].clone());
 
@@ -910,23 +1131,66 @@ fn make_env() -> Env {
                     .expect("failed to execute process");
                 let stdout = String::from_utf8(output.stdout).unwrap();
                 let stderr = String::from_utf8(output.stderr).unwrap();
-                Expr::List(vec![Expr::String(stdout), Expr::String(stderr)])
+                Expr::List(vec![Expr::String(stdout), Expr::String(stderr)].into())
             }
-            a => return Expr::error(format!("Invalid expr shell {}", a)),
+            a => Expr::error_of(ErrorKind::Type, format!("Invalid expr shell {}", a)),
         }
     });
 
+    env.eval_source("<prelude>", PRELUDE)
+        .expect("Failed to parse prelude");
+    env.alias("thread-first", "->");
     env
 }
 
+/// The forms of the standard library that are defined in lisp.
+const PRELUDE: &str = r#"{
+    (defmacro when (cond &rest body)
+        `(if ,cond (do ,@body)))
+
+    (defmacro unless (cond &rest body)
+        `(if ,cond nil (do ,@body)))
+
+    ;; Thread a value through a series of calls, as the first argument of each.
+    ;; (thread-first x (f a) g) becomes (g (f x a)). This is aliased to `->`,
+    ;; which would be read as infix subtraction if it followed `defmacro` here.
+    (defmacro thread-first (x &rest forms)
+        (if (= (len forms) 0)
+            x
+            (let ((form (head forms)))
+                `(thread-first
+                    ,(if (list? form) `(,(head form) ,x ,@(tail form)) `(,form ,x))
+                    ,@(tail forms)))))
+}"#;
+
+/// Print the result of evaluating a program.
+///
+/// Errors are printed to stderr along with their trace.
+fn print_result(result: &Expr) {
+    match result {
+        Expr::Err(e) => eprintln!("Error: {e}"),
+        Expr::None => {}
+        result => println!("{result}"),
+    }
+}
+
 fn main() {
     env_logger::init();
 
 
-    let mut env = make_env();
+    // Programs define things in the `user` namespace, so they can't replace the builtins.
+    let mut env = make_env().namespace("user");
     let args = Program::parse();
+    env.set_compiler_enabled(args.compile);
+    // Import modules relative to the program's file, or the working directory.
+    let root = args
+        .program_name
+        .as_ref()
+        .and_then(|name| std::path::Path::new(name).parent())
+        .unwrap_or(std::path::Path::new("."));
+    env.set_module_resolver(FileResolver::new(root));
     // Either open the file or use the program string.
-    let mut program = match args.program {
+    let program = match args.program {
         Some(ref program) => program.clone(),
         None => {
             match args.program_name {
@@ -938,10 +1202,16 @@ fn main() {
                     if rl.load_history("history.txt").is_err() {
                         println!("No previous history.");
                     }
+                    // Ctrl-C at the prompt is handled by the editor, but while
+                    // evaluating, it interrupts the evaluation instead of exiting.
+                    let interrupt = env.interrupt_handle();
+                    ctrlc::set_handler(move || interrupt.interrupt())
+                        .expect("Failed to set Ctrl-C handler");
                     let mut program = String::new();
                     loop {
                         let readline =
                             rl.readline(if program.is_empty() { ">>> " } else { "... " });
+                        env.interrupt_handle().reset();
                         match readline {
                             Ok(line) => {
                                 if let Err(e) = rl.add_history_entry(line.as_str()) {
@@ -950,9 +1220,9 @@ fn main() {
                                 program.push_str(&line);
                                 match Expr::parse(&program) {
                                     Ok(e) => {
-                                        let result = env.eval(e);
-                                        if result != Expr::None {
-                                            println!("{}", result);
+                                        let result = env.eval_program(e);
+                                        print_result(&result);
+                                        if result != Expr::None && !result.is_err() {
                                             env.bind(Expr::symbol("ans"), result);
                                         }
 
@@ -961,11 +1231,11 @@ fn main() {
                                     Err(e) => {
                                         // Try wrapping the input in parens and parsing it first
                                         if let Ok(e) = Expr::parse(&format!("({})", program)) {
-                                            let result = env.eval(e);
-                                            if result != Expr::None {
-                                                println!("{}", result);
+                                            let result = env.eval_program(e);
+                                            print_result(&result);
+                                            if !result.is_err() {
+                                                env.bind(Expr::symbol("ans"), result);
                                             }
-                                            env.bind(Expr::symbol("ans"), result);
 
                                             program = String::new();
                                             continue;
@@ -975,7 +1245,7 @@ fn main() {
                                             eprintln!("Error in\n`{program}`\n -> {}", e);
                                             program = String::new();
                                         } else {
-                                            program.push_str("\n");
+                                            program.push('\n');
                                         }
                                     }
                                 }
@@ -1000,14 +1270,14 @@ fn main() {
             }
         }
     };
-    
-    match Expr::parse(&mut program) {
-        Ok(e) => {
-            let result = env.eval(e);
-            if result != Expr::None {
-                println!("{}", result);
-            }
-        }
+
+    // Name the source after the file, so errors report where they happened.
+    let source = match (&args.program, &args.program_name) {
+        (None, Some(program_name)) => program_name.as_str(),
+        _ => "<command>",
+    };
+    match env.eval_source(source, &program) {
+        Ok(result) => print_result(&result),
         Err(e) => {
             eprintln!("Parse error: {}", e);
         }
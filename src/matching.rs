@@ -0,0 +1,205 @@
+//! # Pattern Matching
+//!
+//! The `match` form compares a value against a series of patterns, and evaluates
+//! the body of the first clause whose pattern matches, with the variables in the
+//! pattern bound to the matching parts of the value.
+//!
+//! ```lisp
+//! (match request
+//!     ([method "GET" path p] (serve p))
+//!     ([method "POST" body (? map b)] :when (valid? b) (store b))
+//!     ((cmd &rest args) (run cmd args))
+//!     (_ (raise "unsupported request")))
+//! ```
+//!
+//! Patterns extend the [destructuring patterns](crate::Env::bind_pattern) of
+//! function parameters:
+//!
+//! - `_` matches anything, without binding it.
+//! - A symbol matches anything, and binds it to the symbol.
+//! - Numbers, strings, booleans and `nil` match themselves, and a quoted
+//!   expression, like `'ok`, matches the expression.
+//! - A list of patterns matches a list with the same number of elements. The last
+//!   pattern can be preceded by `&rest` to match the remaining elements instead.
+//! - Names in brackets or braces, like `[name age]` or `{name age}`, match a tree,
+//!   map or record with a value for each name, and bind the names to them.
+//! - A map of keys to patterns, like `#[name n age a]`, or a tree with a pattern
+//!   that is not a plain name, like `[method "GET" path p]`, matches a tree or map
+//!   with a value for each key, that matches the key's pattern. A symbol key also
+//!   matches a string key with the same name, for data imported with serde, and
+//!   the field of a record with that name.
+//! - `(? type pattern)` matches a value of the given type that also matches the
+//!   pattern, which can be left out. The type is one of `nil`, `int`, `float`,
+//!   `number`, `string`, `symbol`, `bool`, `list`, `tree`, `map`, `function`,
+//!   `seq`, `box` or `record`. Anything else is evaluated as a predicate, which must return
+//!   `true` for the value.
+//!
+//! A clause can have a guard after its pattern, written `:when condition`. The clause
+//! only matches if the condition, evaluated with the pattern's variables, is `true`.
+use super::{
+    params::{lookup_key, pattern_entries},
+    Env, ErrorKind, EvalError, Expr,
+};
+
+/// Does a value have the type named in a type pattern?
+///
+/// Returns `None` if the name is not a type, so it should be a predicate.
+pub(crate) fn has_type(name: &str, value: &Expr) -> Option<bool> {
+    Some(match name {
+        "nil" => matches!(value, Expr::None),
+        "int" => matches!(value, Expr::Int(_)),
+        "float" => matches!(value, Expr::Float(_)),
+        "number" => matches!(value, Expr::Int(_) | Expr::Float(_)),
+        "string" => matches!(value, Expr::String(_)),
+        "symbol" => matches!(value, Expr::Symbol(_)),
+        "bool" => matches!(value, Expr::Bool(_)),
+        "list" => matches!(value, Expr::List(_)),
+        "tree" => matches!(value, Expr::Tree(_)),
+        "map" => matches!(value, Expr::Map(_)),
+        "function" => matches!(value, Expr::Function(..) | Expr::Builtin(_)),
+        "seq" => matches!(value, Expr::Seq(_)),
+        "box" => matches!(value, Expr::Cell(_)),
+        "record" => matches!(value, Expr::Record(_)),
+        _ => return None,
+    })
+}
+
+/// The error for a pattern that is not well formed.
+fn invalid(pattern: &Expr, reason: &str) -> EvalError {
+    EvalError::new(ErrorKind::Type, format!("Invalid pattern {pattern}, {reason}"))
+}
+
+impl Env {
+    /// Match a value against a pattern, binding the pattern's variables in this scope.
+    ///
+    /// Returns whether the value matched. If it didn't, some of the variables may
+    /// already have been bound, so this should be called in a scope of its own.
+    /// An error is returned if the pattern is not well formed, or a predicate fails.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// let pattern = Expr::parse(r#"[kind "circle" radius (? number r)]"#).unwrap();
+    /// let circle = Expr::parse(r#"["kind" "circle" "radius" 2.5]"#).unwrap();
+    /// assert!(env.match_pattern(&pattern, &circle).unwrap());
+    /// assert_eq!(env.get(&Expr::symbol("r")), Some(Expr::Float(2.5)));
+    ///
+    /// let square = Expr::parse(r#"[kind "square" side 2]"#).unwrap();
+    /// assert!(!env.new_scope().match_pattern(&pattern, &square).unwrap());
+    /// ```
+    pub fn match_pattern(&mut self, pattern: &Expr, value: &Expr) -> Result<bool, EvalError> {
+        match pattern {
+            Expr::Symbol(s) if s.name() == "_" => Ok(true),
+            Expr::Symbol(s) if s.name() == "&rest" => Err(invalid(pattern, "&rest must be in a list")),
+            Expr::Symbol(_) => {
+                self.bind(pattern.clone(), value.clone());
+                Ok(true)
+            }
+            Expr::Quote(expected) => Ok(**expected == *value),
+            Expr::None | Expr::Int(_) | Expr::Float(_) | Expr::String(_) | Expr::Bool(_) => Ok(pattern == value),
+
+            Expr::List(patterns) if patterns.first() == Some(&Expr::symbol("?")) => {
+                let (ty, inner) = match patterns.len() {
+                    2 => (&patterns[1], None),
+                    3 => (&patterns[1], Some(&patterns[2])),
+                    _ => return Err(invalid(pattern, "expected (? type pattern)")),
+                };
+                let is_type = match ty {
+                    Expr::Symbol(s) => has_type(s.name(), value),
+                    _ => None,
+                };
+                let is_type = match is_type {
+                    Some(is_type) => is_type,
+                    // Otherwise, the type is a predicate.
+                    None => {
+                        let predicate = self.eval(ty.clone());
+                        match self.eval(predicate.apply(&[value.quote()])) {
+                            Expr::Err(e) => {
+                                self.take_raised();
+                                return Err(*e);
+                            }
+                            result => result == Expr::Bool(true),
+                        }
+                    }
+                };
+                match inner {
+                    Some(inner) if is_type => self.match_pattern(inner, value),
+                    _ => Ok(is_type),
+                }
+            }
+            Expr::List(patterns) => {
+                let rest_at = patterns.iter().position(|p| *p == Expr::symbol("&rest"));
+                if rest_at.is_some_and(|i| i + 2 != patterns.len()) {
+                    return Err(invalid(pattern, "expected one pattern after &rest"));
+                }
+                let fixed = rest_at.unwrap_or(patterns.len());
+                let values = match value {
+                    Expr::List(values) if values.len() == fixed => values,
+                    Expr::List(values) if rest_at.is_some() && values.len() > fixed => values,
+                    _ => return Ok(false),
+                };
+                for (pattern, value) in patterns.iter().take(fixed).zip(values.iter()) {
+                    if !self.match_pattern(pattern, value)? {
+                        return Ok(false);
+                    }
+                }
+                match rest_at {
+                    Some(i) => self.match_pattern(&patterns[i + 1], &Expr::List(values.slice(fixed..))),
+                    None => Ok(true),
+                }
+            }
+            Expr::Tree(_) | Expr::Map(_) | Expr::Many(_) => {
+                for (key, pattern) in pattern_entries(pattern)? {
+                    match lookup_key(value, &key) {
+                        Some(Some(found)) if self.match_pattern(&pattern, found)? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                Ok(true)
+            }
+            pattern => Err(invalid(pattern, "expected a literal, symbol, list, tree, map or {names}")),
+        }
+    }
+
+    /// Evaluate the first clause of a `match` form whose pattern matches the value.
+    pub(crate) fn eval_match(&mut self, value: Expr, clauses: &[Expr]) -> Expr {
+        for clause in clauses {
+            let (pattern, rest) = match clause {
+                Expr::List(clause) if !clause.is_empty() => (&clause[0], clause.slice(1..)),
+                clause => return Expr::error_of(ErrorKind::Type, format!("Invalid match clause {clause}")),
+            };
+            let (guard, body) = match rest.first() {
+                Some(keyword) if *keyword == Expr::symbol(":when") => match rest.get(1) {
+                    Some(guard) => (Some(guard.clone()), rest.slice(2..)),
+                    None => return Expr::error_of(ErrorKind::Type, format!("Expected a guard after :when in {clause}")),
+                },
+                _ => (None, rest),
+            };
+
+            let mut scope = self.new_scope();
+            match scope.match_pattern(pattern, &value) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => return e.into(),
+            }
+            if let Some(guard) = guard {
+                match scope.eval(guard) {
+                    Expr::Bool(true) => {}
+                    err @ Expr::Err(_) => return err,
+                    _ => continue,
+                }
+            }
+
+            let mut result = Expr::None;
+            for e in body {
+                result = scope.eval(e);
+                if result.is_err() {
+                    break;
+                }
+            }
+            return result;
+        }
+        Expr::error_of(ErrorKind::NoMatch, format!("No pattern matched {value}"))
+    }
+}
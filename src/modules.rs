@@ -0,0 +1,368 @@
+//! # Modules
+//!
+//! A module is a file of lisp code that is evaluated in a scope of its own, and
+//! exports some of its definitions to the programs that import it. By default a
+//! module exports everything it defines at its top level, unless it lists its
+//! exports with `(export name ...)`.
+//!
+//! ```lisp
+//! (import "lib/strings.lisp")             ; binds strings/split, strings/join, ...
+//! (import strings :as s)                  ; binds s/split, s/join, ...
+//! (import strings :only (split join))     ; binds split and join
+//! (define strings (require strings))      ; a tree of the exports, binding nothing
+//! ```
+//!
+//! Modules are found by the environment's [`ModuleResolver`], which reads them from
+//! the filesystem with a [`FileResolver`], or from memory with a [`MemoryResolver`].
+//! Embedders can implement the trait to serve modules from anywhere else.
+//!
+//! Each module is only evaluated once per environment: importing it again reuses its
+//! exports. A module that imports itself, directly or through other modules, is an error.
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use super::{Env, ErrorKind, Expr, OrdMap, Symbol};
+
+/// The code of a module, found by a [`ModuleResolver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleSource {
+    /// The canonical name of the module, like its absolute path.
+    ///
+    /// Modules with the same id are only evaluated once, and errors
+    /// raised by the module report their location in this source.
+    pub id: String,
+    /// The source code of the module.
+    pub code: String,
+}
+
+/// Finds the code of the modules imported by a program.
+///
+/// ```rust
+/// use sage_lisp::{Env, Expr, ModuleResolver, ModuleSource};
+///
+/// /// Serves every module from a single string, for the sake of the example.
+/// struct Constant;
+///
+/// impl ModuleResolver for Constant {
+///     fn resolve(&self, name: &str, _importer: Option<&str>) -> Result<ModuleSource, String> {
+///         Ok(ModuleSource {
+///             id: name.to_string(),
+///             code: format!("(defmacro name () \"{name}\")"),
+///         })
+///     }
+/// }
+///
+/// let mut env = Env::new();
+/// env.bind_core_forms();
+/// env.set_module_resolver(Constant);
+/// let result = env.eval_str("(do (import greeting) (greeting/name))").unwrap();
+/// assert_eq!(result, Expr::from("greeting"));
+/// ```
+pub trait ModuleResolver: Send + Sync {
+    /// Find the module imported with the given name.
+    ///
+    /// The `importer` is the id of the module doing the import, so that names can be
+    /// resolved relative to it, or `None` if the main program is importing.
+    fn resolve(&self, name: &str, importer: Option<&str>) -> Result<ModuleSource, String>;
+}
+
+/// Resolves modules to files, with the `.lisp` extension if the name has none.
+///
+/// Names starting with `.` are relative to the directory of the importing module,
+/// and other names are relative to the root directory of the resolver.
+#[derive(Debug, Clone)]
+pub struct FileResolver {
+    /// The directory that names are relative to.
+    root: PathBuf,
+}
+
+impl FileResolver {
+    /// Create a resolver for the files under a root directory.
+    #[inline]
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, name: &str, importer: Option<&str>) -> Result<ModuleSource, String> {
+        let mut file = PathBuf::from(name);
+        if file.extension().is_none() {
+            file.set_extension("lisp");
+        }
+        let dir = match importer {
+            Some(importer) if name.starts_with('.') => Path::new(importer).parent().unwrap_or(&self.root),
+            _ => &self.root,
+        };
+        let path = dir.join(file);
+        let code = std::fs::read_to_string(&path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        // The same file can be imported through different relative paths.
+        let path = path.canonicalize().unwrap_or(path);
+        Ok(ModuleSource {
+            id: path.display().to_string(),
+            code,
+        })
+    }
+}
+
+/// Resolves modules to code registered with the resolver, by their names.
+///
+/// A name with the `.lisp` extension also finds the module registered without it.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryResolver {
+    /// The code of each module, by its name.
+    modules: HashMap<String, String>,
+}
+
+impl MemoryResolver {
+    /// Create a resolver with no modules.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the code of a module under a name.
+    #[inline]
+    pub fn with_module(mut self, name: impl ToString, code: impl ToString) -> Self {
+        self.modules.insert(name.to_string(), code.to_string());
+        self
+    }
+}
+
+impl ModuleResolver for MemoryResolver {
+    fn resolve(&self, name: &str, _importer: Option<&str>) -> Result<ModuleSource, String> {
+        [Some(name), name.strip_suffix(".lisp")]
+            .into_iter()
+            .flatten()
+            .find_map(|name| {
+                self.modules.get(name).map(|code| ModuleSource {
+                    id: name.to_string(),
+                    code: code.clone(),
+                })
+            })
+            .ok_or_else(|| format!("There is no module named {name}"))
+    }
+}
+
+/// The modules of an environment, shared by all of its scopes.
+#[derive(Default)]
+pub(crate) struct Modules {
+    /// The resolver for finding imported modules, if one is set.
+    resolver: RwLock<Option<Arc<dyn ModuleResolver>>>,
+    /// The exports of each module evaluated so far, by their ids.
+    loaded: RwLock<HashMap<String, Expr>>,
+}
+
+/// A module that is being evaluated.
+struct Loading {
+    /// The address of the context of the environment that is importing it.
+    context: usize,
+    /// The id of the module.
+    id: String,
+    /// The names listed by the module's `export` forms, if it has any.
+    exports: Option<Vec<Expr>>,
+}
+
+thread_local! {
+    /// The modules being evaluated on this thread, innermost last.
+    static LOADING: RefCell<Vec<Loading>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Get the prefix of the qualified names of a module's exports, from the name it was imported with.
+///
+/// This is the name of the file, without its directory and extension: `lib/strings.lisp` becomes `strings`.
+fn default_prefix(name: &str) -> &str {
+    let name = name.rsplit('/').next().unwrap_or(name);
+    name.strip_suffix(".lisp").unwrap_or(name)
+}
+
+/// Get the name a module was imported by, which can be a string or a symbol.
+fn module_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::String(name) => Some(name),
+        Expr::Symbol(name) => Some(name.name()),
+        _ => None,
+    }
+}
+
+impl Env {
+    /// Set the resolver that finds the modules imported by programs in this environment.
+    ///
+    /// Until a resolver is set, importing a module is an error, so that programs
+    /// can't read files unless the embedder allows it.
+    #[inline]
+    pub fn set_module_resolver(&mut self, resolver: impl ModuleResolver + 'static) {
+        *self.context.modules.resolver.write().unwrap() = Some(Arc::new(resolver));
+    }
+
+    /// Evaluate a module, if it hasn't been already, and get a tree of its exports.
+    ///
+    /// The module is evaluated in a new scope of the outermost scope of the environment,
+    /// so it sees the builtins and global definitions, but none of the importer's locals.
+    /// An error is returned if the module can't be found or parsed, if evaluating it raises
+    /// an error, or if it imports itself.
+    pub fn require(&mut self, name: &str) -> Expr {
+        let context = Arc::as_ptr(&self.context) as *const () as usize;
+        let importer = LOADING.with(|loading| {
+            loading
+                .borrow()
+                .iter()
+                .rev()
+                .find(|module| module.context == context)
+                .map(|module| module.id.clone())
+        });
+        let Some(resolver) = self.context.modules.resolver.read().unwrap().clone() else {
+            return Expr::error_of(ErrorKind::Import, format!("Cannot import {name}, no module resolver is set"));
+        };
+        let source = match resolver.resolve(name, importer.as_deref()) {
+            Ok(source) => source,
+            Err(e) => return Expr::error_of(ErrorKind::Import, e),
+        };
+        if let Some(exports) = self.context.modules.loaded.read().unwrap().get(&source.id) {
+            return exports.clone();
+        }
+
+        let cycle = LOADING.with(|loading| {
+            let loading = loading.borrow();
+            let modules: Vec<&str> = loading
+                .iter()
+                .filter(|module| module.context == context)
+                .map(|module| module.id.as_str())
+                .collect();
+            let start = modules.iter().position(|id| *id == source.id)?;
+            Some(modules[start..].join(" -> "))
+        });
+        if let Some(cycle) = cycle {
+            return Expr::error_of(ErrorKind::Import, format!("Cyclic import: {cycle} -> {}", source.id));
+        }
+
+        /// Stops loading the module when dropped, even if evaluating it panics.
+        struct Finish;
+        impl Drop for Finish {
+            fn drop(&mut self) {
+                LOADING.with(|loading| loading.borrow_mut().pop());
+            }
+        }
+
+        let mut scope = self.core().new_scope();
+
+        LOADING.with(|loading| {
+            loading.borrow_mut().push(Loading {
+                context,
+                id: source.id.clone(),
+                exports: None,
+            })
+        });
+        let finish = Finish;
+        let result = scope.eval_source(&source.id, &source.code);
+        let listed = LOADING.with(|loading| loading.borrow_mut().last_mut().and_then(|module| module.exports.take()));
+        drop(finish);
+
+        match result {
+            Ok(result) if result.is_err() => return result,
+            Ok(_) => {}
+            Err(e) => return Expr::error_of(ErrorKind::Import, format!("Could not parse {}: {e}", source.id)),
+        }
+
+        let exports = match listed {
+            Some(names) => {
+                let mut exports = OrdMap::new();
+                for name in names {
+                    match scope.scope.lookup(&name) {
+                        Some(value) => exports.insert(name, value),
+                        None => {
+                            return Expr::error_of(
+                                ErrorKind::Import,
+                                format!("{} exports {name}, which it does not define", source.id),
+                            )
+                        }
+                    };
+                }
+                exports
+            }
+            None => scope
+                .scope
+                .bindings
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(name, _)| matches!(name, Expr::Symbol(_)))
+                .map(|(name, value)| (name.clone(), scope.scope.attach(value.clone())))
+                .collect(),
+        };
+        let exports = Expr::Tree(exports);
+        self.context
+            .modules
+            .loaded
+            .write()
+            .unwrap()
+            .insert(source.id, exports.clone());
+        exports
+    }
+
+    /// Evaluate an `(import name options)` form, binding the module's exports in this scope.
+    pub(crate) fn import(&mut self, args: &[Expr]) -> Expr {
+        let Some(name) = args.first().and_then(module_name) else {
+            return Expr::error_of(ErrorKind::Type, "import expects the name of a module");
+        };
+        let (mut only, mut prefix) = (None, None);
+        for option in args[1..].chunks(2) {
+            match option {
+                [Expr::Symbol(key), Expr::List(names)] if key.name() == ":only" => only = Some(names),
+                [Expr::Symbol(key), Expr::Symbol(alias)] if key.name() == ":as" => prefix = Some(alias.name()),
+                _ => {
+                    let option = Expr::List(option.to_vec().into());
+                    return Expr::error_of(ErrorKind::Type, format!("Invalid import option {option}"));
+                }
+            }
+        }
+
+        let exports = match self.require(name) {
+            Expr::Tree(exports) => exports,
+            error => return error,
+        };
+        if let Some(names) = only {
+            for name in names {
+                match exports.get(name) {
+                    Some(value) => self.bind(name.clone(), value.clone()),
+                    None => {
+                        return Expr::error_of(ErrorKind::Import, format!("The module {} does not export {name}", args[0]))
+                    }
+                }
+            }
+        }
+        // Every export is bound with a prefix, unless only some were asked for without one.
+        if only.is_none() || prefix.is_some() {
+            let prefix = prefix.unwrap_or_else(|| default_prefix(name));
+            for (key, value) in exports.iter() {
+                if let Expr::Symbol(key) = key {
+                    let qualified = Symbol::new(&format!("{prefix}/{}", key.name()));
+                    self.bind(Expr::Symbol(qualified), value.clone());
+                }
+            }
+        }
+        Expr::None
+    }
+
+    /// Evaluate an `(export name ...)` form, listing exports of the module being evaluated.
+    pub(crate) fn export(&mut self, names: Vec<Expr>) -> Expr {
+        if let Some(name) = names.iter().find(|name| !matches!(name, Expr::Symbol(_))) {
+            return Expr::error_of(ErrorKind::Type, format!("export expects the names of definitions, got {name}"));
+        }
+        let context = Arc::as_ptr(&self.context) as *const () as usize;
+        LOADING.with(|loading| {
+            let mut loading = loading.borrow_mut();
+            match loading.iter_mut().rev().find(|module| module.context == context) {
+                Some(module) => {
+                    module.exports.get_or_insert_with(Vec::new).extend(names);
+                    Expr::None
+                }
+                None => Expr::error_of(ErrorKind::Import, "export can only be used in a module"),
+            }
+        })
+    }
+}
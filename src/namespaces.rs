@@ -0,0 +1,203 @@
+//! # Namespaces
+//!
+//! A namespace holds the top-level definitions of a program, apart from the
+//! definitions of other programs and from the builtins. Defining `filter` in a
+//! namespace shadows the builtin there, but doesn't replace it anywhere else.
+//!
+//! ```lisp
+//! (ns geometry)
+//! (defun area (w h) (* w h))
+//!
+//! (ns user)
+//! (refer geometry)
+//! (area 2 3)              ; found in the referred namespace
+//! (geometry/area 2 3)     ; a qualified symbol names the namespace itself
+//! (core/filter even? xs)  ; the builtins are in the `core` namespace
+//! ```
+//!
+//! A program switches namespaces with `(ns name)` between its top-level forms,
+//! creating the namespace the first time. Unqualified symbols are looked up in
+//! the lexical scopes of the code, then in its namespace, then in the namespaces
+//! it refers to, in the order they were referred, and finally in the core, which
+//! is the outermost scope of the environment.
+use std::sync::{Arc, RwLock};
+
+use super::{Env, ErrorKind, EvalError, Expr, Scope, Symbol};
+
+/// The name of the namespace for the outermost scope of an environment.
+const CORE: &str = "core";
+
+/// The namespace that a scope holds the top-level bindings of.
+pub(crate) struct Namespace {
+    /// The name of the namespace.
+    name: Symbol,
+    /// The scopes of the namespaces it refers to, in the order they were referred.
+    refers: RwLock<Vec<Arc<Scope>>>,
+}
+
+impl Namespace {
+    /// Get the value bound to a symbol in one of the namespaces this one refers to.
+    #[inline]
+    pub(crate) fn lookup_referred(&self, symbol: &Expr) -> Option<Expr> {
+        self.refers.read().unwrap().iter().find_map(|scope| scope.lookup(symbol))
+    }
+}
+
+/// Is the expression a top-level `(ns name)` form?
+fn is_ns_form(expr: &Expr) -> bool {
+    matches!(expr, Expr::List(l) if l.first() == Some(&Expr::symbol("ns")))
+}
+
+/// Get the top-level forms of a program, if any of them switch namespaces.
+pub(crate) fn split_program(expr: &Expr) -> Option<Vec<Expr>> {
+    let forms: Vec<Expr> = match expr {
+        Expr::Many(forms) => forms.to_vec(),
+        Expr::List(forms) if forms.first() == Some(&Expr::symbol("do")) => forms.iter().skip(1).cloned().collect(),
+        form if is_ns_form(form) => return Some(vec![form.clone()]),
+        _ => return None,
+    };
+    forms.iter().any(is_ns_form).then_some(forms)
+}
+
+impl Env {
+    /// Get the outermost scope of the environment, which holds the `core` namespace.
+    pub(crate) fn core(&self) -> Env {
+        let mut scope = self.scope.clone();
+        while let Some(parent) = scope.parent.clone() {
+            scope = parent;
+        }
+        Env {
+            scope,
+            context: self.context.clone(),
+            raised: None,
+        }
+    }
+
+    /// Get the top level of a namespace, creating the namespace if it doesn't exist.
+    ///
+    /// Definitions made through the returned environment, including builtins bound
+    /// from Rust, go into the namespace. Other code can use them with qualified symbols,
+    /// like `str/split`, or by referring to the namespace. The namespace named `core`
+    /// is the outermost scope of the environment, where builtins are usually bound.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// env.bind_core_forms();
+    /// env.namespace("str").bind_strict_builtin("len", |env, args| match env.eval(args[0].clone()) {
+    ///     Expr::String(s) => Expr::Int(s.len() as i64),
+    ///     _ => Expr::error("expected a string"),
+    /// });
+    /// assert_eq!(env.eval_str(r#"(str/len "hello")"#).unwrap(), Expr::Int(5));
+    ///
+    /// let mut user = env.namespace("user");
+    /// user.refer("str").unwrap();
+    /// assert_eq!(user.eval_str(r#"(len "hi")"#).unwrap(), Expr::Int(2));
+    /// ```
+    pub fn namespace(&self, name: &str) -> Env {
+        let core = self.core();
+        if name == CORE {
+            return core;
+        }
+        let name = Symbol::new(name);
+        let scope = self
+            .context
+            .namespaces
+            .write()
+            .unwrap()
+            .entry(name.clone())
+            .or_insert_with(|| {
+                Arc::new(Scope {
+                    parent: Some(core.scope),
+                    namespace: Some(Box::new(Namespace {
+                        name,
+                        refers: RwLock::default(),
+                    })),
+                    ..Scope::default()
+                })
+            })
+            .clone();
+        Env {
+            scope,
+            context: self.context.clone(),
+            raised: None,
+        }
+    }
+
+    /// Get the name of the namespace that code in this environment is defined in.
+    ///
+    /// Returns `None` for code in the `core` namespace.
+    pub fn current_namespace(&self) -> Option<Symbol> {
+        self.namespace_scope()
+            .and_then(|scope| scope.namespace.as_ref().map(|ns| ns.name.clone()))
+    }
+
+    /// Refer the current namespace to another one, so its definitions can be used unqualified.
+    ///
+    /// Returns an error if this environment is not in a namespace, or the other namespace
+    /// doesn't exist yet.
+    pub fn refer(&mut self, name: &str) -> Result<(), EvalError> {
+        let Some(scope) = self.namespace_scope() else {
+            return Err(EvalError::new(ErrorKind::Type, "refer can only be used in a namespace"));
+        };
+        let Some(referred) = self.context.namespaces.read().unwrap().get(&Symbol::new(name)).cloned() else {
+            return Err(EvalError::new(ErrorKind::Unbound, format!("There is no namespace named {name}")));
+        };
+        let ns = scope.namespace.as_ref().unwrap();
+        let mut refers = ns.refers.write().unwrap();
+        if !Arc::ptr_eq(&referred, scope) && !refers.iter().any(|r| Arc::ptr_eq(r, &referred)) {
+            refers.push(referred);
+        }
+        Ok(())
+    }
+
+    /// Get the scope of the namespace that this environment is in, if any.
+    fn namespace_scope(&self) -> Option<&Arc<Scope>> {
+        let mut scope = Some(&self.scope);
+        while let Some(s) = scope {
+            if s.namespace.is_some() {
+                return Some(s);
+            }
+            scope = s.parent.as_ref();
+        }
+        None
+    }
+
+    /// Look up a qualified symbol, like `str/split`, in the namespace it names.
+    pub(crate) fn get_qualified(&self, symbol: &Expr) -> Option<Expr> {
+        let Expr::Symbol(symbol) = symbol else {
+            return None;
+        };
+        let (namespace, name) = symbol.qualified()?;
+        let scope = if namespace == CORE {
+            self.core().scope
+        } else {
+            self.context.namespaces.read().unwrap().get(&Symbol::new(namespace))?.clone()
+        };
+        scope.lookup(&Expr::symbol(name))
+    }
+
+    /// Evaluate the top-level forms of a program, switching namespaces at each `(ns name)`.
+    ///
+    /// The environment stays in the last namespace, so the next program continues there.
+    pub(crate) fn eval_namespaced(&mut self, forms: Vec<Expr>) -> Expr {
+        let mut result = Expr::None;
+        for form in forms {
+            result = match &form {
+                Expr::List(l) if is_ns_form(&form) => match l.iter().skip(1).collect::<Vec<_>>()[..] {
+                    [Expr::Symbol(name)] => {
+                        *self = self.namespace(name.name());
+                        Expr::None
+                    }
+                    _ => Expr::error_of(ErrorKind::Type, format!("ns expects the name of a namespace, got {form}")),
+                },
+                _ => self.eval_program(form),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,413 @@
+//! # Parameter Lists
+//!
+//! Functions defined in lisp take a list of parameters, which can use the
+//! lambda list keywords below to accept a varying number of arguments.
+//!
+//! ```lisp
+//! (defun f (a b &optional (c 10) &rest more &key verbose) ...)
+//! ```
+//!
+//! - Parameters before any keyword are required.
+//! - `&optional` parameters are filled by position if there are enough
+//!   arguments. They are written as `name`, or `(name default)`. Without a
+//!   default, a missing optional parameter is bound to `None`.
+//! - `&rest name` binds the remaining arguments as a list.
+//! - `&key` parameters are passed by name at the call site, as `:name value`,
+//!   after all the positional arguments. They are written like `&optional`
+//!   parameters. If there is also a `&rest` parameter, it receives the
+//!   keyword arguments too.
+//!
+//! Defaults are evaluated in the scope of the call, so they can refer to the
+//! parameters before them.
+//!
+//! ## Destructuring
+//!
+//! Anywhere a parameter name is expected, as well as in `let` and `define`,
+//! a pattern can be used to unpack the value instead:
+//!
+//! - A symbol binds the whole value.
+//! - A list of patterns, like `(a b)`, matches a list with the same number of
+//!   elements. The last pattern can be preceded by `&rest` to bind the remaining
+//!   elements instead, like `(head &rest tail)`.
+//! - Names in brackets or braces, like `[name age]` or `{name age}`, look up each
+//!   name as a key in a tree, map or record, and bind the name to its value. A symbol
+//!   key also matches a string key with the same name, so these patterns work on
+//!   data imported with serde, and the field of a record with that name.
+//! - A map of keys to patterns, like `#[name n age (a &rest _)]`, looks up each key
+//!   and matches its value against the pattern, so the names can differ from the keys.
+//!   A tree works the same way, like `[name (first last)]`, as long as one of its
+//!   patterns is not a plain name.
+//!
+//! Patterns can be nested, like `((k v) &rest more)`.
+use super::{Env, ErrorKind, EvalError, Expr, Symbol};
+
+/// A parameter with an optional default expression.
+type Param<'a> = (&'a Expr, Option<&'a Expr>);
+
+/// A parameter list split into its sections.
+#[derive(Default)]
+struct ParamList<'a> {
+    required: Vec<&'a Expr>,
+    optional: Vec<Param<'a>>,
+    rest: Option<&'a Expr>,
+    key: Vec<Param<'a>>,
+}
+
+/// The sections of a parameter list, in the order they must appear.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Section {
+    Required,
+    Optional,
+    Rest,
+    Key,
+}
+
+/// The error for a value that does not have the shape of a pattern.
+fn mismatch(pattern: &Expr, value: &Expr, expected: &str) -> EvalError {
+    EvalError::new(
+        ErrorKind::Type,
+        format!("Cannot destructure {value} with pattern {pattern}, expected {expected}"),
+    )
+}
+
+/// Get the keys of a tree, map or `{name ...}` pattern, with the pattern for each key's value.
+///
+/// Names in braces, and a tree of only symbols, like `[name age]`, bind each name to
+/// the value of the key with the same name. Other trees and maps pair keys with patterns.
+pub(crate) fn pattern_entries(pattern: &Expr) -> Result<Vec<(Expr, Expr)>, EvalError> {
+    let names = |names: Vec<&Expr>| {
+        names
+            .into_iter()
+            .map(|name| match name {
+                Expr::Symbol(_) => Ok((name.clone(), name.clone())),
+                _ => Err(EvalError::new(
+                    ErrorKind::Type,
+                    format!("Invalid pattern {pattern}, expected only names in braces"),
+                )),
+            })
+            .collect()
+    };
+    match pattern {
+        Expr::Many(items) => names(items.iter().collect()),
+        Expr::Tree(t) if t.iter().all(|(k, v)| matches!((k, v), (Expr::Symbol(_), Expr::Symbol(_)))) => {
+            names(t.iter().flat_map(|(k, v)| [k, v]).collect())
+        }
+        Expr::Tree(t) => Ok(t.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+        Expr::Map(m) => Ok(m.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+        _ => Ok(vec![]),
+    }
+}
+
+/// Look up a key of a tree or map pattern in a value.
+///
+/// A symbol key also finds a string key with the same name, so that patterns
+/// work on data imported with serde, and the field of a record with that name.
+/// Returns `None` if the value is not a tree, map or record.
+pub(crate) fn lookup_key<'a>(value: &'a Expr, key: &Expr) -> Option<Option<&'a Expr>> {
+    let by_name = |s: &Symbol| Expr::String(s.name().to_string());
+    Some(match (value, key) {
+        (Expr::Tree(t), Expr::Symbol(s)) => t.get(key).or_else(|| t.get(&by_name(s))),
+        (Expr::Map(m), Expr::Symbol(s)) => m.get(key).or_else(|| m.get(&by_name(s))),
+        (Expr::Tree(t), _) => t.get(key),
+        (Expr::Map(m), _) => m.get(key),
+        (Expr::Record(r), _) => r.get_key(key),
+        _ => return None,
+    })
+}
+
+/// Is the parameter a lambda list keyword, like `&optional`?
+fn is_lambda_keyword(param: &Expr) -> bool {
+    matches!(param, Expr::Symbol(s) if matches!(s.name(), "&optional" | "&rest" | "&key"))
+}
+
+/// Can the parameters be stored by position? This is true when they are
+/// all distinct symbols, without any lambda list keywords.
+pub(crate) fn is_positional(params: &[Expr]) -> bool {
+    params.iter().enumerate().all(|(i, param)| {
+        matches!(param, Expr::Symbol(_)) && !is_lambda_keyword(param) && !params[..i].contains(param)
+    })
+}
+
+/// The error for a call with the wrong number of arguments to a function
+/// with only required parameters.
+fn arity_error(name: &Expr, expected: usize, got: usize) -> EvalError {
+    EvalError::new(
+        ErrorKind::Arity,
+        format!("{name} expected {expected} arguments, got {got}"),
+    )
+}
+
+impl<'a> ParamList<'a> {
+    /// Split a parameter list into its sections.
+    fn parse(params: &'a [Expr]) -> Result<Self, String> {
+        let mut list = Self::default();
+        let mut section = Section::Required;
+        for param in params {
+            let next = match param {
+                Expr::Symbol(s) => match s.name() {
+                    "&optional" => Some(Section::Optional),
+                    "&rest" => Some(Section::Rest),
+                    "&key" => Some(Section::Key),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(next) = next {
+                if next <= section {
+                    return Err(format!("Unexpected {param} in parameter list"));
+                }
+                if section == Section::Rest && list.rest.is_none() {
+                    return Err("Expected a parameter after &rest".to_string());
+                }
+                section = next;
+                continue;
+            }
+
+            match section {
+                Section::Required => list.required.push(param),
+                Section::Optional => list.optional.push(Self::param_with_default(param)?),
+                Section::Rest if list.rest.is_none() => list.rest = Some(param),
+                Section::Rest => return Err(format!("Unexpected {param} after &rest parameter")),
+                Section::Key => {
+                    let (name, default) = Self::param_with_default(param)?;
+                    if !matches!(name, Expr::Symbol(_)) {
+                        return Err(format!("Invalid keyword parameter {name}"));
+                    }
+                    list.key.push((name, default))
+                }
+            }
+        }
+        if section == Section::Rest && list.rest.is_none() {
+            return Err("Expected a parameter after &rest".to_string());
+        }
+        Ok(list)
+    }
+
+    /// Parse a parameter written as `name` or `(name default)`.
+    fn param_with_default(param: &'a Expr) -> Result<Param<'a>, String> {
+        match param {
+            Expr::List(l) if l.len() == 2 => Ok((&l[0], Some(&l[1]))),
+            Expr::List(_) => Err(format!("Invalid parameter {param}, expected (name default)")),
+            param => Ok((param, None)),
+        }
+    }
+
+    /// Describe the number of positional arguments the function expects.
+    fn expected(&self) -> String {
+        let min = self.required.len();
+        let max = min + self.optional.len();
+        if self.rest.is_some() || !self.key.is_empty() {
+            format!("at least {min} arguments")
+        } else if min == max {
+            format!("{min} arguments")
+        } else {
+            format!("{min} to {max} arguments")
+        }
+    }
+}
+
+impl Env {
+    /// Create the scope for a call to a function that closes over this environment,
+    /// with the function's parameters bound to the arguments.
+    ///
+    /// The `name` is the expression that was called, which is used in errors.
+    pub(crate) fn call_scope(&self, name: &Expr, params: &[Expr], args: Vec<Expr>) -> Result<Env, EvalError> {
+        if is_positional(params) {
+            if params.len() != args.len() {
+                return Err(arity_error(name, params.len(), args.len()));
+            }
+            return Ok(self.new_param_scope(params.to_vec(), args));
+        }
+        let mut env = self.new_scope();
+        env.bind_params(name, params, args)?;
+        Ok(env)
+    }
+
+    /// Bind the arguments of a call to the function's parameters in this scope.
+    ///
+    /// The `name` is the expression that was called, which is used in errors.
+    pub(crate) fn bind_params(
+        &mut self,
+        name: &Expr,
+        params: &[Expr],
+        args: Vec<Expr>,
+    ) -> Result<(), EvalError> {
+        // Most functions only have required parameters.
+        if !params.iter().any(is_lambda_keyword) {
+            if params.len() != args.len() {
+                return Err(arity_error(name, params.len(), args.len()));
+            }
+            for (param, arg) in params.iter().cloned().zip(args) {
+                self.bind_pattern(param, arg)?;
+            }
+            return Ok(());
+        }
+
+        let list = ParamList::parse(params)
+            .map_err(|e| EvalError::new(ErrorKind::Type, format!("{name}: {e}")))?;
+        let arity_error = || {
+            EvalError::new(
+                ErrorKind::Arity,
+                format!("{name} expected {}, got {}", list.expected(), args.len()),
+            )
+        };
+
+        let positional = list.required.len() + list.optional.len();
+        if args.len() < list.required.len()
+            || (args.len() > positional && list.rest.is_none() && list.key.is_empty())
+        {
+            return Err(arity_error());
+        }
+
+        let mut args = args.into_iter();
+        for param in &list.required {
+            self.bind_pattern((*param).clone(), args.next().unwrap())?;
+        }
+        for (param, default) in &list.optional {
+            let value = match args.next() {
+                Some(arg) => arg,
+                None => self.eval_default(*default)?,
+            };
+            self.bind_pattern((*param).clone(), value)?;
+        }
+
+        let rest = args.collect::<Vec<_>>();
+        if !list.key.is_empty() {
+            if rest.len() % 2 != 0 {
+                return Err(EvalError::new(
+                    ErrorKind::Arity,
+                    format!("{name} expected keyword arguments in :name value pairs"),
+                ));
+            }
+            let mut keywords = vec![];
+            for pair in rest.chunks(2) {
+                let keyword = match &pair[0] {
+                    Expr::Symbol(s) => s.name().strip_prefix(':'),
+                    _ => None,
+                };
+                match keyword.and_then(|k| list.key.iter().position(|(param, _)| **param == Expr::symbol(k))) {
+                    Some(index) => keywords.push((index, pair[1].clone())),
+                    None => {
+                        return Err(EvalError::new(
+                            ErrorKind::Arity,
+                            format!("{name} got unexpected keyword argument {}", pair[0]),
+                        ))
+                    }
+                }
+            }
+            for (index, (param, default)) in list.key.iter().enumerate() {
+                let value = match keywords.iter().find(|(i, _)| *i == index) {
+                    Some((_, value)) => value.clone(),
+                    None => self.eval_default(*default)?,
+                };
+                self.bind_pattern((*param).clone(), value)?;
+            }
+        }
+        if let Some(param) = list.rest {
+            self.bind_pattern(param.clone(), Expr::List(rest.into()))?;
+        }
+        Ok(())
+    }
+
+    /// Bind the parts of a value to the symbols in a pattern.
+    ///
+    /// A pattern is a symbol, a list of patterns (optionally ending with `&rest pattern`),
+    /// a tree or map from keys to patterns, or names in brackets or braces, like `[name age]`,
+    /// bound to the keys with the same names. If the value does not have the shape of the pattern,
+    /// an error is returned, and some of the symbols may already have been bound.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Env, Expr};
+    ///
+    /// let mut env = Env::new();
+    /// let pattern = Expr::parse("(a (b c))").unwrap();
+    /// let value = Expr::parse("(1 (2 3))").unwrap();
+    /// env.bind_pattern(pattern, value).unwrap();
+    /// assert_eq!(env.get(&Expr::symbol("c")), Some(Expr::Int(3)));
+    ///
+    /// let pattern = Expr::parse("[name age]").unwrap();
+    /// let value = Expr::parse(r#"["name" "bob" "age" 3]"#).unwrap();
+    /// env.bind_pattern(pattern, value.clone()).unwrap();
+    /// assert_eq!(env.get(&Expr::symbol("name")), Some(Expr::from("bob")));
+    /// assert_eq!(env.get(&Expr::symbol("age")), Some(Expr::Int(3)));
+    ///
+    /// let pattern = Expr::parse("#[name n]").unwrap();
+    /// env.bind_pattern(pattern, value.clone()).unwrap();
+    /// assert_eq!(env.get(&Expr::symbol("n")), Some(Expr::from("bob")));
+    ///
+    /// let pattern = Expr::parse("[name n]").unwrap();
+    /// assert!(env.bind_pattern(pattern, value).is_err());
+    ///
+    /// let pattern = Expr::parse("(a b)").unwrap();
+    /// assert!(env.bind_pattern(pattern, Expr::Int(1)).is_err());
+    /// ```
+    pub fn bind_pattern(&mut self, pattern: Expr, value: Expr) -> Result<(), EvalError> {
+        match pattern {
+            Expr::Symbol(_) => {
+                self.bind(pattern, value);
+                Ok(())
+            }
+            Expr::List(patterns) => {
+                let rest_at = patterns.iter().position(|p| *p == Expr::symbol("&rest"));
+                if rest_at.is_some_and(|i| i + 2 != patterns.len()) {
+                    return Err(EvalError::new(
+                        ErrorKind::Type,
+                        format!("Invalid pattern {}, expected one pattern after &rest", Expr::List(patterns)),
+                    ));
+                }
+                let fixed = rest_at.unwrap_or(patterns.len());
+                let values = match value {
+                    Expr::List(values) if values.len() == fixed => values,
+                    Expr::List(values) if rest_at.is_some() && values.len() > fixed => values,
+                    value => {
+                        let expected = match rest_at {
+                            Some(_) => format!("a list of at least {fixed} elements"),
+                            None => format!("a list of {fixed} elements"),
+                        };
+                        return Err(mismatch(&Expr::List(patterns), &value, &expected));
+                    }
+                };
+
+                let mut patterns = patterns.into_iter();
+                let mut values = values.into_iter();
+                for (pattern, value) in patterns.by_ref().take(fixed).zip(values.by_ref()) {
+                    self.bind_pattern(pattern, value)?;
+                }
+                if let Some(rest) = patterns.nth(1) {
+                    self.bind_pattern(rest, Expr::List(values.collect()))?;
+                }
+                Ok(())
+            }
+            Expr::Tree(_) | Expr::Map(_) | Expr::Many(_) => {
+                for (key, sub_pattern) in pattern_entries(&pattern)? {
+                    let Some(found) = lookup_key(&value, &key) else {
+                        return Err(mismatch(&pattern, &value, "a tree, map or record"));
+                    };
+                    match found {
+                        Some(found) => self.bind_pattern(sub_pattern, found.clone())?,
+                        None => {
+                            return Err(mismatch(&pattern, &value, &format!("a value for the key {key}")))
+                        }
+                    }
+                }
+                Ok(())
+            }
+            pattern => Err(EvalError::new(
+                ErrorKind::Type,
+                format!("Invalid pattern {pattern}, expected a symbol, list, tree, map or {{names}}"),
+            )),
+        }
+    }
+
+    /// Evaluate the default value of a missing parameter.
+    fn eval_default(&mut self, default: Option<&Expr>) -> Result<Expr, EvalError> {
+        match default.map(|default| self.eval(default.clone())) {
+            Some(Expr::Err(e)) => {
+                self.take_raised();
+                Err(*e)
+            }
+            Some(value) => Ok(value),
+            None => Ok(Expr::None),
+        }
+    }
+}
@@ -2,8 +2,13 @@
 //!
 //! The parser is responsible for parsing the input string into a Lisp expression.
 //! We use `nom` to parse the input string.
+//!
+//! The parser can also record where each list expression starts in the source,
+//! so that errors raised at runtime can point back at the code that caused them.
 use std::{
-    collections::{BTreeMap, HashMap},
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
     sync::Arc,
 };
 
@@ -21,7 +26,137 @@ use nom::{
     IResult,
 };
 
-use super::Expr;
+use super::{collections::vector::WeakVector, Expr, HashTrieMap, OrdMap};
+
+/// A location in a source file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Span {
+    /// The name of the source, usually a file path.
+    pub source: Arc<str>,
+    /// The line number, starting at 1.
+    pub line: usize,
+    /// The column number, starting at 1.
+    pub column: usize,
+}
+
+/// Print a span as `source:line:column`.
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}:{}:{}", self.source, self.line, self.column)
+    }
+}
+
+/// The locations of the list expressions parsed from a source.
+///
+/// Lists are looked up by identity, not by value: a location belongs to the list
+/// that was parsed there and its clones, so identical expressions elsewhere in the
+/// source, or in other sources, keep their own locations. A location is forgotten
+/// once every clone of its list has been dropped.
+#[derive(Clone, Default)]
+pub struct SourceMap {
+    spans: HashMap<usize, (WeakVector<Expr>, Span)>,
+}
+
+impl SourceMap {
+    /// Get the location of a parsed list expression.
+    #[inline]
+    pub fn get(&self, expr: &Expr) -> Option<&Span> {
+        match expr {
+            Expr::List(list) => {
+                let (_, span) = self.spans.get(&list.downgrade()?.id())?;
+                Some(span)
+            }
+            _ => None,
+        }
+    }
+
+    /// Add all the locations from another source map to this one, and forget the
+    /// locations of lists that no longer exist.
+    pub fn extend(&mut self, other: SourceMap) {
+        self.spans.retain(|_, (list, _)| list.is_alive());
+        self.spans.extend(other.spans);
+    }
+
+    /// Is the source map empty?
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
+
+/// Print the locations, without the lists they belong to.
+impl Debug for SourceMap {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_list().entries(self.spans.values().map(|(_, span)| span)).finish()
+    }
+}
+
+/// The lists recorded so far by [`record_spans`].
+struct Recording {
+    /// The address of the start of the input.
+    base: usize,
+    /// The lists parsed so far, with their offsets from the start of the input.
+    lists: Vec<(Expr, usize)>,
+}
+
+thread_local! {
+    /// The spans recorded while parsing with [`record_spans`], if any.
+    static RECORDED_SPANS: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+/// Run a parser over `input`, and record the location of every list it parses.
+///
+/// The `source` is the name used for the source in the resulting spans.
+pub(crate) fn record_spans<T>(
+    input: &str,
+    source: &str,
+    parse: impl FnOnce(&str) -> T,
+) -> (T, SourceMap) {
+    RECORDED_SPANS.with(|spans| {
+        *spans.borrow_mut() = Some(Recording {
+            base: input.as_ptr() as usize,
+            lists: vec![],
+        })
+    });
+    let result = parse(input);
+    let recorded = RECORDED_SPANS
+        .with(|spans| spans.borrow_mut().take())
+        .map(|recording| recording.lists)
+        .unwrap_or_default();
+
+    // Find the offset of the start of each line, to convert offsets to lines and columns.
+    let line_starts = std::iter::once(0)
+        .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let source: Arc<str> = Arc::from(source);
+
+    let mut map = SourceMap::default();
+    for (expr, offset) in recorded {
+        let line = line_starts.partition_point(|&start| start <= offset);
+        let column = input[line_starts[line - 1]..offset].chars().count() + 1;
+        let Expr::List(list) = expr else { continue };
+        if let Some(list) = list.downgrade() {
+            let span = Span {
+                source: source.clone(),
+                line,
+                column,
+            };
+            map.spans.entry(list.id()).or_insert((list, span));
+        }
+    }
+    (result, map)
+}
+
+/// Record the location of a list that starts at the beginning of `input`,
+/// if spans are being recorded.
+fn record_span(input: &str, expr: &Expr) {
+    RECORDED_SPANS.with(|spans| {
+        if let Some(recording) = spans.borrow_mut().as_mut() {
+            let offset = input.trim_start().as_ptr() as usize - recording.base;
+            recording.lists.push((expr.clone(), offset));
+        }
+    })
+}
 
 fn parse_int_literal<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     input: &'a str,
@@ -96,14 +231,16 @@ fn parse_string_literal<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
 fn parse_list<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     input: &'a str,
 ) -> IResult<&'a str, Expr, E> {
-    map(
+    let (rest, list) = map(
         delimited(
             char('('),
             cut(many0(parse_expr)),
             cut(preceded(multispace0, char(')'))),
         ),
-        Expr::List,
-    )(input)
+        |items: Vec<Expr>| Expr::List(items.into()),
+    )(input)?;
+    record_span(input, &list);
+    Ok((rest, list))
 }
 
 fn parse_block<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
@@ -132,7 +269,7 @@ fn parse_map<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
             ),
         ),
         |pairs| {
-            let mut map = HashMap::new();
+            let mut map = HashTrieMap::new();
             for (k, v) in pairs {
                 map.insert(k, v);
             }
@@ -154,7 +291,7 @@ fn parse_tree<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
             ),
         ),
         |pairs| {
-            let mut tree = BTreeMap::new();
+            let mut tree = OrdMap::new();
             for (k, v) in pairs {
                 tree.insert(k, v);
             }
@@ -195,9 +332,9 @@ pub fn parse_expr<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
 }
 
 fn parse_compare<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    input: &'a str,
+    start: &'a str,
 ) -> IResult<&'a str, Expr, E> {
-    let (input, lhs) = parse_sum(input)?;
+    let (input, lhs) = parse_sum(start)?;
     let (input, op) = opt(alt((
         tag("<="),
         tag(">="),
@@ -220,15 +357,16 @@ fn parse_compare<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
                 _ => unreachable!(),
             },
         ))
+        .inspect(|(_, expr)| record_span(start, expr))
     } else {
         Ok((input, lhs))
     }
 }
 
 fn parse_sum<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    input: &'a str,
+    start: &'a str,
 ) -> IResult<&'a str, Expr, E> {
-    let (input, lhs) = parse_mul(input)?;
+    let (input, lhs) = parse_mul(start)?;
     let (input, op) = opt(one_of("+-"))(input)?;
     if let Some(op) = op {
         let (input, rhs) = parse_sum(input)?;
@@ -240,15 +378,16 @@ fn parse_sum<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
                 _ => unreachable!(),
             },
         ))
+        .inspect(|(_, expr)| record_span(start, expr))
     } else {
         Ok((input, lhs))
     }
 }
 
 fn parse_mul<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    input: &'a str,
+    start: &'a str,
 ) -> IResult<&'a str, Expr, E> {
-    let (input, lhs) = parse_pow(input)?;
+    let (input, lhs) = parse_pow(start)?;
     let (input, _) = multispace0(input)?;
     let (input, op) = opt(one_of("*/%"))(input)?;
     if let Some(op) = op {
@@ -262,15 +401,16 @@ fn parse_mul<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
                 _ => unreachable!(),
             },
         ))
+        .inspect(|(_, expr)| record_span(start, expr))
     } else {
         Ok((input, lhs))
     }
 }
 
 fn parse_pow<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    input: &'a str,
+    start: &'a str,
 ) -> IResult<&'a str, Expr, E> {
-    let (input, lhs) = parse_access(input)?;
+    let (input, lhs) = parse_access(start)?;
     let (input, _) = multispace0(input)?;
     let (input, op) = opt(one_of("^"))(input)?;
     if let Some(op) = op {
@@ -282,15 +422,16 @@ fn parse_pow<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
                 _ => unreachable!(),
             },
         ))
+        .inspect(|(_, expr)| record_span(start, expr))
     } else {
         Ok((input, lhs))
     }
 }
 
 fn parse_access<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
-    input: &'a str,
+    start: &'a str,
 ) -> IResult<&'a str, Expr, E> {
-    let (input, lhs) = parse_atom(input)?;
+    let (input, lhs) = parse_atom(start)?;
     let (input, _) = multispace0(input)?;
 
     let (input, op) = opt(one_of("@"))(input)?;
@@ -314,6 +455,7 @@ fn parse_access<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
 
         // println!("Result: {}", result);
 
+        record_span(start, &result);
         Ok((input, result))
     } else {
         Ok((input, lhs))
@@ -326,6 +468,27 @@ fn parse_quote<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     map(pair(tag("'"), parse_atom), |(_, expr)| expr.quote())(input)
 }
 
+fn parse_quasiquote<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    input: &'a str,
+) -> IResult<&'a str, Expr, E> {
+    map(pair(tag("`"), parse_atom), |(_, expr)| {
+        Expr::symbol("quasiquote").apply(&[expr])
+    })(input)
+}
+
+fn parse_unquote<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
+    input: &'a str,
+) -> IResult<&'a str, Expr, E> {
+    alt((
+        map(pair(tag(",@"), parse_atom), |(_, expr)| {
+            Expr::symbol("unquote-splicing").apply(&[expr])
+        }),
+        map(pair(tag(","), parse_atom), |(_, expr)| {
+            Expr::symbol("unquote").apply(&[expr])
+        }),
+    ))(input)
+}
+
 fn is_symbol_char(c: char) -> bool {
     c.is_alphanumeric()
         || c == '_'
@@ -341,6 +504,7 @@ fn is_symbol_char(c: char) -> bool {
         || c == '>'
         || c == '='
         || c == '&'
+        || c == ':'
         || c == '|'
         || c == '^'
         || c == '\\'
@@ -355,7 +519,8 @@ fn parse_symbol<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     let (input, first) = take_while1(|x| is_symbol_char(x) && !x.is_numeric())(input)?;
     // Parse rest of characters
     let (input, rest) = take_while(is_symbol_char)(input)?;
-    // Combine first and rest
+    // Combine first and rest. Qualified symbols, like `str/split`, are read as one
+    // symbol, and resolved in their namespace when they are looked up.
     let symbol = format!("{}{}", first, rest);
     // println!("Got symbol: {:?}", symbol);
     // Return symbol
@@ -380,6 +545,8 @@ fn parse_atom<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
         context("map", parse_map),
         context("tree", parse_tree),
         context("quote", parse_quote),
+        context("quasiquote", parse_quasiquote),
+        context("unquote", parse_unquote),
         context("symbol", parse_symbol),
     ))(input)
 }
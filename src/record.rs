@@ -0,0 +1,248 @@
+//! # Records
+//!
+//! A record is a value of a named type, with a fixed set of fields. Record types
+//! are defined with `defrecord`, which binds a constructor, a predicate, and an
+//! accessor for each field:
+//!
+//! ```lisp
+//! (defrecord Point (x y))
+//! (define p (make-Point 1 2))   ; #Point[x 1 y 2]
+//! (Point? p)                    ; true
+//! (Point-x p)                   ; 1
+//! (map->Point ["x" 3 "y" 4])    ; #Point[x 3 y 4], from data imported with serde
+//! ```
+//!
+//! The constructors check that every field is given, and nothing else, so a typo
+//! in a field name is an error instead of a missing value. Records are exported to
+//! serde as maps from their field names to their values.
+use std::sync::Arc;
+
+use super::{Env, ErrorKind, Expr, Symbol, Vector};
+
+/// A record type, defined with `defrecord`.
+///
+/// Every definition creates a new type, even if it has the same name as another, so
+/// the functions of a type only accept records constructed by that definition. This
+/// keeps records of an older definition, or of a type with the same name in another
+/// namespace or module, from being mistaken for the new one.
+#[derive(Debug, PartialEq, PartialOrd, Hash)]
+struct RecordType {
+    /// The name of the type.
+    name: Symbol,
+    /// The names of the fields.
+    fields: Box<[Symbol]>,
+}
+
+/// A value of a record type, defined with `defrecord`.
+///
+/// Records are compared by the name and fields of their type, then by their values.
+///
+/// ```rust
+/// use std::collections::HashMap;
+/// use sage_lisp::{Env, Expr, Record};
+///
+/// let mut env = Env::new();
+/// env.bind_core_forms();
+/// let p = env.eval_str("(do (defrecord Point (x y)) (make-Point 1 2))").unwrap();
+/// assert_eq!(p, Expr::Record(Record::new("Point", [("x", Expr::Int(1)), ("y", Expr::Int(2))])));
+///
+/// // Records are deserialized like maps, from their field names to their values.
+/// let fields: HashMap<String, i64> = Expr::deserialize(&p).unwrap();
+/// assert_eq!(fields, HashMap::from([("x".to_string(), 1), ("y".to_string(), 2)]));
+///
+/// // Redefining the type creates a new one, which records of the old one are not.
+/// env.bind_symbol("p", p);
+/// assert_eq!(env.eval_str("(do (defrecord Point (x y z)) (Point? p))").unwrap(), Expr::Bool(false));
+/// ```
+#[derive(Debug, Clone, PartialEq, PartialOrd, Hash)]
+pub struct Record {
+    /// The record's type, shared by every record of the type.
+    ty: Arc<RecordType>,
+    /// The values of the fields, in the same order as their names.
+    values: Vector<Expr>,
+}
+
+impl Record {
+    /// Create a record from the names and values of its fields.
+    ///
+    /// The record has a type of its own, which is equal to, but not the same as,
+    /// a type defined with `defrecord`, so the functions of that type don't accept it.
+    pub fn new<K: Into<Symbol>>(name: impl Into<Symbol>, fields: impl IntoIterator<Item = (K, Expr)>) -> Self {
+        let (fields, values): (Vec<Symbol>, Vector<Expr>) = fields.into_iter().map(|(k, v)| (k.into(), v)).unzip();
+        Self {
+            ty: Arc::new(RecordType {
+                name: name.into(),
+                fields: fields.into(),
+            }),
+            values,
+        }
+    }
+
+    /// Get the name of the record's type.
+    #[inline]
+    pub fn type_name(&self) -> &Symbol {
+        &self.ty.name
+    }
+
+    /// Get the value of a field, if the record has it.
+    #[inline]
+    pub fn get(&self, field: &str) -> Option<&Expr> {
+        let i = self.ty.fields.iter().position(|f| f.name() == field)?;
+        self.values.get(i)
+    }
+
+    /// Get the value of a field, from a key of a tree or map, which can be a symbol or string.
+    ///
+    /// ```rust
+    /// use sage_lisp::{Expr, Record};
+    ///
+    /// let p = Record::new("Point", [("x", Expr::Int(1)), ("y", Expr::Int(2))]);
+    /// assert_eq!(p.get_key(&Expr::symbol("x")), Some(&Expr::Int(1)));
+    /// assert_eq!(p.get_key(&Expr::from("y")), Some(&Expr::Int(2)));
+    /// assert_eq!(p.get_key(&Expr::Int(0)), None);
+    /// ```
+    #[inline]
+    pub fn get_key(&self, key: &Expr) -> Option<&Expr> {
+        match key {
+            Expr::Symbol(s) => self.get(s.name()),
+            Expr::String(s) => self.get(s),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the names and values of the fields, in the order they were declared.
+    #[inline]
+    pub fn fields(&self) -> impl Iterator<Item = (&Symbol, &Expr)> {
+        self.ty.fields.iter().zip(self.values.iter())
+    }
+
+    /// Is the record of the given type, and not just one with the same name?
+    #[inline]
+    fn is_of(&self, ty: &Arc<RecordType>) -> bool {
+        Arc::ptr_eq(&self.ty, ty)
+    }
+}
+
+/// Print a record like a tree, tagged with its type.
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#{}[", self.ty.name.name())?;
+        for (i, (field, value)) in self.fields().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{} {}", field.name(), value)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl Env {
+    /// Evaluate a `(defrecord Name (fields))` form, binding the functions for the record type.
+    pub(crate) fn define_record(&mut self, args: &[Expr]) -> Expr {
+        let (name, fields) = match args {
+            [Expr::Symbol(name), Expr::List(fields)] => (name.clone(), fields),
+            _ => return Expr::error_of(ErrorKind::Type, "defrecord expects a name and a list of fields"),
+        };
+        let mut names: Vec<Symbol> = vec![];
+        for field in fields {
+            match field {
+                Expr::Symbol(field) if !names.contains(field) => names.push(field.clone()),
+                Expr::Symbol(field) => {
+                    return Expr::error_of(ErrorKind::Type, format!("{} has more than one field named {}", name.name(), field.name()))
+                }
+                field => return Expr::error_of(ErrorKind::Type, format!("Invalid field {field} of {}", name.name())),
+            }
+        }
+        let ty = Arc::new(RecordType {
+            name: name.clone(),
+            fields: names.into(),
+        });
+        let type_name = name.name().to_string();
+
+        let record_type = ty.clone();
+        self.bind_strict_builtin(&format!("make-{type_name}"), move |env, args| {
+            let (name, fields) = (&record_type.name, &record_type.fields);
+            if args.len() != fields.len() {
+                let names = Expr::List(fields.iter().cloned().map(Expr::Symbol).collect());
+                return Expr::error_of(
+                    ErrorKind::Arity,
+                    format!("make-{} expected the fields {names}, got {} arguments", name.name(), args.len()),
+                );
+            }
+            match env.eval_all(args) {
+                Ok(values) => Expr::Record(Record {
+                    ty: record_type.clone(),
+                    values: values.into(),
+                }),
+                Err(err) => err,
+            }
+        });
+
+        let record_type = ty.clone();
+        self.bind_strict_builtin(&format!("map->{type_name}"), move |env, args| {
+            let (name, fields) = (&record_type.name, &record_type.fields);
+            let entries: Vec<(Expr, Expr)> = match args.first().map(|arg| env.eval(arg.clone())) {
+                Some(Expr::Tree(t)) => t.into_iter().collect(),
+                Some(Expr::Map(m)) => m.into_iter().collect(),
+                Some(err @ Expr::Err(_)) => return err,
+                other => {
+                    let other = other.unwrap_or_default();
+                    return Expr::error_of(ErrorKind::Type, format!("map->{} expected a tree or map, got {other}", name.name()));
+                }
+            };
+            let mut values = vec![Expr::None; fields.len()];
+            let mut given = vec![false; fields.len()];
+            for (key, value) in entries {
+                let field = match &key {
+                    Expr::Symbol(s) => s.name(),
+                    Expr::String(s) => s.as_str(),
+                    _ => "",
+                };
+                match fields.iter().position(|f| f.name() == field) {
+                    Some(i) => (values[i], given[i]) = (value, true),
+                    None => return Expr::error_of(ErrorKind::Type, format!("{} has no field {key}", name.name())),
+                }
+            }
+            if let Some(i) = given.iter().position(|given| !given) {
+                return Expr::error_of(ErrorKind::Type, format!("{} is missing the field {}", name.name(), fields[i].name()));
+            }
+            Expr::Record(Record {
+                ty: record_type.clone(),
+                values: values.into(),
+            })
+        });
+
+        let record_type = ty.clone();
+        self.bind_strict_builtin(&format!("{type_name}?"), move |env, args| {
+            if args.len() != 1 {
+                return Expr::error_of(ErrorKind::Arity, format!("{}? expected 1 argument, got {}", record_type.name.name(), args.len()));
+            }
+            match env.eval(args[0].clone()) {
+                Expr::Record(r) => Expr::Bool(r.is_of(&record_type)),
+                Expr::Err(err) => Expr::Err(err),
+                _ => Expr::Bool(false),
+            }
+        });
+
+        for (i, field) in ty.fields.iter().enumerate() {
+            let accessor = format!("{type_name}-{}", field.name());
+            let (record_type, name) = (ty.clone(), accessor.clone());
+            self.bind_strict_builtin(&accessor, move |env, args| {
+                if args.len() != 1 {
+                    return Expr::error_of(ErrorKind::Arity, format!("{name} expected 1 argument, got {}", args.len()));
+                }
+                match env.eval(args[0].clone()) {
+                    Expr::Record(r) if r.is_of(&record_type) => r.values[i].clone(),
+                    Expr::Record(r) if r.ty.name == record_type.name => Expr::error_of(
+                        ErrorKind::Type,
+                        format!("{name} expected a {}, got {r} from another definition of the type", record_type.name.name()),
+                    ),
+                    Expr::Err(err) => Expr::Err(err),
+                    other => Expr::error_of(ErrorKind::Type, format!("{name} expected a {}, got {other}", record_type.name.name())),
+                }
+            });
+        }
+        Expr::None
+    }
+}
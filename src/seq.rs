@@ -0,0 +1,323 @@
+//! # Lazy Sequences
+//!
+//! A [`Seq`] is a sequence of values that are only computed when they are needed.
+//! This allows for infinite sequences, like every natural number, and for large
+//! sequences, like the lines of a huge file, to be processed one value at a time.
+//!
+//! A sequence is a description of where its values come from, like a range or a
+//! list, and of the transformations applied to them, like `map` or `filter`. It is
+//! realized by iterating over it with [`Seq::iter`], which applies the functions
+//! in the environment that is iterating. Nothing is cached, so iterating over the
+//! same sequence twice computes its values again.
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    sync::Arc,
+};
+
+use super::{Env, Expr, Vector};
+
+/// A function that starts a new iteration over a sequence supplied by the host.
+pub type SeqFn = dyn Fn() -> Box<dyn Iterator<Item = Expr> + Send> + Send + Sync;
+
+/// A lazy sequence of values.
+///
+/// Sequences are compared and hashed by identity, like boxes, because comparing
+/// their values could take forever.
+///
+/// ```rust
+/// use sage_lisp::{Env, Expr, Seq};
+///
+/// let mut env = Env::new();
+/// env.bind_builtin("square", |env, args| match env.eval(args[0].clone()) {
+///     Expr::Int(n) => Expr::Int(n * n),
+///     _ => Expr::error("expected an integer"),
+/// });
+///
+/// // The squares of every natural number, of which we only compute the first five.
+/// let squares = Seq::range(0, 1, None).map(Expr::symbol("square"));
+/// let first = squares.take(5).realize(&env).unwrap();
+/// assert_eq!(Expr::List(first), Expr::parse("(0 1 4 9 16)").unwrap());
+/// ```
+#[derive(Clone)]
+pub struct Seq(Arc<Source>);
+
+/// Where the values of a sequence come from.
+enum Source {
+    /// Integers counting from a start by a step, up to an optional inclusive end.
+    Range { start: i64, step: i64, end: Option<i64> },
+    /// The items of a list.
+    Items(Vector<Expr>),
+    /// The items of a list, repeated forever.
+    Cycle(Vector<Expr>),
+    /// A value, followed by the results of repeatedly applying a function to it.
+    Iterate { f: Expr, init: Expr },
+    /// The values of a sequence supplied by the host application.
+    Host(Arc<SeqFn>),
+    /// The results of applying a function to each value of a sequence.
+    Map { f: Expr, seq: Seq },
+    /// The values of a sequence that satisfy a predicate.
+    Filter { f: Expr, seq: Seq },
+    /// The first values of a sequence.
+    Take { n: usize, seq: Seq },
+    /// The values of a sequence after the first ones.
+    Drop { n: usize, seq: Seq },
+    /// The values of a sequence until one fails to satisfy a predicate.
+    TakeWhile { f: Expr, seq: Seq },
+}
+
+impl Seq {
+    fn new(source: Source) -> Self {
+        Self(Arc::new(source))
+    }
+
+    /// Create a sequence of integers counting from `start` by `step`.
+    ///
+    /// If `end` is given, the sequence stops after passing it, like `range` does.
+    /// Otherwise, it continues until the integers overflow.
+    #[inline]
+    pub fn range(start: i64, step: i64, end: Option<i64>) -> Self {
+        Self::new(Source::Range { start, step, end })
+    }
+
+    /// Create a sequence that repeats the items of a list forever.
+    #[inline]
+    pub fn cycle(items: impl Into<Vector<Expr>>) -> Self {
+        Self::new(Source::Cycle(items.into()))
+    }
+
+    /// Create an infinite sequence of `init`, `(f init)`, `(f (f init))`, and so on.
+    #[inline]
+    pub fn iterate(f: Expr, init: Expr) -> Self {
+        Self::new(Source::Iterate { f, init })
+    }
+
+    /// Create a sequence from the host application.
+    ///
+    /// The function is called to start each iteration over the sequence, so it
+    /// can, for example, open a file and return an iterator over its lines.
+    /// If the iterator yields an error value, the sequence stops with that error.
+    #[inline]
+    pub fn from_fn(f: impl Fn() -> Box<dyn Iterator<Item = Expr> + Send> + Send + Sync + 'static) -> Self {
+        Self::new(Source::Host(Arc::new(f)))
+    }
+
+    /// Apply a function to each value of the sequence.
+    #[inline]
+    pub fn map(&self, f: Expr) -> Self {
+        Self::new(Source::Map { f, seq: self.clone() })
+    }
+
+    /// Keep the values of the sequence for which a predicate returns true.
+    #[inline]
+    pub fn filter(&self, f: Expr) -> Self {
+        Self::new(Source::Filter { f, seq: self.clone() })
+    }
+
+    /// Keep the first `n` values of the sequence.
+    #[inline]
+    pub fn take(&self, n: usize) -> Self {
+        Self::new(Source::Take { n, seq: self.clone() })
+    }
+
+    /// Skip the first `n` values of the sequence.
+    #[inline]
+    pub fn skip(&self, n: usize) -> Self {
+        Self::new(Source::Drop { n, seq: self.clone() })
+    }
+
+    /// Keep the values of the sequence until a predicate doesn't return true.
+    #[inline]
+    pub fn take_while(&self, f: Expr) -> Self {
+        Self::new(Source::TakeWhile { f, seq: self.clone() })
+    }
+
+    /// Iterate over the values of the sequence, computing them in the given environment.
+    ///
+    /// If computing a value returns an error, the iterator yields the error and stops.
+    #[inline]
+    pub fn iter(&self, env: &Env) -> SeqIter {
+        SeqIter {
+            env: env.clone(),
+            values: Some(self.values(env)),
+        }
+    }
+
+    /// Get the first value of the sequence, if it has one.
+    pub fn first(&self, env: &Env) -> Result<Option<Expr>, Expr> {
+        self.iter(env).next().transpose()
+    }
+
+    /// Get the value at an index of the sequence, if it is that long.
+    pub fn nth(&self, env: &Env, n: usize) -> Result<Option<Expr>, Expr> {
+        self.iter(env).nth(n).transpose()
+    }
+
+    /// Compute every value of the sequence, and collect them into a list.
+    ///
+    /// This only finishes for finite sequences, unless the environment is
+    /// interrupted, or has a limit on the size of collections.
+    pub fn realize(&self, env: &Env) -> Result<Vector<Expr>, Expr> {
+        let mut items = Vector::new();
+        for value in self.iter(env) {
+            items.push_back(value?);
+            if let Err(e) = env.check_collection_size(items.len()) {
+                return Err(e.into());
+            }
+        }
+        Ok(items)
+    }
+
+    /// The address of the sequence, which identifies it.
+    #[inline]
+    pub(crate) fn addr(&self) -> usize {
+        Arc::as_ptr(&self.0) as *const () as usize
+    }
+
+    /// Start iterating over the values of the sequence.
+    fn values(&self, env: &Env) -> Values {
+        match &*self.0 {
+            Source::Range { start, step, end } => {
+                let (step, end) = (*step, *end);
+                let past_end = move |i: &i64| match end {
+                    Some(end) if step < 0 => *i < end,
+                    Some(end) => *i > end,
+                    None => false,
+                };
+                Box::new(
+                    std::iter::successors(Some(*start), move |i| i.checked_add(step))
+                        .take_while(move |i| !past_end(i))
+                        .map(|i| Ok(Expr::Int(i))),
+                )
+            }
+            Source::Items(items) => Box::new(items.clone().into_iter().map(Ok)),
+            Source::Cycle(items) => {
+                let items = items.clone();
+                let len = items.len();
+                Box::new((0..).take_while(move |_| len > 0).map(move |i| Ok(items[i % len].clone())))
+            }
+            Source::Iterate { f, init } => {
+                let (f, mut env) = (f.clone(), env.clone());
+                Box::new(std::iter::successors(Some(Ok(init.clone())), move |prev| match prev {
+                    Ok(value) => Some(call(&mut env, &f, value)),
+                    Err(_) => None,
+                }))
+            }
+            Source::Host(f) => Box::new(f().map(|value| if value.is_err() { Err(value) } else { Ok(value) })),
+            Source::Map { f, seq } => {
+                let (f, mut env) = (f.clone(), env.clone());
+                Box::new(seq.values(&env).map(move |value| value.and_then(|value| call(&mut env, &f, &value))))
+            }
+            Source::Filter { f, seq } => {
+                let (f, mut env) = (f.clone(), env.clone());
+                Box::new(seq.values(&env).filter_map(move |value| match value {
+                    Ok(value) => match call(&mut env, &f, &value) {
+                        Ok(Expr::Bool(true)) => Some(Ok(value)),
+                        Ok(_) => None,
+                        Err(e) => Some(Err(e)),
+                    },
+                    Err(e) => Some(Err(e)),
+                }))
+            }
+            Source::Take { n, seq } => Box::new(seq.values(env).take(*n)),
+            Source::Drop { n, seq } => {
+                // Errors computing the skipped values are still reported.
+                let mut remaining = *n;
+                Box::new(seq.values(env).filter(move |value| {
+                    if value.is_err() || remaining == 0 {
+                        return true;
+                    }
+                    remaining -= 1;
+                    false
+                }))
+            }
+            Source::TakeWhile { f, seq } => {
+                let (f, mut env) = (f.clone(), env.clone());
+                Box::new(seq.values(&env).map_while(move |value| match value {
+                    Ok(value) => match call(&mut env, &f, &value) {
+                        Ok(Expr::Bool(true)) => Some(Ok(value)),
+                        Ok(_) => None,
+                        Err(e) => Some(Err(e)),
+                    },
+                    Err(e) => Some(Err(e)),
+                }))
+            }
+        }
+    }
+}
+
+/// View a list as a sequence.
+impl From<Vector<Expr>> for Seq {
+    #[inline]
+    fn from(items: Vector<Expr>) -> Self {
+        Self::new(Source::Items(items))
+    }
+}
+
+/// Print a sequence without computing its values.
+impl Debug for Seq {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "<seq>")
+    }
+}
+
+/// The values of a sequence, or the error that stopped computing them.
+type Values = Box<dyn Iterator<Item = Result<Expr, Expr>> + Send>;
+
+/// An iterator over the values of a [`Seq`].
+///
+/// This yields each value as it is computed. If computing a value fails, the
+/// error is yielded, and the iterator stops.
+pub struct SeqIter {
+    /// The environment iterating, which can be interrupted.
+    env: Env,
+    /// The values left to compute, until an error stops the iteration.
+    values: Option<Values>,
+}
+
+impl Iterator for SeqIter {
+    type Item = Result<Expr, Expr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Sequences like ranges can produce values forever without evaluating
+        // anything, so let the host stop them.
+        if let Err(e) = self.env.check_interrupt() {
+            self.values = None;
+            return Some(Err(e.into()));
+        }
+        let value = self.values.as_mut()?.next();
+        if !matches!(value, Some(Ok(_))) {
+            self.values = None;
+        }
+        value
+    }
+}
+
+/// Call a function on a value of a sequence.
+fn call(env: &mut Env, f: &Expr, value: &Expr) -> Result<Expr, Expr> {
+    let result = env.eval(Expr::List(vec![f.clone(), value.quote()].into()));
+    if result.is_err() {
+        Err(result)
+    } else {
+        Ok(result)
+    }
+}
+
+impl Env {
+    /// Evaluate an expression, realizing it into a list if it is a lazy sequence.
+    ///
+    /// Builtins that need every value of a list at once can use this to accept
+    /// lazy sequences as well as lists.
+    pub fn eval_realized(&mut self, expr: Expr) -> Expr {
+        match self.eval(expr) {
+            Expr::Seq(seq) => match seq.realize(self) {
+                Ok(items) => Expr::List(items),
+                Err(e) => {
+                    // Short-circuit the builtin, like an argument that failed to evaluate.
+                    self.raised.get_or_insert_with(|| Box::new(e.clone()));
+                    e
+                }
+            },
+            value => value,
+        }
+    }
+}
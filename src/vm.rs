@@ -0,0 +1,457 @@
+//! # Virtual Machine
+//!
+//! The virtual machine runs the bytecode made by the [compiler](crate::Code).
+//! It keeps a stack of values, and a stack of activations, one for each compiled
+//! function being called. Calls to compiled functions push an activation instead
+//! of recursing, and tail calls replace the current one, so deep recursion doesn't
+//! overflow the stack of the host.
+//!
+//! Builtins are called with the environment of the activation, like the
+//! tree-walking evaluator calls them. Expressions that are only known at run
+//! time, like macro expansions and the results of lazy builtins, are compiled
+//! and run in a new activation.
+use std::sync::Arc;
+
+use super::{
+    compiler::{Code, Op},
+    limits::Budget,
+    Builtin, Env, ErrorKind, EvalError, EvalLimits, Expr, Frame,
+};
+
+/// The state of a call to compiled code.
+struct Activation {
+    /// The code being run.
+    code: Arc<Code>,
+    /// The index of the next instruction.
+    ip: usize,
+    /// The environment the code runs in.
+    env: Env,
+    /// The height of the value stack when the activation started.
+    base: usize,
+    /// The function call that started the activation, which is recorded in the
+    /// trace of any error that escapes from it.
+    call: Option<Call>,
+    /// Did the activation enter a level of the evaluation budget?
+    entered: bool,
+}
+
+/// A call to a function, kept for error traces.
+struct Call {
+    /// The code that made the call.
+    caller: Arc<Code>,
+    /// The index of the call form in the constants of the caller.
+    form: u32,
+    /// The parameters of the function.
+    params: Vec<Expr>,
+}
+
+/// A virtual machine running compiled code.
+struct Vm<'a> {
+    /// The values being operated on.
+    stack: Vec<Expr>,
+    /// The activations, innermost last.
+    activations: Vec<Activation>,
+    /// The calls to strict builtins whose arguments are being evaluated, innermost last,
+    /// with the number of activations when they started. These are recorded in the trace
+    /// of any error raised by one of their arguments.
+    pending: Vec<(usize, u32, Arc<str>)>,
+    /// The limits to enforce, if any.
+    limits: Option<&'a EvalLimits>,
+}
+
+impl Env {
+    /// Run compiled code in this environment.
+    ///
+    /// If `limits` are given, each instruction takes a step from the environment's budget.
+    pub(crate) fn run(&mut self, code: Arc<Code>, limits: Option<&EvalLimits>) -> Expr {
+        let mut vm = Vm {
+            stack: vec![],
+            activations: vec![Activation {
+                code,
+                ip: 0,
+                env: self.clone(),
+                base: 0,
+                call: None,
+                entered: false,
+            }],
+            pending: vec![],
+            limits,
+        };
+        loop {
+            match vm.step() {
+                Ok(Some(result)) => return result,
+                Ok(None) => {}
+                Err(err) => return vm.unwind(err),
+            }
+        }
+    }
+}
+
+impl Vm<'_> {
+    /// The innermost activation.
+    #[inline]
+    fn top(&mut self) -> &mut Activation {
+        self.activations.last_mut().unwrap()
+    }
+
+    /// Push a value, or fail with it if it's an error.
+    #[inline]
+    fn push(&mut self, value: Expr) -> Result<(), Expr> {
+        if value.is_err() {
+            return Err(value);
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Run the next instruction. Returns the result of the code once it returns.
+    fn step(&mut self) -> Result<Option<Expr>, Expr> {
+        let limits = self.limits;
+        let top = self.activations.last_mut().unwrap();
+        let op = top.code.ops[top.ip];
+        top.ip += 1;
+        if let Some(limits) = limits {
+            top.env.context.budget.step(limits)?;
+        }
+
+        match op {
+            Op::Const(i) => {
+                let value = top.code.consts[i as usize].clone();
+                self.push(value)?;
+            }
+            Op::Param(slot) => {
+                let name = &top.code.params[slot as usize];
+                let value = top.env.get_param(slot as usize, name).unwrap_or_else(|| name.clone());
+                self.push(value)?;
+            }
+            Op::Global(i) => {
+                let name = &top.code.consts[i as usize];
+                let value = top.env.get(name).unwrap_or_else(|| name.clone());
+                self.push(value)?;
+            }
+            Op::Pop => {
+                self.stack.pop();
+            }
+            Op::Jump(target) => top.ip = target as usize,
+            Op::JumpUnless(target) => {
+                if self.stack.pop() != Some(Expr::Bool(true)) {
+                    top.ip = target as usize;
+                }
+            }
+            Op::Closure(i) => {
+                let (params, body) = &top.code.functions[i as usize];
+                let function = Expr::Function(
+                    Some(Box::new(top.env.clone())),
+                    params.clone(),
+                    Box::new(Expr::Code(body.clone())),
+                );
+                self.stack.push(function);
+            }
+            Op::Tree(i) | Op::Map(i) => {
+                let keys = match &top.code.consts[i as usize] {
+                    Expr::List(keys) => keys.clone(),
+                    _ => unreachable!("The keys of a tree or map are a list"),
+                };
+                let values = self.stack.split_off(self.stack.len() - keys.len());
+                let pairs = keys.into_iter().zip(values);
+                self.stack.push(match op {
+                    Op::Tree(_) => Expr::Tree(pairs.collect()),
+                    _ => Expr::Map(pairs.collect()),
+                });
+            }
+            Op::Dispatch { call, end, tail } => {
+                top.env.context.interrupt.check()?;
+                match self.stack.last() {
+                    Some(Expr::Function(..)) => {}
+                    Some(Expr::Builtin(builtin)) if builtin.strict => {
+                        self.pending.push((self.activations.len(), call, builtin.name.clone()));
+                    }
+                    _ => {
+                        top.ip = end as usize;
+                        let callee = self.stack.pop().unwrap();
+                        self.call_unevaluated(callee, call, tail)?;
+                    }
+                }
+            }
+            Op::Call { call, argc, tail } => {
+                let args = self.stack.split_off(self.stack.len() - argc as usize);
+                match self.stack.pop() {
+                    Some(Expr::Function(closure, params, body)) => {
+                        self.call_function(closure.map(|closure| *closure), params, *body, call, args, tail)?
+                    }
+                    Some(Expr::Builtin(builtin)) => {
+                        self.pending.pop();
+                        let args = args.iter().map(Expr::quote).collect();
+                        let result = self.apply_builtin(&builtin, call, args)?;
+                        self.finish(result, builtin.lazy_eval, tail)?;
+                    }
+                    callee => unreachable!("Dispatched a call to {callee:?}"),
+                }
+            }
+            Op::Return => {
+                let result = self.stack.pop().unwrap_or_default();
+                let activation = self.activations.pop().unwrap();
+                self.stack.truncate(activation.base);
+                if activation.entered {
+                    activation.env.context.budget.exit();
+                }
+                if self.activations.is_empty() {
+                    return Ok(Some(result));
+                }
+                self.stack.push(result);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The call form at an index in the constants of the innermost activation.
+    fn form(&mut self, call: u32) -> &Expr {
+        &self.top().code.consts[call as usize]
+    }
+
+    /// Start running code in a new activation, or in place of the innermost one for a tail call.
+    fn enter(&mut self, code: Arc<Code>, env: Env, call: Option<Call>, tail: bool) -> Result<(), Expr> {
+        if tail {
+            let base = self.top().base;
+            self.stack.truncate(base);
+            let top = self.top();
+            top.code = code;
+            top.ip = 0;
+            top.env = env;
+            // Evaluating an expression in place of a call keeps the call in the trace.
+            if call.is_some() {
+                top.call = call;
+            }
+            return Ok(());
+        }
+
+        let entered = match self.limits {
+            Some(limits) => {
+                let budget = &env.context.budget;
+                if let Err(err) = budget.enter(limits) {
+                    budget.exit();
+                    return Err(err.into());
+                }
+                true
+            }
+            None => false,
+        };
+        self.activations.push(Activation {
+            code,
+            ip: 0,
+            env,
+            base: self.stack.len(),
+            call,
+            entered,
+        });
+        Ok(())
+    }
+
+    /// Call a function with evaluated arguments.
+    fn call_function(
+        &mut self,
+        closure: Option<Env>,
+        params: Vec<Expr>,
+        body: Expr,
+        call: u32,
+        args: Vec<Expr>,
+        tail: bool,
+    ) -> Result<(), Expr> {
+        let top = self.activations.last().unwrap();
+        let form = &top.code.consts[call as usize];
+        let head = match form {
+            Expr::List(items) => &items[0],
+            _ => form,
+        };
+        let mut env = closure
+            .as_ref()
+            .unwrap_or(&top.env)
+            .call_scope(head, &params, args)
+            .map_err(|err| Expr::from(err.at(top.env.span_of(form))))?;
+
+        match body {
+            Expr::Code(code) => {
+                let call = Call {
+                    caller: top.code.clone(),
+                    form: call,
+                    params,
+                };
+                self.enter(code, env, Some(call), tail)
+            }
+            body => {
+                // The function wasn't compiled, so walk its body instead.
+                let mut result = env.eval(body);
+                if let Expr::Err(e) = &mut result {
+                    let span = top.env.span_of(form);
+                    if e.span.is_none() {
+                        e.span = span.clone();
+                    }
+                    let args = params
+                        .into_iter()
+                        .map(|param| {
+                            let value = env.scope.lookup(&param).unwrap_or_default();
+                            (param, value)
+                        })
+                        .collect();
+                    e.push_frame(Frame::Function {
+                        name: head.clone(),
+                        args,
+                        span,
+                    });
+                }
+                self.push(result)
+            }
+        }
+    }
+
+    /// Call something that takes its arguments unevaluated, like a builtin
+    /// that isn't strict or a macro, or that can't be called at all.
+    fn call_unevaluated(&mut self, callee: Expr, call: u32, tail: bool) -> Result<(), Expr> {
+        let form = self.form(call).clone();
+        let (head, args) = match &form {
+            Expr::List(items) => (&items[0], items.iter().skip(1).cloned().collect()),
+            _ => unreachable!("A call form is a list"),
+        };
+
+        match callee {
+            Expr::Builtin(builtin) => {
+                let result = self.apply_builtin(&builtin, call, args)?;
+                self.finish(result, builtin.lazy_eval, tail)
+            }
+            Expr::Macro(expander) => {
+                let env = &self.top().env;
+                let mut expansion = env.expand_macro(head, &expander, args);
+                if let Expr::Err(e) = &mut expansion {
+                    if e.span.is_none() {
+                        e.span = env.span_of(&form);
+                    }
+                    return Err(expansion);
+                }
+                self.evaluate(expansion, tail)
+            }
+            Expr::Tree(_) | Expr::Map(_) => {
+                let env = &mut self.top().env;
+                let key = match args.first() {
+                    Some(key) => env.eval(key.clone()),
+                    None => return Err(EvalError::new(ErrorKind::Arity, "Expected a key to index the tree").into()),
+                };
+                if key.is_err() {
+                    return Err(key);
+                }
+                let value = match callee {
+                    Expr::Tree(t) => t.get(&key).cloned(),
+                    Expr::Map(m) => m.get(&key).cloned(),
+                    _ => unreachable!(),
+                };
+                self.push(value.unwrap_or_default())
+            }
+            Expr::Symbol(s) => {
+                let span = self.top().env.span_of(&form);
+                Err(EvalError::new(ErrorKind::Unbound, format!("Symbol {} not found", s.name()))
+                    .at(span)
+                    .into())
+            }
+            // Like the tree-walking evaluator, a list that isn't a call evaluates to itself.
+            _ => self.push(form),
+        }
+    }
+
+    /// Apply a builtin to a list of arguments, in the environment of the innermost activation.
+    fn apply_builtin(&mut self, builtin: &Builtin, call: u32, args: Vec<Expr>) -> Result<Expr, Expr> {
+        let limits = self.limits;
+        let top = self.top();
+        let env = &mut top.env;
+        env.raised = None;
+        let mut result = builtin.apply(env, args);
+        // If one of the arguments failed, return its error
+        // instead of whatever the builtin made of it.
+        if let Some(err) = env.take_raised() {
+            result = err;
+        }
+        if let Some(limits) = limits {
+            if let Err(err) = Budget::check_result(limits, &result) {
+                result = err.into();
+            }
+        }
+        if let Expr::Err(e) = &mut result {
+            let form = top.code.consts[call as usize].clone();
+            let span = top.env.span_of(&form);
+            if e.span.is_none() {
+                e.span = span.clone();
+            }
+            e.push_frame(Frame::Builtin {
+                name: builtin.name.to_string(),
+                call: form,
+                span,
+            });
+            return Err(result);
+        }
+        Ok(result)
+    }
+
+    /// Push the result of a builtin, or evaluate it if the builtin is lazy.
+    fn finish(&mut self, result: Expr, lazy: bool, tail: bool) -> Result<(), Expr> {
+        if lazy {
+            self.evaluate(result, tail)
+        } else {
+            self.push(result)
+        }
+    }
+
+    /// Evaluate an expression that was made while running, by compiling it.
+    fn evaluate(&mut self, expr: Expr, tail: bool) -> Result<(), Expr> {
+        let env = self.top().env.clone();
+        let code = env.compile_code(expr);
+        self.enter(code, env, None, tail)
+    }
+
+    /// Unwind all of the activations after an error, recording
+    /// the function calls it escaped from in its trace.
+    fn unwind(&mut self, mut err: Expr) -> Expr {
+        while let Some(activation) = self.activations.pop() {
+            if activation.entered {
+                activation.env.context.budget.exit();
+            }
+            let Expr::Err(e) = &mut err else {
+                continue;
+            };
+            while let Some((_, call, name)) = self
+                .pending
+                .pop_if(|(depth, ..)| *depth > self.activations.len())
+            {
+                let form = activation.code.consts[call as usize].clone();
+                let span = activation.env.span_of(&form);
+                if e.span.is_none() {
+                    e.span = span.clone();
+                }
+                e.push_frame(Frame::Builtin {
+                    name: name.to_string(),
+                    call: form,
+                    span,
+                });
+            }
+            let Some(call) = activation.call else {
+                continue;
+            };
+            let form = &call.caller.consts[call.form as usize];
+            let name = match form {
+                Expr::List(items) => items[0].clone(),
+                form => form.clone(),
+            };
+            let args = call
+                .params
+                .into_iter()
+                .map(|param| {
+                    let value = activation.env.scope.lookup(&param).unwrap_or_default();
+                    (param, value)
+                })
+                .collect();
+            let span = activation.env.span_of(form);
+            if e.span.is_none() {
+                e.span = span.clone();
+            }
+            e.push_frame(Frame::Function { name, args, span });
+        }
+        err
+    }
+}